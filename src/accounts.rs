@@ -0,0 +1,232 @@
+use crate::history::{escape, unescape};
+use crate::protocol;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use ring::rand::SystemRandom;
+use ring::signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// Size of an Ed25519 public key
+pub const PUBLIC_KEY_LEN: usize = 32;
+
+fn key_error() -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        "Chattest accounts error: the key is not valid!",
+    )
+}
+
+/// Turns bytes into hex digits
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Turns hex digits back into bytes
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(text.get(idx..idx + 2)?, 16).ok())
+        .collect()
+}
+
+/// What protects a registered name
+#[derive(Clone, Debug, PartialEq)]
+pub enum Credential {
+    /// The argon2 hash of a password, in the PHC string format
+    Password(String),
+    /// The Ed25519 public key of a client
+    Key(Vec<u8>),
+}
+
+impl Credential {
+    /// Protects a name with `password`, only its hash is kept
+    pub fn password(password: &str) -> io::Result<Self> {
+        let mut salt = [0u8; 16];
+        ring::rand::SecureRandom::fill(&SystemRandom::new(), &mut salt)
+            .map_err(|_| io::Error::other("Chattest accounts error: no random numbers!"))?;
+        let hash_error = |error| io::Error::other(format!("Chattest accounts error: {}!", error));
+        let salt = SaltString::encode_b64(&salt).map_err(hash_error)?;
+        let hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(hash_error)?;
+        Ok(Credential::Password(hash.to_string()))
+    }
+
+    /// Protects a name with the Ed25519 `public_key` of a client
+    pub fn key(public_key: Vec<u8>) -> io::Result<Self> {
+        if public_key.len() != PUBLIC_KEY_LEN {
+            return Err(key_error());
+        }
+        Ok(Credential::Key(public_key))
+    }
+
+    /// Method of the AUTH messages that can prove the name
+    pub fn method(&self) -> u8 {
+        match self {
+            Credential::Password(_) => protocol::AUTH_PASSWORD,
+            Credential::Key(_) => protocol::AUTH_KEY,
+        }
+    }
+
+    /// Checks the `proof` sent with AUTH by a client that wants to be `name`
+    /// and was sent `nonce`
+    pub fn verify(&self, name: &str, nonce: &[u8], method: u8, proof: &[u8]) -> bool {
+        match self {
+            Credential::Password(hash) if method == protocol::AUTH_PASSWORD => {
+                match PasswordHash::new(hash) {
+                    Ok(hash) => Argon2::default().verify_password(proof, &hash).is_ok(),
+                    Err(_) => false,
+                }
+            }
+            Credential::Key(public_key) if method == protocol::AUTH_KEY => {
+                UnparsedPublicKey::new(&signature::ED25519, public_key)
+                    .verify(&signed(name, nonce), proof)
+                    .is_ok()
+            }
+            _ => false,
+        }
+    }
+}
+
+/// What a client signs to prove that `name` is his: the nonce and the name
+fn signed(name: &str, nonce: &[u8]) -> Vec<u8> {
+    let mut message = nonce.to_vec();
+    message.extend_from_slice(name.as_bytes());
+    message
+}
+
+/// File where the host keeps the accounts of `room` by default
+pub fn default_path(admin: &str, room: &str) -> PathBuf {
+    crate::room_file("accounts", admin, room, "txt")
+}
+
+/// The names registered in a room, they are saved in a file if the list has
+/// one: every line is `name<TAB>password<TAB>hash` or `name<TAB>key<TAB>hex`
+#[derive(Default)]
+pub struct Accounts {
+    path: Option<PathBuf>,
+    accounts: Vec<(String, Credential)>,
+}
+
+impl Accounts {
+    /// Accounts that are not saved anywhere
+    pub fn new() -> Self {
+        Accounts::default()
+    }
+
+    /// Loads the accounts saved at `path`, the file is created when someone
+    /// registers
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut accounts = Vec::new();
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    // Lines that can't be understood are skipped
+                    let mut fields = line.splitn(3, '\t');
+                    match (fields.next(), fields.next(), fields.next()) {
+                        (Some(name), Some("password"), Some(hash)) => {
+                            accounts.push((unescape(name), Credential::Password(hash.to_string())))
+                        }
+                        (Some(name), Some("key"), Some(key)) => {
+                            if let Some(key) = unhex(key) {
+                                accounts.push((unescape(name), Credential::Key(key)))
+                            }
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            Err(error) if error.kind() == ErrorKind::NotFound => (),
+            Err(error) => return Err(error),
+        }
+        Ok(Accounts {
+            path: Some(path),
+            accounts,
+        })
+    }
+
+    /// What protects `name`, if it's registered
+    pub fn get(&self, name: &str) -> Option<&Credential> {
+        self.accounts
+            .iter()
+            .find(|(registered, _)| registered == name)
+            .map(|(_, credential)| credential)
+    }
+
+    /// Registers `name`, or changes what protects it
+    pub fn register(&mut self, name: String, credential: Credential) -> io::Result<()> {
+        self.accounts.retain(|(registered, _)| *registered != name);
+        self.accounts.push((name, credential));
+        self.save()
+    }
+
+    /// Writes all the accounts in their file
+    fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = File::create(path)?;
+        for (name, credential) in &self.accounts {
+            match credential {
+                Credential::Password(hash) => {
+                    writeln!(file, "{}\tpassword\t{}", escape(name), hash)?
+                }
+                Credential::Key(key) => writeln!(file, "{}\tkey\t{}", escape(name), hex(key))?,
+            }
+        }
+        file.flush()
+    }
+}
+
+/// The Ed25519 key of a client, used to prove the names it registered
+pub struct Identity {
+    pair: Ed25519KeyPair,
+}
+
+impl Identity {
+    /// File where the client keeps its key by default
+    pub fn default_path() -> PathBuf {
+        crate::data_dir().join("identity.pk8")
+    }
+
+    /// Loads the key saved at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let pkcs8 = fs::read(path)?;
+        let pair = Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(|_| key_error())?;
+        Ok(Identity { pair })
+    }
+
+    /// Loads the key saved at `path`, or makes a new one and saves it there
+    pub fn open_or_create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        if path.exists() {
+            return Identity::open(path);
+        }
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| io::Error::other("Chattest accounts error: no random numbers!"))?;
+        crate::write_secret(path, pkcs8.as_ref())?;
+        Identity::open(path)
+    }
+
+    /// The public key, sent with REGISTER
+    pub fn public_key(&self) -> Vec<u8> {
+        self.pair.public_key().as_ref().to_vec()
+    }
+
+    /// Proof sent with AUTH by a client that wants to be `name` and was
+    /// sent `nonce`
+    pub fn sign(&self, name: &str, nonce: &[u8]) -> Vec<u8> {
+        self.pair.sign(&signed(name, nonce)).as_ref().to_vec()
+    }
+}
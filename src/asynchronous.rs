@@ -0,0 +1,419 @@
+use crate::connection::{refused, unexpected};
+use crate::lobby::Lobby;
+use crate::protocol::{self, Code, Reason};
+use crate::room::{Action, Id, Moderation};
+use bytes::BytesMut;
+use futures_util::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio_util::codec::{Decoder, Encoder, Framed, FramedWrite};
+
+/// Codec for `tokio_util`, the messages are framed exactly like the ones of
+/// `BlockingStream` and `NonBlockingStream`
+#[derive(Default, Debug)]
+pub struct ChattestCodec;
+
+impl Decoder for ChattestCodec {
+    type Item = Code;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Code>> {
+        match protocol::frame_len(src)? {
+            Some(len) if len <= src.len() => {
+                // Take out the whole message so the next one starts at the beginning
+                let frame = src.split_to(len);
+                protocol::decode(&frame).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+impl Encoder<Code> for ChattestCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Code, dst: &mut BytesMut) -> io::Result<()> {
+        let mut buf = Vec::new();
+        protocol::encode(&item, &mut buf);
+        dst.extend_from_slice(&buf);
+        Ok(())
+    }
+}
+
+/// Waits for the next message, the connection being closed is an error
+async fn next(framed: &mut Framed<TcpStream, ChattestCodec>) -> io::Result<Code> {
+    match framed.next().await {
+        Some(result) => result,
+        None => Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "Chattest stream error: connection closed!",
+        )),
+    }
+}
+
+/// A client that joined a room
+pub struct Client {
+    framed: Framed<TcpStream, ChattestCodec>,
+    room: String,
+    admin: String,
+}
+
+impl Client {
+    /// Connects to the room at `addr` and joins it as `name`, the error is
+    /// `AlreadyExists` if someone else in the room uses that name
+    pub async fn connect<A: ToSocketAddrs>(addr: A, name: String) -> io::Result<Self> {
+        Self::connect_with_password(addr, name, None).await
+    }
+
+    /// Like `connect`, but it gives the `password` if the room asks for it.
+    /// The error is `PermissionDenied` if the room has a password and it's
+    /// `None` or if the name is registered, and `ConnectionRefused` if the
+    /// password is wrong
+    pub async fn connect_with_password<A: ToSocketAddrs>(
+        addr: A,
+        name: String,
+        password: Option<&str>,
+    ) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        let mut framed = Framed::new(stream, ChattestCodec);
+
+        // Tell the server which version of the protocol is used, this
+        // client can't receive files or resume the session
+        let offered = protocol::CAPABILITIES & !(protocol::CAP_FILES | protocol::CAP_RESUME);
+        framed.send(Code::Hello(protocol::VERSION, offered)).await?;
+        match next(&mut framed).await? {
+            Code::Hello(version, _) if version >= protocol::MIN_VERSION => (),
+            Code::Hello(..) => return Err(refused(Reason::UnsupportedVersion)),
+            Code::Rejected(reason) => return Err(refused(reason)),
+            code => return Err(unexpected(code)),
+        }
+
+        framed.send(Code::Name(name)).await?;
+        let mut response = next(&mut framed).await?;
+        // Prove that the password is known if the room has one
+        if let Code::Challenge(nonce) = &response {
+            let password = password.ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    "Chattest error: the room has a password!",
+                )
+            })?;
+            framed
+                .send(Code::Answer(protocol::prove(password, nonce)))
+                .await?;
+            response = next(&mut framed).await?;
+        }
+        match response {
+            Code::Welcome(room, admin) => Ok(Client {
+                framed,
+                room,
+                admin,
+            }),
+            Code::AlreadyHere => Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                "Chattest error: there is already someone with this name!",
+            )),
+            Code::AuthRequired(..) => Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "Chattest error: the name is registered!",
+            )),
+            Code::Rejected(reason) => Err(refused(reason)),
+            code => Err(unexpected(code)),
+        }
+    }
+
+    /// Name of the room
+    pub fn room(&self) -> &str {
+        &self.room
+    }
+
+    /// Name of the admin of the room
+    pub fn admin(&self) -> &str {
+        &self.admin
+    }
+
+    /// Sends a text message to the room
+    pub async fn send(&mut self, text: String) -> io::Result<()> {
+        self.framed.send(Code::MessageTo(text)).await
+    }
+
+    /// Sends a private text message to the participant `to`
+    pub async fn send_direct(&mut self, to: String, text: String) -> io::Result<()> {
+        self.framed.send(Code::DirectTo(to, text)).await
+    }
+
+    /// Changes the text `id` sent before, the room responds with `Edit`
+    pub async fn edit(&mut self, id: u64, text: String) -> io::Result<()> {
+        self.framed.send(Code::Edit(id, text)).await
+    }
+
+    /// Deletes the text `id` sent before, the room responds with `Delete`
+    pub async fn delete(&mut self, id: u64) -> io::Result<()> {
+        self.framed.send(Code::Delete(id)).await
+    }
+
+    /// Asks to be called `name` from now on, the room responds with
+    /// `Renamed` or with `AlreadyHere` if the name is taken
+    pub async fn rename(&mut self, name: String) -> io::Result<()> {
+        self.framed.send(Code::Nick(name)).await
+    }
+
+    /// Tells the room that the text with the id `id` arrived, the texts
+    /// with an id are received as `Stamped`
+    pub async fn ack(&mut self, id: u64) -> io::Result<()> {
+        self.framed.send(Code::Ack(id)).await
+    }
+
+    /// Waits for the next message of the room, `None` means that the
+    /// connection was closed
+    pub async fn recv(&mut self) -> Option<io::Result<Code>> {
+        self.framed.next().await
+    }
+}
+
+/// What the tasks of the server tell to the one that owns the room, the
+/// commands of an admin start with the name of his room
+enum Input {
+    Connected(Id, SocketAddr, UnboundedSender<Output>),
+    Received(Id, Code),
+    Disconnected(Id),
+    Say(String, String),
+    Whisper(String, String, String),
+    Moderate(String, Moderation),
+}
+
+/// What the room asks to the task that writes to a client
+enum Output {
+    Send(Code),
+    Close,
+}
+
+/// One or more rooms served by tasks spawned on the current Tokio runtime,
+/// they are stopped when the `Server` is dropped
+pub struct Server {
+    inputs: UnboundedSender<Input>,
+    events: UnboundedReceiver<String>,
+    local_addr: SocketAddr,
+    tasks: [JoinHandle<()>; 2],
+}
+
+impl Server {
+    /// Binds `addr` and starts serving `rooms` (a `Room` or a `Lobby`)
+    pub async fn bind<A: ToSocketAddrs>(addr: A, rooms: impl Into<Lobby>) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let (inputs, inputs_rx) = mpsc::unbounded_channel();
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let tasks = [
+            tokio::spawn(accept(listener, inputs.clone(), events_tx.clone())),
+            tokio::spawn(run(rooms.into(), inputs_rx, events_tx)),
+        ];
+        Ok(Server {
+            inputs,
+            events,
+            local_addr,
+            tasks,
+        })
+    }
+
+    /// Address the server is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Sends a message of the admin of the room called `room`
+    pub fn say(&self, room: &str, text: String) {
+        let _ = self.inputs.send(Input::Say(room.to_string(), text));
+    }
+
+    /// Sends a private message of the admin of the room called `room` to
+    /// the participant `to`
+    pub fn whisper(&self, room: &str, to: String, text: String) {
+        let _ = self.inputs.send(Input::Whisper(room.to_string(), to, text));
+    }
+
+    /// Kicks, bans or mutes someone in the room called `room`
+    pub fn moderate(&self, room: &str, command: Moderation) {
+        let _ = self.inputs.send(Input::Moderate(room.to_string(), command));
+    }
+
+    /// Waits for the next line that describes what happens in the room
+    pub async fn event(&mut self) -> Option<String> {
+        self.events.recv().await
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Accepts the clients and starts the tasks that read and write their messages
+async fn accept(
+    listener: TcpListener,
+    inputs: UnboundedSender<Input>,
+    events: UnboundedSender<String>,
+) {
+    let mut next = 0;
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                let _ = events.send(format!("  Accept error: {}", error));
+                continue;
+            }
+        };
+        let id = next;
+        next += 1;
+        let (reader, writer) = stream.into_split();
+        let (outputs, outputs_rx) = mpsc::unbounded_channel();
+        // The room must know about the client before his messages arrive
+        if inputs.send(Input::Connected(id, addr, outputs)).is_err() {
+            return;
+        }
+        tokio::spawn(write_loop(writer, outputs_rx));
+        tokio::spawn(read_loop(id, reader, inputs.clone(), events.clone()));
+    }
+}
+
+async fn read_loop(
+    id: Id,
+    mut reader: OwnedReadHalf,
+    inputs: UnboundedSender<Input>,
+    events: UnboundedSender<String>,
+) {
+    let mut codec = ChattestCodec;
+    let mut buf = BytesMut::new();
+    'read: loop {
+        // Decode every message that arrived completely
+        loop {
+            match codec.decode(&mut buf) {
+                Ok(Some(code)) => {
+                    if inputs.send(Input::Received(id, code)).is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => break,
+                // The client sent something that can't be decoded, skip it
+                Err(error) => {
+                    let _ = events.send(format!("  Error with client {}: {}", id, error));
+                    // A message too big to be collected can't be skipped
+                    if protocol::frame_len(&buf).is_err() {
+                        break 'read;
+                    }
+                }
+            }
+        }
+        match reader.read_buf(&mut buf).await {
+            Ok(0) => break,
+            Ok(_) => (),
+            Err(error) => {
+                let _ = events.send(format!(
+                    "  Error with client {}: {}({:?})",
+                    id,
+                    error,
+                    error.kind()
+                ));
+                break;
+            }
+        }
+    }
+    let _ = inputs.send(Input::Disconnected(id));
+}
+
+async fn write_loop(writer: OwnedWriteHalf, mut outputs: UnboundedReceiver<Output>) {
+    let mut writer = FramedWrite::new(writer, ChattestCodec);
+    while let Some(output) = outputs.recv().await {
+        match output {
+            Output::Send(code) => {
+                if writer.send(code).await.is_err() {
+                    break;
+                }
+            }
+            Output::Close => break,
+        }
+    }
+    let _ = writer.into_inner().shutdown().await;
+}
+
+/// Owns the rooms and turns their actions into outputs for the clients
+async fn run(
+    mut lobby: Lobby,
+    mut inputs: UnboundedReceiver<Input>,
+    events: UnboundedSender<String>,
+) {
+    let mut connections = HashMap::new();
+    loop {
+        // Wait for the next input, or until someone away must be forgotten
+        let input = match lobby.next_expiry() {
+            Some(wait) => match tokio::time::timeout(wait, inputs.recv()).await {
+                Ok(input) => input,
+                Err(_) => {
+                    let actions = lobby.expire();
+                    apply(actions, &mut connections, &events);
+                    continue;
+                }
+            },
+            None => inputs.recv().await,
+        };
+        let actions = match input {
+            Some(Input::Connected(id, addr, outputs)) => {
+                connections.insert(id, outputs);
+                lobby.connected(id, addr)
+            }
+            Some(Input::Received(id, code)) => lobby.received(id, code),
+            Some(Input::Disconnected(id)) => {
+                connections.remove(&id);
+                lobby.disconnected(id)
+            }
+            Some(Input::Say(room, text)) => lobby.say(&room, text),
+            Some(Input::Whisper(room, to, text)) => lobby.whisper(&room, to, text),
+            Some(Input::Moderate(room, command)) => lobby.moderate(&room, command),
+            None => break,
+        };
+        apply(actions, &mut connections, &events);
+        let actions = lobby.expire();
+        apply(actions, &mut connections, &events);
+    }
+}
+
+/// Turns the actions of the rooms into outputs for the clients
+fn apply(
+    actions: Vec<Action>,
+    connections: &mut HashMap<Id, UnboundedSender<Output>>,
+    events: &UnboundedSender<String>,
+) {
+    for action in actions {
+        match action {
+            Action::Send(id, code) => {
+                if let Some(outputs) = connections.get(&id) {
+                    let _ = outputs.send(Output::Send(code));
+                }
+            }
+            Action::Close(id) => {
+                if let Some(outputs) = connections.remove(&id) {
+                    let _ = outputs.send(Output::Close);
+                }
+            }
+            Action::Log(line) => {
+                // Nobody is listening if the server is gone
+                let _ = events.send(line);
+            }
+            // This server never offers TLS
+            Action::StartTls(id) => {
+                if let Some(outputs) = connections.remove(&id) {
+                    let _ = outputs.send(Output::Close);
+                }
+            }
+        }
+    }
+}
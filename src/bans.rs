@@ -0,0 +1,144 @@
+use crate::history::{escape, unescape};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+/// Who can't join a room
+#[derive(Clone, Debug, PartialEq)]
+pub enum Ban {
+    /// Nobody can join with this name
+    Name(String),
+    /// Nobody can join from this address
+    Ip(IpAddr),
+}
+
+impl Ban {
+    /// An IP address if `target` is one, a name otherwise
+    pub fn parse(target: &str) -> Self {
+        match target.parse::<IpAddr>() {
+            // Written as IPv4 if it is one, like it's shown to the host
+            Ok(ip) => Ban::Ip(ip.to_canonical()),
+            Err(_) => Ban::Name(target.to_string()),
+        }
+    }
+
+    /// Checks if the ban applies to a client called `name` (if he has a
+    /// name yet) that connected from `ip`
+    pub fn applies(&self, name: Option<&str>, ip: IpAddr) -> bool {
+        match self {
+            Ban::Name(banned) => name == Some(banned.as_str()),
+            // The IPv4 clients of a socket that listens on IPv6 too have
+            // an IPv4-mapped address
+            Ban::Ip(banned) => banned.to_canonical() == ip.to_canonical(),
+        }
+    }
+
+    /// The same ban with the address written as IPv4 if it is one
+    fn canonical(self) -> Self {
+        match self {
+            Ban::Ip(ip) => Ban::Ip(ip.to_canonical()),
+            ban => ban,
+        }
+    }
+}
+
+/// File where the host keeps the bans of `room` by default
+pub fn default_path(admin: &str, room: &str) -> PathBuf {
+    crate::room_file("bans", admin, room, "txt")
+}
+
+/// The bans of a room, they are saved in a file if the list has one:
+/// every line is `name<TAB>name` or `ip<TAB>address`
+#[derive(Default)]
+pub struct BanList {
+    path: Option<PathBuf>,
+    bans: Vec<Ban>,
+}
+
+impl BanList {
+    /// A list that is not saved anywhere
+    pub fn new() -> Self {
+        BanList::default()
+    }
+
+    /// Loads the bans saved at `path`, the file is created when the list
+    /// changes
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut bans = Vec::new();
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    // Lines that can't be understood are skipped
+                    match line.split_once('\t') {
+                        Some(("name", name)) => bans.push(Ban::Name(unescape(name))),
+                        Some(("ip", ip)) => {
+                            if let Ok(ip) = ip.parse::<IpAddr>() {
+                                bans.push(Ban::Ip(ip.to_canonical()))
+                            }
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            Err(error) if error.kind() == ErrorKind::NotFound => (),
+            Err(error) => return Err(error),
+        }
+        Ok(BanList {
+            path: Some(path),
+            bans,
+        })
+    }
+
+    pub fn bans(&self) -> &[Ban] {
+        &self.bans
+    }
+
+    /// Checks if a client called `name` (if he has a name yet) that connected
+    /// from `ip` is banned
+    pub fn is_banned(&self, name: Option<&str>, ip: IpAddr) -> bool {
+        self.bans.iter().any(|ban| ban.applies(name, ip))
+    }
+
+    /// Adds a ban, returns `false` if it was already there
+    pub fn add(&mut self, ban: Ban) -> io::Result<bool> {
+        let ban = ban.canonical();
+        if self.bans.contains(&ban) {
+            return Ok(false);
+        }
+        self.bans.push(ban);
+        self.save().map(|_| true)
+    }
+
+    /// Removes a ban, returns `false` if it wasn't there
+    pub fn remove(&mut self, ban: &Ban) -> io::Result<bool> {
+        let ban = ban.clone().canonical();
+        let len = self.bans.len();
+        self.bans.retain(|other| *other != ban);
+        if self.bans.len() == len {
+            return Ok(false);
+        }
+        self.save().map(|_| true)
+    }
+
+    /// Writes the whole list in its file
+    fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = File::create(path)?;
+        for ban in &self.bans {
+            match ban {
+                Ban::Name(name) => writeln!(file, "name\t{}", escape(name))?,
+                Ban::Ip(ip) => writeln!(file, "ip\t{}", ip)?,
+            }
+        }
+        file.flush()
+    }
+}
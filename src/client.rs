@@ -1,26 +1,82 @@
 use crate::*;
-use std::io::ErrorKind;
-use std::net::TcpStream;
+use accounts::Identity;
+use chattest::discovery;
+use chattest::e2e::{self, KnownKeys};
+use chattest::files;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long the servers on the network have to respond
+const DISCOVERY_WAIT: Duration = Duration::from_millis(500);
+/// How long to wait before connecting again after the connection was lost,
+/// it doubles every time it fails
+const RETRY_WAIT: Duration = Duration::from_secs(1);
+/// The longest wait between two attempts to connect again
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(30);
+/// How many times to try to connect again before giving up
+const MAX_RETRIES: u32 = 10;
 
 pub fn chat(win: &Window, name: &mut std::string::String) -> bool {
-    let mut stream;
+    // Look for the rooms on the network, so that he doesn't need to know
+    // their address
+    win.printw("  Looking for rooms on the network...");
+    win.refresh();
+    let found = discovery::discover(protocol::PORT, DISCOVERY_WAIT).unwrap_or_default();
+    win.mv(0, 0);
+    win.clrtobot();
+
+    // The room he picked, if he picked one
+    let mut room: Option<String>;
+    let mut connection;
     while {
-        // Get the IP address of the room he wants to connect to
-        win.printw("  What's the address of the room?\n  [press ESC to return to menu]\n > ");
-        // .subwin(1, 15, win.get_cur_y(), win.get_cur_x()).unwrap()
-        let (ip, esc) = get_string(&win);
-        // Remove the text but don't update the screen
-        win.mv(0, 0);
-        win.clrtobot();
-        if esc {
-            return false;
-        }
-        // Connect to that address
-        stream = TcpStream::connect(ip + ":7357");
-        stream.is_err()
+        // Let him pick one of the rooms that were found
+        let picked = if found.is_empty() {
+            None
+        } else {
+            win.printw("  Rooms found on the network\n  [press ESC to return to menu]\n\n");
+            let mut items: Vec<String> = found
+                .iter()
+                .map(|found| format!("{} by {} ({})", found.room, found.admin, found.addr.ip()))
+                .collect();
+            items.push("Write an address".to_string());
+            let picked = choose(win, 3, &items);
+            win.mv(0, 0);
+            win.clrtobot();
+            match picked {
+                Some(picked) => found.get(picked),
+                None => return false,
+            }
+        };
+        room = picked.map(|found| found.room.clone());
+        connection = match picked {
+            Some(found) => connection::Connection::connect(found.addr),
+            None => {
+                // Get the IP address of the room he wants to connect to
+                win.printw("  What's the address of the room?\n  [host or host:port, press ESC to return to menu]\n > ");
+                // .subwin(1, 15, win.get_cur_y(), win.get_cur_x()).unwrap()
+                let (addr, esc) = get_string(win);
+                // Remove the text but don't update the screen
+                win.mv(0, 0);
+                win.clrtobot();
+                if esc {
+                    return false;
+                }
+                // Connect to that address, the port can be left out
+                protocol::parse_addr(&addr, protocol::PORT)
+                    .and_then(connection::Connection::connect)
+            }
+        };
+        connection.is_err()
     } {
         // Notify the error
-        win.printw("  Couldn't connect to the server!");
+        win.printw(format!(
+            "  Couldn't connect to the server!\n  {}",
+            connection.err().unwrap()
+        ));
         noecho();
         win.getch();
         echo();
@@ -28,21 +84,121 @@ pub fn chat(win: &Window, name: &mut std::string::String) -> bool {
         win.mv(0, 0);
         win.clrtobot();
     }
-    // Unwrap the stream because it's safe to do it now
-    let mut stream = chattest::BlockingStream::new(stream.unwrap());
+    // Unwrap the connection because it's safe to do it now
+    let mut connection = connection.unwrap();
     println!("Connected!");
 
-    let mut result;
-    while {
+    // Let him pick one of the rooms of the server
+    let rooms = if room.is_none() && connection.capabilities() & protocol::CAP_ROOMS != 0 {
+        connection.rooms().unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+    if !rooms.is_empty() {
+        win.printw("  Which room do you want to join?\n  [press ESC to return to the menu]\n\n");
+        let items: Vec<String> = rooms
+            .iter()
+            .map(|room| {
+                let mut item = match room.members {
+                    1 => format!("{} (1 user", room.name),
+                    count => format!("{} ({} users", room.name, count),
+                };
+                if room.password {
+                    item.push_str(", password");
+                }
+                item + ")"
+            })
+            .collect();
+        let picked = choose(win, 3, &items);
+        win.mv(0, 0);
+        win.clrtobot();
+        match picked {
+            // The first room is the one joined anyway
+            Some(0) => (),
+            Some(picked) => room = Some(rooms[picked].name.clone()),
+            None => return false,
+        }
+    }
+    if let Some(room) = room.filter(|_| connection.capabilities() & protocol::CAP_ROOMS != 0) {
+        if let Err(error) = connection.pick_room(&room) {
+            win.printw(format!(
+                "  {}\n  [press any key to return to the menu]",
+                error
+            ));
+            win.getch();
+            win.mv(0, 0);
+            win.clrtobot();
+            return false;
+        }
+    }
+
+    let mut session = loop {
         // Send the name of the user to the server
-        stream.write(chattest::Code::Name(name.clone())).unwrap();
-        result = stream.read().unwrap();
-        result == chattest::Code::AlreadyHere
-    } {
+        let mut joined = connection.join(name.clone());
+        // Ask for the password if the room has one
+        if let Ok(connection::Join::PasswordRequired(asking)) = joined {
+            win.printw("  The room has a password\n  [press ESC to return to the menu]\n > ");
+            let (password, esc) = get_secret(win);
+            win.mv(0, 0);
+            win.clrtobot();
+            if esc {
+                return false;
+            }
+            joined = asking.answer(&password);
+        }
+        // Prove that the name is his if it's registered
+        if let Ok(connection::Join::AuthRequired(asking)) = joined {
+            joined = match asking.auth_method() {
+                Some(protocol::AUTH_KEY) => match Identity::open(Identity::default_path()) {
+                    Ok(identity) => asking.auth_key(&identity),
+                    Err(_) => Err(io::Error::new(
+                        ErrorKind::NotFound,
+                        "The name is registered with a key that is not on this computer!",
+                    )),
+                },
+                // Anyone could read the password
+                _ if asking.fingerprint().is_none() => Err(io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    "The name is registered with a password and the connection is not encrypted!",
+                )),
+                _ => {
+                    win.printw("  The name is registered, write its password\n");
+                    win.printw("  [press ESC to return to the menu]\n > ");
+                    let (password, esc) = get_secret(win);
+                    win.mv(0, 0);
+                    win.clrtobot();
+                    if esc {
+                        return false;
+                    }
+                    asking.auth_password(&password)
+                }
+            };
+        }
+        match joined {
+            Ok(connection::Join::Joined(session)) => break session,
+            Ok(connection::Join::NameTaken(taken)) => connection = taken,
+            Ok(connection::Join::PasswordRequired(_) | connection::Join::AuthRequired(_)) => {
+                win.printw("  The room keeps asking for the password!\n  [press any key to return to the menu]");
+                win.getch();
+                win.mv(0, 0);
+                win.clrtobot();
+                return false;
+            }
+            Err(error) => {
+                win.printw(format!(
+                    "  {}\n  [press any key to return to the menu]",
+                    error
+                ));
+                win.getch();
+                win.mv(0, 0);
+                win.clrtobot();
+                return false;
+            }
+        }
         win.printw("  There is already someone with your name!\n  Write a new name\n  [press ESC to return to the menu]\n > ");
         let (string, esc) =
             // &win.subwin(1, 30, win.get_cur_y(), win.get_cur_x()).unwrap()
-            get_string(&win);
+            get_string(win);
         if esc {
             return false;
         }
@@ -50,117 +206,799 @@ pub fn chat(win: &Window, name: &mut std::string::String) -> bool {
         // Remove the text
         win.mv(0, 0);
         win.clrtobot();
-    }
-    let admin = match result {
-        chattest::Code::Welcome(room, admin) => {
-            win.printw("  Connected to room ");
-            win.printw(room);
-            win.printw("\n The admin is ");
-            win.printw(&admin);
-            win.refresh();
-            admin
-        }
-        _ => panic!("Server didn't respond correctly"),
     };
-    let mut stream = stream.non_blocking();
-    win.nodelay(true);
+    win.printw("  Connected to room ");
+    win.printw(session.room());
+    win.printw("\n The admin is ");
+    win.printw(session.admin());
+    win.refresh();
+    let admin = session.admin().to_string();
+    // Wait for the keys only for a while, so that new messages are shown
+    win.timeout(50);
 
-    win.mvprintw(LAST, 0, " > ");
+    let mut messages = Messages::new();
+    // Tell if what is said can be read by someone else
+    match session.fingerprint() {
+        Some(fingerprint) => messages.push(format!("  Encrypted, fingerprint: {}", fingerprint)),
+        None => messages.push("  The connection is not encrypted!".to_string()),
+    };
+    if let Some(group) = session.group() {
+        messages.push(format!(
+            "  End-to-end encrypted, your key: {}",
+            group.fingerprint()
+        ));
+    }
+    // The keys of the others must be the same every time
+    let known_keys = KnownKeys::new(KnownKeys::default_path());
+    let host = session.peer_addr().to_string();
+    // Press Tab to show or hide the users in the room
+    let mut users = Users::new(admin.clone());
+    users.draw(win);
+    messages.draw(win, "", 0);
 
-    let mut messages = Vec::new();
-    let mut selected = 0;
+    // His texts that the room didn't send back yet: where they are, what
+    // comes before them and what was sent
+    let mut pending: Vec<(usize, String, String)> = Vec::new();
+    // The texts with an id, to change them when they are edited
+    let mut shown: HashMap<u64, Shown> = HashMap::new();
+    let mut ids = session.capabilities() & protocol::CAP_IDS != 0;
+    // The files he offered: their names and sizes
+    let mut offers: HashMap<u32, (String, u64)> = HashMap::new();
+    // The files offered to him that he didn't answer yet
+    let mut offered: HashMap<u64, (String, String, u64)> = HashMap::new();
+    // The files being sent or received, with the line of their progress
+    let mut transfers: HashMap<u64, Progress> = HashMap::new();
+
+    // When to connect again, if the connection was lost
+    let mut reconnect: Option<Reconnect> = None;
+    // What he said while the connection was lost: where it is, who it's for
+    // if it's private and the text
+    let mut queued: Vec<(usize, Option<String>, String)> = Vec::new();
 
     let mut string = String::new();
     let mut cursor = 0;
     loop {
-        match stream.try_read() {
-            Ok(Some(code)) => {
-                match code {
-                    chattest::Code::MessageFrom(name, message) => {
-                        println!("{}> {}", name, message);
-                        messages.push(format!("  {}> {}", name, message));
-                    }
-                    chattest::Code::MessageTo(message) => {
-                        println!("{}# {}", admin, message);
-                        messages.push(format!("  {}# {}", admin, message));
-                    }
-                    _ => println!("Strange code: {:?}", code),
-                }
-                if messages.len() == 1 {
-                    win.mvprintw(3, 0, messages.last().unwrap());
-                    win.clrtobot();
-                    win.mvprintw(LAST, 0, " > ");
-                    win.printw(&string);
-                    win.mv(LAST, 3 + cursor as i32);
-                } else if messages.len() == selected + 2 {
-                    selected += 1;
-                    win.mvprintw(3, 0, messages.last().unwrap());
-                    win.clrtobot();
-                    win.mvprintw(LAST, 0, " > ");
-                    win.printw(&string);
-                    win.mv(LAST, 3 + cursor as i32);
-                }
+        // Try to connect again once it's time, without stopping the keys
+        if let Some(lost) = reconnect.as_mut() {
+            if lost.due() {
+                lost.attempt(&session);
+                messages.set(
+                    lost.idx,
+                    "  Connection lost! Connecting again... [press ESC to return to the menu]"
+                        .to_string(),
+                );
+                messages.draw(win, &string, cursor);
             }
-            Ok(None) => (),
-            Err(error) => match error.kind() {
-                ErrorKind::ConnectionReset => {
+            match lost.result() {
+                None => (),
+                Some(Ok(resumed)) => {
+                    session.replace(resumed);
+                    messages.set(lost.idx, "  Connection lost! Connected again".to_string());
+                    messages.draw(win, &string, cursor);
+                    ids = session.capabilities() & protocol::CAP_IDS != 0;
+                    reconnect = None;
+                }
+                // He can't join again with the same name
+                Some(Err(error))
+                    if lost.attempts == MAX_RETRIES
+                        || matches!(
+                            error.kind(),
+                            ErrorKind::AlreadyExists | ErrorKind::PermissionDenied
+                        ) =>
+                {
                     win.mvprintw(
                         0,
                         0,
-                        "  Connection lost!\n  [press any key to return to the menu]\n",
+                        format!(
+                            "  Connection lost!\n  {}\n  [press any key to return to the menu]\n",
+                            error
+                        ),
                     );
                     win.clrtobot();
                     win.nodelay(false);
                     win.getch();
                     return false;
                 }
-                _ => println!("Error: {}", error),
+                Some(Err(error)) => {
+                    lost.retry();
+                    messages.set(lost.idx, lost.line(Some(&error)));
+                    messages.draw(win, &string, cursor);
+                }
+            }
+        }
+        let read = match reconnect {
+            Some(_) => Ok(None),
+            None => session.try_read(),
+        };
+        match read {
+            Ok(Some(code)) => {
+                let moved = match code {
+                    protocol::Code::MessageFrom(..)
+                    | protocol::Code::MessageTo(_)
+                    | protocol::Code::DirectFrom(..)
+                    | protocol::Code::EncryptedFrom(..) => match text(&admin, &code) {
+                        Some((who, text)) => messages.push(format!("  {}{}", who, text)),
+                        None => false,
+                    },
+                    protocol::Code::Stamped(id, time, code) => {
+                        let clock = history::clock(time);
+                        // His texts are sent back once the room relayed them
+                        let own = match &*code {
+                            protocol::Code::MessageFrom(from, message) if *from == *name => {
+                                Some(message)
+                            }
+                            protocol::Code::DirectTo(_, message) => Some(message),
+                            _ => None,
+                        };
+                        let stamped = match own {
+                            Some(message) => {
+                                let pos = pending.iter().position(|(.., other)| other == message);
+                                // The ones sent before it were refused
+                                pos.and_then(|pos| pending.drain(..=pos).next_back())
+                                    .map(|(idx, who, text)| Shown::own(idx, &clock, who, text))
+                            }
+                            None => text(&admin, &code)
+                                .map(|(who, text)| Shown::new(messages.len(), &clock, who, text)),
+                        };
+                        match stamped {
+                            Some(stamped) => {
+                                let moved = if stamped.idx == messages.len() {
+                                    messages.push(stamped.line())
+                                } else {
+                                    messages.set(stamped.idx, stamped.line())
+                                };
+                                shown.insert(id, stamped);
+                                moved
+                            }
+                            None => false,
+                        }
+                    }
+                    protocol::Code::Delivered(id, _) => match shown.get_mut(&id) {
+                        Some(text) => {
+                            text.received += 1;
+                            messages.set(text.idx, text.line())
+                        }
+                        None => false,
+                    },
+                    protocol::Code::Edit(id, new) => match shown.get_mut(&id) {
+                        Some(text) => {
+                            text.text = new;
+                            text.edited = true;
+                            messages.set(text.idx, text.line())
+                        }
+                        // It arrived before the client joined
+                        None => messages.push(format!("  A message was edited: {}", new)),
+                    },
+                    protocol::Code::Delete(id) => match shown.remove(&id) {
+                        Some(text) => messages.set(text.idx, format!("{}(deleted)", text.who)),
+                        None => messages.push("  A message was deleted".to_string()),
+                    },
+                    protocol::Code::FileOffered(transfer, from, file, size, _) => {
+                        let line = format!(
+                            "  {} offers the file {} ({} bytes), write /accept {} or /refuse {}",
+                            from, file, size, transfer, transfer
+                        );
+                        offered.insert(transfer, (file, from, size));
+                        messages.push(line)
+                    }
+                    protocol::Code::FileAnswered(offer, transfer, accepted, who) => {
+                        match offers.get(&offer) {
+                            Some((file, size)) if accepted => {
+                                let progress = Progress {
+                                    idx: messages.len(),
+                                    label: format!("Sending {} to {}", file, who),
+                                    size: *size,
+                                    saved: None,
+                                };
+                                let moved = messages.push(progress.line(0));
+                                transfers.insert(transfer, progress);
+                                moved
+                            }
+                            Some((file, _)) => messages.push(format!("  {} refused {}", who, file)),
+                            None => false,
+                        }
+                    }
+                    protocol::Code::FileReceived(transfer, bytes) => {
+                        match transfers.get(&transfer) {
+                            Some(progress) => {
+                                let moved = messages.set(progress.idx, progress.line(bytes));
+                                if bytes >= progress.size {
+                                    transfers.remove(&transfer);
+                                }
+                                moved
+                            }
+                            None => false,
+                        }
+                    }
+                    protocol::Code::FileCancel(transfer, reason) => {
+                        if let Some(progress) = transfers.remove(&transfer) {
+                            messages.set(
+                                progress.idx,
+                                format!("  {}: stopped, {}", progress.label, reason),
+                            )
+                        } else if let Some((file, from, _)) = offered.remove(&transfer) {
+                            messages.push(format!("  {} took back the file {}", from, file))
+                        } else {
+                            false
+                        }
+                    }
+                    protocol::Code::History(entries) => {
+                        // Past messages are shown with the time they were sent
+                        for entry in entries {
+                            let mark = if entry.sender == admin { '#' } else { '>' };
+                            let clock = history::clock(entry.time);
+                            let who = format!("{}{} ", entry.sender, mark);
+                            let mut past = if entry.sender == *name {
+                                Shown::own(messages.len(), &clock, who, entry.text)
+                            } else {
+                                Shown::new(messages.len(), &clock, who, entry.text)
+                            };
+                            past.edited = entry.change == history::Change::Edited;
+                            messages.push(past.line());
+                            // They can be edited and deleted like the new ones
+                            if entry.id != 0 {
+                                shown.insert(entry.id, past);
+                            }
+                        }
+                        messages.last();
+                        true
+                    }
+                    protocol::Code::NoSuchUser(name) => {
+                        messages.push(format!("  There is no user called {}!", name))
+                    }
+                    protocol::Code::UserList(names) => {
+                        users.set(names);
+                        users.draw(win);
+                        true
+                    }
+                    protocol::Code::UserJoined(name) => {
+                        let line = format!("  User {} connected!", name);
+                        users.joined(name);
+                        users.draw(win);
+                        messages.push(line);
+                        true
+                    }
+                    protocol::Code::UserLeft(name) => {
+                        users.left(&name);
+                        users.draw(win);
+                        messages.push(format!("  User {} disconnected!", name));
+                        true
+                    }
+                    protocol::Code::Renamed(old, new) => {
+                        let line = if old == *name {
+                            *name = new.clone();
+                            format!("  You are now called {}", new)
+                        } else {
+                            format!("  User {} is now called {}", old, new)
+                        };
+                        users.renamed(&old, new);
+                        users.draw(win);
+                        messages.push(line);
+                        true
+                    }
+                    protocol::Code::Verified(verified) => {
+                        users.verified(verified.clone());
+                        users.draw(win);
+                        if verified == *name {
+                            messages.push(format!("  Your name {} is verified", verified))
+                        } else {
+                            false
+                        }
+                    }
+                    protocol::Code::MemberKey(member, key) => {
+                        let fingerprint = e2e::fingerprint(&key);
+                        match known_keys.pin(&host, &member, &fingerprint) {
+                            Ok(e2e::Pinned::Known) => false,
+                            Ok(e2e::Pinned::New) => messages.push(format!(
+                                "  The key of {} is {}, check it with /fingerprint {}",
+                                member, fingerprint, member
+                            )),
+                            Ok(e2e::Pinned::Changed(known)) => messages.push(format!(
+                                "  WARNING: the key of {} changed from {} to {}!",
+                                member, known, fingerprint
+                            )),
+                            Err(error) => {
+                                messages.push(format!("  Couldn't check the key: {}", error))
+                            }
+                        }
+                    }
+                    protocol::Code::Rekey(_) | protocol::Code::GroupKeyFrom(..) => false,
+                    protocol::Code::AlreadyHere => {
+                        messages.push("  There is already someone with that name!".to_string())
+                    }
+                    protocol::Code::Muted(0) => messages.push("  You can talk again!".to_string()),
+                    protocol::Code::Muted(protocol::MUTED_FOREVER) => {
+                        messages.push("  You are muted!".to_string())
+                    }
+                    protocol::Code::Muted(seconds) => {
+                        messages.push(format!("  You are muted for {} more seconds!", seconds))
+                    }
+                    protocol::Code::Kicked | protocol::Code::Banned => {
+                        let what = if code == protocol::Code::Kicked {
+                            "kicked from"
+                        } else {
+                            "banned from"
+                        };
+                        win.mvprintw(
+                            0,
+                            0,
+                            format!(
+                                "  You were {} the room!\n  [press any key to return to the menu]\n",
+                                what
+                            ),
+                        );
+                        win.clrtobot();
+                        win.nodelay(false);
+                        win.getch();
+                        return false;
+                    }
+                    _ => messages.push(format!("  Strange code: {:?}", code)),
+                };
+                if moved {
+                    messages.draw(win, &string, cursor);
+                }
+            }
+            Ok(None) => (),
+            Err(error) => match error.kind() {
+                // Keep the messages and try to connect again
+                ErrorKind::ConnectionReset => {
+                    reconnect = Some(Reconnect::start(&mut messages));
+                    messages.draw(win, &string, cursor);
+                }
+                _ => {
+                    messages.push(format!("  Error: {}", error));
+                    messages.draw(win, &string, cursor);
+                }
             },
         }
-        if let Some(input) = try_get_string(&win, &mut string, &mut cursor) {
+        // Send what he said while the connection was lost
+        while reconnect.is_none() && !queued.is_empty() {
+            let (_, to, text) = &queued[0];
+            let sent = match to {
+                Some(to) => session.send_direct(to.clone(), text.clone()),
+                None => session.send(text.clone()),
+            };
+            // The room didn't send the key of the group yet
+            if sent
+                .as_ref()
+                .is_err_and(|error| error.kind() == ErrorKind::WouldBlock)
+            {
+                break;
+            }
+            let (idx, to, text) = queued.remove(0);
+            let who = to.map_or_else(String::new, |to| format!("(to {}) ", to));
+            let mut dropped = false;
+            let line = match sent {
+                Ok(()) => {
+                    // Wait for the room to send it back
+                    if ids {
+                        pending.push((idx, who.clone(), text.clone()));
+                    }
+                    format!("  {}{}", who, text)
+                }
+                Err(error) => not_sent(error, &mut dropped),
+            };
+            if messages.set(idx, line) {
+                messages.draw(win, &string, cursor);
+            }
+            // Lost again, the rest waits for the next time
+            if dropped {
+                reconnect = Some(Reconnect::start(&mut messages));
+                messages.draw(win, &string, cursor);
+            }
+        }
+        if let Some(input) = try_get_string(win, &mut string, &mut cursor) {
             match input {
+                // He doesn't want to wait for the connection
+                Input::Character('\u{1b}') if reconnect.is_some() => {
+                    win.nodelay(false);
+                    return false;
+                }
+                // Keep the texts until the connection is back
+                Input::Character('\n') if string.len() > 1 && reconnect.is_some() => {
+                    let line = match command(&string) {
+                        Some(("msg", args)) => match first_word(args) {
+                            (to, text) if !text.is_empty() => {
+                                queued.push((
+                                    messages.len(),
+                                    Some(to.to_string()),
+                                    text.to_string(),
+                                ));
+                                format!("  (to {}) {} (waiting for the connection)", to, text)
+                            }
+                            _ => "  Usage: /msg <name> <text>".to_string(),
+                        },
+                        Some(_) => "  Wait for the connection to be back".to_string(),
+                        None => {
+                            queued.push((messages.len(), None, string.clone()));
+                            format!("  {} (waiting for the connection)", string)
+                        }
+                    };
+                    string.clear();
+                    cursor = 0;
+                    messages.push(line);
+                    messages.draw(win, &string, cursor);
+                }
                 Input::Character('\n') if string.len() > 1 => {
-                    stream
-                        .write(chattest::Code::MessageTo(string.clone()))
-                        .unwrap();
-                    messages.push(format!("  {}", string));
-                    if messages.len() == 1 {
-                        win.mvprintw(3, 0, messages.last().unwrap());
-                        win.clrtobot();
-                        win.mvprintw(LAST, 0, " > ");
-                        win.printw(&string);
-                        win.mv(LAST, 3 + cursor as i32);
-                    } else if messages.len() == selected + 2 {
-                        selected += 1;
-                        win.mvprintw(3, 0, messages.last().unwrap());
-                        win.clrtobot();
-                        win.mvprintw(LAST, 0, " > ");
-                        win.printw(&string);
-                        win.mv(LAST, 3 + cursor as i32);
-                    }
+                    // The text that was sent, if the room will send it back
+                    let mut sending = None;
+                    // Whether the connection was lost while sending it
+                    let mut dropped = false;
+                    let line = match command(&string) {
+                        Some(("msg", args)) => match first_word(args) {
+                            (to, text) if !text.is_empty() => {
+                                match session.send_direct(to.to_string(), text.to_string()) {
+                                    Ok(()) => {
+                                        sending = Some((format!("(to {}) ", to), text.to_string()));
+                                        format!("  (to {}) {}", to, text)
+                                    }
+                                    Err(error) => not_sent(error, &mut dropped),
+                                }
+                            }
+                            _ => "  Usage: /msg <name> <text>".to_string(),
+                        },
+                        Some(("nick", new)) if !new.is_empty() => {
+                            match session.rename(new.to_string()) {
+                                Ok(()) => format!("  Asking to be called {}", new),
+                                Err(error) => not_sent(error, &mut dropped),
+                            }
+                        }
+                        Some(("nick", _)) => "  Usage: /nick <name>".to_string(),
+                        Some(("register", "")) => {
+                            match Identity::open_or_create(Identity::default_path()) {
+                                Ok(identity) => match session.register_key(&identity) {
+                                    Ok(()) => {
+                                        format!("  Asking to register {} with your key", name)
+                                    }
+                                    Err(error) => not_sent(error, &mut dropped),
+                                },
+                                Err(error) => format!("  Couldn't load your key: {}", error),
+                            }
+                        }
+                        // Anyone could read the password
+                        Some(("register", "password")) if session.fingerprint().is_none() => {
+                            "  The connection is not encrypted, register with your key".to_string()
+                        }
+                        Some(("register", "password")) => {
+                            // Ask for the password in the input line
+                            win.mvprintw(LAST, 0, " Password: ");
+                            win.clrtoeol();
+                            match get_secret(win) {
+                                (password, false) if !password.is_empty() => {
+                                    match session.register_password(&password) {
+                                        Ok(()) => {
+                                            format!("  Asking to register {} with a password", name)
+                                        }
+                                        Err(error) => not_sent(error, &mut dropped),
+                                    }
+                                }
+                                _ => "  The name was not registered".to_string(),
+                            }
+                        }
+                        Some(("register", _)) => "  Usage: /register [password]".to_string(),
+                        // Change or delete the message that is shown
+                        Some(("edit" | "delete", _))
+                            if session.capabilities() & protocol::CAP_EDIT == 0 =>
+                        {
+                            "  The room doesn't let you change the messages".to_string()
+                        }
+                        Some(("edit", "")) => "  Usage: /edit <text>".to_string(),
+                        Some((command @ ("edit" | "delete"), text)) => {
+                            let selected = shown
+                                .iter()
+                                .find(|(_, shown)| shown.own && shown.idx == messages.selected())
+                                .map(|(id, _)| *id);
+                            match (selected, command) {
+                                (Some(id), "edit") => match session.edit(id, text.to_string()) {
+                                    Ok(()) => "  Asking to change the message".to_string(),
+                                    Err(error) => not_sent(error, &mut dropped),
+                                },
+                                (Some(id), _) => match session.delete(id) {
+                                    Ok(()) => "  Asking to delete the message".to_string(),
+                                    Err(error) => not_sent(error, &mut dropped),
+                                },
+                                (None, _) => {
+                                    "  Show one of your messages with the arrows first".to_string()
+                                }
+                            }
+                        }
+                        // Send and receive files
+                        Some(("send" | "accept" | "refuse" | "cancel", _))
+                            if session.capabilities() & protocol::CAP_FILES == 0 =>
+                        {
+                            "  The room doesn't let you send files".to_string()
+                        }
+                        Some(("send", args)) => match first_word(args) {
+                            (to, path) if !path.is_empty() => {
+                                // Send it to everyone with *
+                                let to = if to == "*" { "" } else { to };
+                                let path = Path::new(path);
+                                match session.offer_file(to.to_string(), path) {
+                                    Ok(offer) => {
+                                        let file = path.file_name().unwrap_or_default();
+                                        let file = file.to_string_lossy().to_string();
+                                        let size = path.metadata().map_or(0, |meta| meta.len());
+                                        let line = format!("  Offering the file {}", file);
+                                        offers.insert(offer, (file, size));
+                                        line
+                                    }
+                                    Err(error) => format!("  Couldn't send the file: {}", error),
+                                }
+                            }
+                            _ => "  Usage: /send <name or *> <path>".to_string(),
+                        },
+                        Some(("accept", id)) => match parse_id(id)
+                            .and_then(|id| offered.remove(&id).map(|file| (id, file)))
+                        {
+                            Some((transfer, (file, from, size))) => {
+                                match session.accept_file(transfer, &files::default_dir()) {
+                                    Ok(path) => {
+                                        let progress = Progress {
+                                            idx: messages.len(),
+                                            label: format!("Receiving {} from {}", file, from),
+                                            size,
+                                            saved: Some(path),
+                                        };
+                                        let line = progress.line(0);
+                                        transfers.insert(transfer, progress);
+                                        line
+                                    }
+                                    Err(error) => format!("  Couldn't save the file: {}", error),
+                                }
+                            }
+                            None => "  Usage: /accept <id of a file offered>".to_string(),
+                        },
+                        Some(("refuse", id)) => match parse_id(id)
+                            .and_then(|id| offered.remove(&id).map(|file| (id, file)))
+                        {
+                            Some((transfer, (file, ..))) => match session.refuse_file(transfer) {
+                                Ok(_) => format!("  Refused the file {}", file),
+                                Err(error) => not_sent(error, &mut dropped),
+                            },
+                            None => "  Usage: /refuse <id of a file offered>".to_string(),
+                        },
+                        Some(("cancel", id)) => match parse_id(id)
+                            .and_then(|id| transfers.remove(&id).map(|progress| (id, progress)))
+                        {
+                            Some((transfer, progress)) => match session.cancel_file(transfer) {
+                                Ok(_) => {
+                                    messages.set(
+                                        progress.idx,
+                                        format!("  {}: cancelled", progress.label),
+                                    );
+                                    format!("  Cancelled: {}", progress.label)
+                                }
+                                Err(error) => not_sent(error, &mut dropped),
+                            },
+                            None => "  Usage: /cancel <id of a file being sent>".to_string(),
+                        },
+                        Some(("fingerprint", who)) => match session.group() {
+                            Some(group) if who.is_empty() => {
+                                format!("  Your key: {}", group.fingerprint())
+                            }
+                            Some(group) => match group.member_fingerprint(who) {
+                                Some(fingerprint) => format!("  Key of {}: {}", who, fingerprint),
+                                None => format!("  The key of {} is not known", who),
+                            },
+                            None => "  The room is not end-to-end encrypted".to_string(),
+                        },
+                        Some((name, _)) => format!("  Unknown command /{}", name),
+                        None => match session.send(string.clone()) {
+                            Ok(()) => {
+                                sending = Some((String::new(), string.clone()));
+                                format!("  {}", string)
+                            }
+                            Err(error) => not_sent(error, &mut dropped),
+                        },
+                    };
                     string.clear();
                     cursor = 0;
-                    win.mv(LAST, 3);
-                    win.clrtobot();
+                    // Wait for the room to send it back
+                    if let Some((who, text)) = sending.filter(|_| ids) {
+                        pending.push((messages.len(), who, text));
+                    }
+                    messages.push(line);
+                    // Keep the messages and try to connect again
+                    if dropped {
+                        reconnect = Some(Reconnect::start(&mut messages));
+                    }
+                    messages.draw(win, &string, cursor);
                 }
-                Input::KeyUp if selected > 0 => {
-                    selected -= 1;
-                    win.mvprintw(3, 0, &messages[selected]);
-                    win.clrtobot();
-                    win.mvprintw(LAST, 0, " > ");
-                    win.printw(&string);
-                    win.mv(LAST, 3 + cursor as i32);
+                Input::Character('\t') => {
+                    users.toggle();
+                    users.draw(win);
+                    messages.draw(win, &string, cursor);
                 }
-                Input::KeyDown if selected + 1 < messages.len() => {
-                    selected += 1;
-                    win.mvprintw(3, 0, &messages[selected]);
-                    win.clrtobot();
-                    win.mvprintw(LAST, 0, " > ");
-                    win.printw(&string);
-                    win.mv(LAST, 3 + cursor as i32);
+                Input::KeyUp if messages.up() => {
+                    messages.draw(win, &string, cursor);
+                }
+                Input::KeyDown if messages.down() => {
+                    messages.draw(win, &string, cursor);
                 }
                 _ => (),
             }
         }
     }
 }
+
+/// The connection that was lost, it's tried again waiting more and more
+struct Reconnect {
+    /// When to try again
+    at: Instant,
+    /// How long it waited the last time
+    wait: Duration,
+    attempts: u32,
+    /// Where its line is in the messages
+    idx: usize,
+    /// Where the result of the attempt arrives while it's made
+    attempt: Option<Receiver<io::Result<connection::Session>>>,
+}
+
+impl Reconnect {
+    /// The connection was just lost, it's told at the end of `messages`
+    fn start(messages: &mut Messages) -> Self {
+        let lost = Reconnect {
+            at: Instant::now() + RETRY_WAIT,
+            wait: RETRY_WAIT,
+            attempts: 0,
+            idx: messages.len(),
+            attempt: None,
+        };
+        messages.push(lost.line(None));
+        lost
+    }
+
+    /// Whether it's time to try again
+    fn due(&self) -> bool {
+        self.attempt.is_none() && Instant::now() >= self.at
+    }
+
+    /// Connects again to the room of `session` in another thread, the room
+    /// could take a while to respond
+    fn attempt(&mut self, session: &connection::Session) {
+        let comeback = session.comeback();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            // Nobody waits for it if he returned to the menu
+            let _ = sender.send(comeback.connect());
+        });
+        self.attempt = Some(receiver);
+    }
+
+    /// The result of the attempt, once it's over
+    fn result(&mut self) -> Option<io::Result<connection::Session>> {
+        let result = match self.attempt.as_ref()?.try_recv() {
+            Ok(result) => result,
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => Err(io::Error::other(
+                "Chattest error: the attempt to connect again stopped!",
+            )),
+        };
+        self.attempt = None;
+        Some(result)
+    }
+
+    /// The last attempt failed, the next one waits twice as much
+    fn retry(&mut self) {
+        self.attempts += 1;
+        self.wait = (self.wait * 2).min(MAX_RETRY_WAIT);
+        self.at = Instant::now() + self.wait;
+    }
+
+    /// The line that tells when it's tried again and why the last attempt
+    /// failed
+    fn line(&self, error: Option<&io::Error>) -> String {
+        match error {
+            Some(error) => format!(
+                "  Connection lost! Trying again in {}s ({}) [press ESC to return to the menu]",
+                self.wait.as_secs(),
+                error
+            ),
+            None => format!(
+                "  Connection lost! Trying again in {}s [press ESC to return to the menu]",
+                self.wait.as_secs()
+            ),
+        }
+    }
+}
+
+/// The line shown for what couldn't be sent, `dropped` is set if it's
+/// because the connection was lost
+fn not_sent(error: io::Error, dropped: &mut bool) -> String {
+    *dropped |= matches!(
+        error.kind(),
+        ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe
+    );
+    format!("  Not sent: {}", error)
+}
+
+/// A text of the room with an id
+struct Shown {
+    /// Where it is in the messages
+    idx: usize,
+    /// What is shown before the text, like the time and who sent it
+    who: String,
+    text: String,
+    /// Whether this client sent it
+    own: bool,
+    /// How many received it, if this client sent it
+    received: usize,
+    edited: bool,
+}
+
+impl Shown {
+    fn new(idx: usize, clock: &str, who: String, text: String) -> Self {
+        Shown {
+            idx,
+            who: format!("  [{}] {}", clock, who),
+            text,
+            own: false,
+            received: 0,
+            edited: false,
+        }
+    }
+
+    /// A text sent by this client, the room sent it back so it's delivered
+    fn own(idx: usize, clock: &str, who: String, text: String) -> Self {
+        Shown {
+            own: true,
+            ..Shown::new(idx, clock, who, text)
+        }
+    }
+
+    fn line(&self) -> String {
+        let mut line = format!("{}{}", self.who, self.text);
+        if self.edited {
+            line.push_str(" (edited)");
+        }
+        match (self.own, self.received) {
+            (false, _) => (),
+            (true, 0) => line.push_str(" (delivered)"),
+            (true, count) => line.push_str(&format!(" (delivered, received by {})", count)),
+        }
+        line
+    }
+}
+
+/// A file being sent or received
+struct Progress {
+    /// Where its progress is in the messages
+    idx: usize,
+    /// What is done, like who sends what
+    label: String,
+    size: u64,
+    /// Where it's saved, if it's received
+    saved: Option<PathBuf>,
+}
+
+impl Progress {
+    /// The line that shows that `bytes` of the file arrived
+    fn line(&self, bytes: u64) -> String {
+        match &self.saved {
+            _ if bytes < self.size => {
+                format!("  {}: {}%", self.label, bytes * 100 / self.size)
+            }
+            Some(path) => format!("  {}: done, saved in {}", self.label, path.display()),
+            None => format!("  {}: done", self.label),
+        }
+    }
+}
+
+/// How a text of the room is shown: who sent it and the text, `None` if
+/// `code` is not a text
+fn text(admin: &str, code: &protocol::Code) -> Option<(String, String)> {
+    match code {
+        protocol::Code::MessageFrom(name, message) => {
+            Some((format!("{}> ", name), message.clone()))
+        }
+        protocol::Code::MessageTo(message) => Some((format!("{}# ", admin), message.clone())),
+        protocol::Code::DirectFrom(name, message) => {
+            Some((format!("{} (private)> ", name), message.clone()))
+        }
+        protocol::Code::EncryptedFrom(name, ..) => Some((
+            format!("{}> ", name),
+            "(this message can't be decrypted)".to_string(),
+        )),
+        _ => None,
+    }
+}
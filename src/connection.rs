@@ -0,0 +1,685 @@
+use crate::accounts::Identity;
+use crate::e2e::{Group, KeyPair};
+use crate::files::Files;
+use crate::protocol::{self, BlockingStream, Code, NonBlockingStream, Reason, RoomInfo};
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How long `Session::resume` waits for the room every time
+pub const RESPONSE_WAIT: Duration = Duration::from_secs(10);
+
+/// Error for a server that refused the client
+pub(crate) fn refused(reason: Reason) -> io::Error {
+    io::Error::new(
+        ErrorKind::ConnectionRefused,
+        format!(
+            "Chattest error: the server refused the connection ({})!",
+            reason
+        ),
+    )
+}
+
+/// Error for a message that wasn't expected at that moment
+pub(crate) fn unexpected(code: Code) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("Chattest error: message not expected {:?}!", code),
+    )
+}
+
+/// Error for a password that would be sent on a connection that is not
+/// encrypted
+fn plaintext_password() -> io::Error {
+    io::Error::new(
+        ErrorKind::PermissionDenied,
+        "Chattest error: the password can't be sent on a connection that is not encrypted!",
+    )
+}
+
+/// Encrypts the stream if the server agreed to, the server must have the same
+/// certificate it had the first time
+#[cfg(feature = "tls")]
+fn secure(stream: BlockingStream, capabilities: u32) -> io::Result<BlockingStream> {
+    use crate::tls::{self, KnownHosts};
+    let host = stream.peer_addr()?.to_string();
+    let known_hosts = KnownHosts::new(KnownHosts::default_path());
+    let known = known_hosts.get(&host)?;
+    if capabilities & protocol::CAP_TLS == 0 {
+        // Someone in the middle could be hiding that the server uses TLS
+        if known.is_some() {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                format!(
+                    "Chattest error: the server at {} used TLS before but now it doesn't, \
+                     if that's expected remove it from the known hosts!",
+                    host
+                ),
+            ));
+        }
+        return Ok(stream);
+    }
+    let stream = stream.start_tls(tls::client_config()?)?;
+    let fingerprint = stream.fingerprint().unwrap_or_default();
+    match known {
+        // Nothing was sent yet, it can't be read by the wrong server
+        Some(known) if known != fingerprint => Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "Chattest error: the certificate of the server at {} changed since the last \
+                 time, if that's expected remove it from the known hosts!",
+                host
+            ),
+        )),
+        Some(_) => Ok(stream),
+        // Trust the certificate from now on
+        None => {
+            known_hosts.add(&host, &fingerprint)?;
+            Ok(stream)
+        }
+    }
+}
+
+/// A connection to a room that didn't join it yet
+pub struct Connection {
+    stream: BlockingStream,
+    capabilities: u32,
+    /// Nonce sent by the room and the name used to join, if the room has a
+    /// password
+    challenge: Option<(Vec<u8>, String)>,
+    /// Method and nonce sent by the room and the name used to join, if the
+    /// name is registered
+    auth: Option<(u8, Vec<u8>, String)>,
+}
+
+/// What happens when a `Connection` tries to join the room
+// It's made once for every connection, the size doesn't matter
+#[allow(clippy::large_enum_variant)]
+pub enum Join {
+    /// The room accepted the name
+    Joined(Session),
+    /// There is already someone with that name, another one can be tried
+    NameTaken(Connection),
+    /// The room has a password, it must be given with `Connection::answer`
+    PasswordRequired(Connection),
+    /// The name is registered, it must be proved with
+    /// `Connection::auth_password` or `Connection::auth_key` depending on
+    /// `Connection::auth_method`
+    AuthRequired(Connection),
+}
+
+impl Connection {
+    /// Connects to the room at `addr` and agrees on the version of the protocol
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        Self::greet(TcpStream::connect(addr)?)
+    }
+
+    /// Like `connect`, but it gives up with `TimedOut` if the server doesn't
+    /// respond within `timeout`, every time it waits for it
+    pub fn connect_timeout(addr: &SocketAddr, timeout: Duration) -> io::Result<Self> {
+        let stream = TcpStream::connect_timeout(addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        Self::greet(stream)
+    }
+
+    /// Says HELLO to the server at the other side of `stream`
+    fn greet(stream: TcpStream) -> io::Result<Self> {
+        let mut stream = BlockingStream::new(stream);
+        // Offer TLS only if this program can use it
+        let mut offered = protocol::CAPABILITIES | protocol::CAP_E2E | protocol::CAP_ROOMS;
+        if cfg!(feature = "tls") {
+            offered |= protocol::CAP_TLS;
+        }
+        // Tell the server which version of the protocol is used
+        stream.write(Code::Hello(protocol::VERSION, offered))?;
+        match stream.read()? {
+            Code::Hello(version, capabilities) if version >= protocol::MIN_VERSION => {
+                #[cfg(feature = "tls")]
+                let stream = secure(stream, capabilities)?;
+                Ok(Connection {
+                    stream,
+                    capabilities,
+                    challenge: None,
+                    auth: None,
+                })
+            }
+            Code::Hello(..) => Err(refused(Reason::UnsupportedVersion)),
+            Code::Rejected(reason) => Err(refused(reason)),
+            code => Err(unexpected(code)),
+        }
+    }
+
+    /// Capabilities supported by both sides
+    pub fn capabilities(&self) -> u32 {
+        self.capabilities
+    }
+
+    /// Fingerprint of the certificate of the server, if the connection is
+    /// encrypted
+    pub fn fingerprint(&self) -> Option<String> {
+        self.stream.fingerprint()
+    }
+
+    /// The rooms hosted by the server, the first one is joined if the
+    /// client doesn't pick another one with `pick_room`
+    pub fn rooms(&mut self) -> io::Result<Vec<RoomInfo>> {
+        if self.capabilities & protocol::CAP_ROOMS == 0 {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "Chattest error: the server can't list its rooms!",
+            ));
+        }
+        self.stream.write(Code::ListRooms)?;
+        match self.stream.read()? {
+            Code::Rooms(names) => Ok(names),
+            code => Err(unexpected(code)),
+        }
+    }
+
+    /// Picks the room to join before calling `join`, the capabilities are
+    /// the ones of that room from now on. The error is `ConnectionRefused`
+    /// if there is no such room
+    pub fn pick_room(&mut self, room: &str) -> io::Result<()> {
+        self.stream.write(Code::Room(room.to_string()))?;
+        match self.stream.read()? {
+            Code::Hello(_, capabilities) => {
+                self.capabilities = capabilities;
+                Ok(())
+            }
+            Code::Rejected(reason) => Err(refused(reason)),
+            code => Err(unexpected(code)),
+        }
+    }
+
+    /// Asks to join the room as `name`
+    pub fn join(mut self, name: String) -> io::Result<Join> {
+        self.stream.write(Code::Name(name.clone()))?;
+        self.joined(name)
+    }
+
+    /// Proves that the `password` of the room is known, after `join`
+    /// returned `Join::PasswordRequired`. A wrong password is refused with
+    /// a `ConnectionRefused` error
+    pub fn answer(mut self, password: &str) -> io::Result<Join> {
+        let (nonce, name) = self.challenge.take().ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                "Chattest error: the room didn't ask for a password!",
+            )
+        })?;
+        self.stream
+            .write(Code::Answer(protocol::prove(password, &nonce)))?;
+        self.joined(name)
+    }
+
+    /// How the registered name must be proved, after `join` returned
+    /// `Join::AuthRequired`
+    pub fn auth_method(&self) -> Option<u8> {
+        self.auth.as_ref().map(|(method, ..)| *method)
+    }
+
+    /// Proves that the registered name is of the client with its `password`,
+    /// the error is `PermissionDenied` if the connection is not encrypted
+    pub fn auth_password(self, password: &str) -> io::Result<Join> {
+        if self.stream.fingerprint().is_none() {
+            return Err(plaintext_password());
+        }
+        self.auth(|_, _| password.as_bytes().to_vec())
+    }
+
+    /// Proves that the registered name is of the client with its key
+    pub fn auth_key(self, identity: &Identity) -> io::Result<Join> {
+        self.auth(|name, nonce| identity.sign(name, nonce))
+    }
+
+    /// Sends the proof made by `prove` with the name and the nonce
+    fn auth(mut self, prove: impl FnOnce(&str, &[u8]) -> Vec<u8>) -> io::Result<Join> {
+        let (method, nonce, name) = self.auth.take().ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                "Chattest error: the name is not registered!",
+            )
+        })?;
+        let proof = prove(&name, &nonce);
+        self.stream.write(Code::Auth(method, proof))?;
+        self.joined(name)
+    }
+
+    /// Waits for the room to respond to the request of joining as `name`
+    fn joined(mut self, name: String) -> io::Result<Join> {
+        let code = self.stream.read()?;
+        self.respond(code, name)
+    }
+
+    /// Handles the response `code` of the room to the request of joining as
+    /// `name`
+    fn respond(mut self, code: Code, name: String) -> io::Result<Join> {
+        match code {
+            Code::Welcome(room, admin) => {
+                let peer_addr = self.stream.peer_addr()?;
+                // Tell the others the key to send the group keys with
+                let group = if self.capabilities & protocol::CAP_E2E != 0 {
+                    let group = Group::new(
+                        KeyPair::open_or_create(KeyPair::default_path())?,
+                        name.clone(),
+                    );
+                    self.stream.write(group.announce())?;
+                    Some(group)
+                } else {
+                    None
+                };
+                Ok(Join::Joined(Session {
+                    stream: self.stream.non_blocking(),
+                    capabilities: self.capabilities,
+                    name,
+                    room,
+                    admin,
+                    peer_addr,
+                    group,
+                    last_id: 0,
+                    last_time: 0,
+                    files: Files::new(),
+                    token: None,
+                    since: None,
+                }))
+            }
+            Code::AlreadyHere => Ok(Join::NameTaken(self)),
+            Code::Challenge(nonce) => {
+                self.challenge = Some((nonce, name));
+                Ok(Join::PasswordRequired(self))
+            }
+            Code::AuthRequired(method, nonce) => {
+                self.auth = Some((method, nonce, name));
+                Ok(Join::AuthRequired(self))
+            }
+            Code::Rejected(reason) => Err(refused(reason)),
+            code => Err(unexpected(code)),
+        }
+    }
+}
+
+/// A connection that joined a room, reading from it never blocks
+pub struct Session {
+    stream: NonBlockingStream,
+    capabilities: u32,
+    name: String,
+    room: String,
+    admin: String,
+    peer_addr: SocketAddr,
+    /// Keys of the room, if it's end-to-end encrypted
+    group: Option<Group>,
+    /// Id and time of the last stamped text received
+    last_id: u64,
+    last_time: u64,
+    /// Files being sent and received
+    files: Files,
+    /// Token given by the room to resume the session
+    token: Option<Vec<u8>>,
+    /// After joining again, the past messages older than this time were
+    /// already received
+    since: Option<u64>,
+}
+
+impl Session {
+    /// Name used in the room
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Name of the room
+    pub fn room(&self) -> &str {
+        &self.room
+    }
+
+    /// Name of the admin of the room
+    pub fn admin(&self) -> &str {
+        &self.admin
+    }
+
+    /// Capabilities supported by both sides
+    pub fn capabilities(&self) -> u32 {
+        self.capabilities
+    }
+
+    /// Fingerprint of the certificate of the server, if the connection is
+    /// encrypted
+    pub fn fingerprint(&self) -> Option<String> {
+        self.stream.fingerprint()
+    }
+
+    /// Address of the room
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// Keys of the room, if it's end-to-end encrypted
+    pub fn group(&self) -> Option<&Group> {
+        self.group.as_ref()
+    }
+
+    /// Sends a text message to the room, it's encrypted if the room is
+    /// end-to-end encrypted: the error is `WouldBlock` if the group key
+    /// didn't arrive yet
+    pub fn send(&mut self, text: String) -> io::Result<()> {
+        match &self.group {
+            Some(group) => {
+                let code = group.encrypt(&text)?;
+                self.stream.write(code)
+            }
+            None => self.stream.write(Code::MessageTo(text)),
+        }
+    }
+
+    /// Sends a private text message to the participant `to`
+    pub fn send_direct(&mut self, to: String, text: String) -> io::Result<()> {
+        self.stream.write(Code::DirectTo(to, text))
+    }
+
+    /// Changes the text `id` sent before, the room responds with `Edit`
+    pub fn edit(&mut self, id: u64, text: String) -> io::Result<()> {
+        self.stream.write(Code::Edit(id, text))
+    }
+
+    /// Deletes the text `id` sent before, the room responds with `Delete`
+    pub fn delete(&mut self, id: u64) -> io::Result<()> {
+        self.stream.write(Code::Delete(id))
+    }
+
+    /// Offers the file at `path` to the participant `to`, or to everyone if
+    /// it's empty, returns the id of the offer. The room responds with
+    /// `FileAnswered` for every participant that answers, then the file is
+    /// sent while reading with `try_read`
+    pub fn offer_file(&mut self, to: String, path: &Path) -> io::Result<u32> {
+        let (offer, code) = self.files.offer(to, path)?;
+        self.stream.write(code)?;
+        Ok(offer)
+    }
+
+    /// Accepts the file offered with `FileOffered`, it's saved in `dir` while
+    /// reading with `try_read`. Returns where it will be
+    pub fn accept_file(&mut self, transfer: u64, dir: &Path) -> io::Result<PathBuf> {
+        let (path, code) = self.files.accept(transfer, dir)?;
+        self.stream.write(code)?;
+        Ok(path)
+    }
+
+    /// Refuses the file offered with `FileOffered`, returns `false` if
+    /// there is no such offer
+    pub fn refuse_file(&mut self, transfer: u64) -> io::Result<bool> {
+        match self.files.refuse(transfer) {
+            Some(code) => self.stream.write(code).map(|()| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Stops sending or receiving a file, returns `false` if it's not being
+    /// sent or received
+    pub fn cancel_file(&mut self, transfer: u64) -> io::Result<bool> {
+        match self.files.cancel(transfer) {
+            Some(code) => self.stream.write(code).map(|()| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Asks to be called `name` from now on, the room responds with
+    /// `Renamed` or with `AlreadyHere` if the name is taken
+    pub fn rename(&mut self, name: String) -> io::Result<()> {
+        self.stream.write(Code::Nick(name))
+    }
+
+    /// Protects the name used in the room with `password`, the room responds
+    /// with `Verified`. The error is `PermissionDenied` if the connection is
+    /// not encrypted
+    pub fn register_password(&mut self, password: &str) -> io::Result<()> {
+        if self.stream.fingerprint().is_none() {
+            return Err(plaintext_password());
+        }
+        self.stream.write(Code::Register(
+            protocol::AUTH_PASSWORD,
+            password.as_bytes().to_vec(),
+        ))
+    }
+
+    /// Protects the name used in the room with the key of `identity`, the
+    /// room responds with `Verified`
+    pub fn register_key(&mut self, identity: &Identity) -> io::Result<()> {
+        self.stream
+            .write(Code::Register(protocol::AUTH_KEY, identity.public_key()))
+    }
+
+    /// Connects again to the room after the connection was lost, when
+    /// `try_read` returned `ConnectionReset`, like `Comeback::connect` does.
+    /// The files being sent or received are stopped
+    pub fn resume(&mut self) -> io::Result<()> {
+        let session = self.comeback().connect()?;
+        self.replace(session);
+        Ok(())
+    }
+
+    /// What is needed to connect again to the room, so that it can be done
+    /// without blocking the thread of the session
+    pub fn comeback(&self) -> Comeback {
+        Comeback {
+            peer_addr: self.peer_addr,
+            room: self.room.clone(),
+            name: self.name.clone(),
+            token: self.token.clone(),
+            last_id: self.last_id,
+            last_time: self.last_time,
+        }
+    }
+
+    /// Continues with `session`, made by `Comeback::connect`, after the
+    /// connection was lost. The files being sent or received are stopped
+    pub fn replace(&mut self, session: Session) {
+        self.files.lost();
+        *self = Session {
+            // The texts that arrived before are not shown twice
+            last_id: self.last_id,
+            last_time: self.last_time,
+            files: std::mem::take(&mut self.files),
+            ..session
+        };
+    }
+
+    /// Returns the next message of the room if it arrived. In the rooms that
+    /// are end-to-end encrypted the keys are handled here and the texts are
+    /// returned as `MessageFrom`, or as `EncryptedFrom` if they can't be
+    /// decrypted. The texts with an id are returned as `Stamped` and
+    /// acknowledged, the ones that arrived before are dropped. The files
+    /// are sent and saved here too, their progress is returned as
+    /// `FileReceived` on both sides
+    pub fn try_read(&mut self) -> io::Result<Option<Code>> {
+        // Send the parts of the files that can go now
+        for code in self.files.chunks() {
+            self.stream.write(code)?;
+        }
+        if let Some(code) = self.files.event() {
+            return Ok(Some(code));
+        }
+        let code = self.stream.try_read()?;
+        // Keep track of the name once the room accepted the new one
+        if let Some(Code::Renamed(old, new)) = &code {
+            if *old == self.name {
+                self.name = new.clone();
+            }
+        }
+        match code {
+            Some(Code::Stamped(id, time, code)) => {
+                // The ids only grow, an older one was already received
+                if id <= self.last_id {
+                    return Ok(None);
+                }
+                self.last_id = id;
+                self.last_time = time;
+                let code = self.encrypted(*code)?;
+                // Tell the room that it arrived, unless it's a text sent
+                // by this client
+                if !self.sent_by_self(&code) {
+                    self.stream.write(Code::Ack(id))?;
+                }
+                Ok(Some(Code::Stamped(id, time, Box::new(code))))
+            }
+            Some(
+                code @ (Code::FileOffered(..)
+                | Code::FileAnswered(..)
+                | Code::FileChunk(..)
+                | Code::FileReceived(..)
+                | Code::FileCancel(..)),
+            ) => {
+                for code in self.files.received(code) {
+                    self.stream.write(code)?;
+                }
+                Ok(self.files.event())
+            }
+            // Keep the token to resume the session if the connection is lost
+            Some(Code::Token(_, token)) => {
+                self.token = Some(token);
+                Ok(None)
+            }
+            // After joining again the older messages were already received
+            Some(Code::History(mut entries)) => {
+                if let Some(since) = self.since.take() {
+                    entries.retain(|entry| entry.time > since);
+                }
+                Ok(Some(Code::History(entries)))
+            }
+            Some(code) => self.encrypted(code).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether a text that the room sent back was sent by this client
+    fn sent_by_self(&self, code: &Code) -> bool {
+        match code {
+            Code::MessageFrom(from, _) | Code::EncryptedFrom(from, ..) => *from == self.name,
+            Code::DirectTo(..) => true,
+            _ => false,
+        }
+    }
+
+    /// Handles a message of an end-to-end encrypted room
+    fn encrypted(&mut self, code: Code) -> io::Result<Code> {
+        let group = match &mut self.group {
+            Some(group) => group,
+            None => return Ok(code),
+        };
+        match &code {
+            Code::MemberKey(name, key) => group.add_member(name.clone(), key.clone()),
+            // Only who is in the room gets the group keys
+            Code::UserList(names) => group.set_roster(names.clone()),
+            Code::UserJoined(name) => group.joined(name.clone()),
+            Code::UserLeft(name) => group.remove_member(name),
+            Code::Renamed(old, new) => group.rename(old, new.clone()),
+            // Send the new group key to the others
+            Code::Rekey(epoch) => {
+                for code in group.rekey(*epoch) {
+                    self.stream.write(code)?;
+                }
+            }
+            Code::GroupKeyFrom(from, epoch, sealed) => group.receive_key(from, *epoch, sealed)?,
+            Code::EncryptedFrom(from, epoch, sealed) => {
+                if let Ok(text) = group.decrypt(from, *epoch, sealed) {
+                    return Ok(Code::MessageFrom(from.clone(), text));
+                }
+            }
+            _ => (),
+        }
+        Ok(code)
+    }
+}
+
+/// How to connect again to the room of a `Session` that lost the connection,
+/// made by `Session::comeback`
+pub struct Comeback {
+    peer_addr: SocketAddr,
+    room: String,
+    name: String,
+    token: Option<Vec<u8>>,
+    /// Id and time of the last stamped text received
+    last_id: u64,
+    last_time: u64,
+}
+
+impl Comeback {
+    /// Connects again to the room, the session given back must be passed to
+    /// `Session::replace`. If the room gave a token the session is resumed:
+    /// the others don't see the client leave and the texts said meanwhile
+    /// arrive with `History`. Otherwise, or if the room forgot the session,
+    /// it joins again with the same name: the error is `AlreadyExists` if
+    /// someone else took it and `PermissionDenied` if the room asks for the
+    /// password or for the proof of a registered name. It's `TimedOut` if
+    /// the room doesn't respond within `RESPONSE_WAIT`
+    pub fn connect(self) -> io::Result<Session> {
+        let resumed = match self.token.clone() {
+            Some(token) => self.resumed(token)?,
+            None => None,
+        };
+        match resumed {
+            Some(session) => Ok(session),
+            // The past messages it sends were already received
+            None => Ok(Session {
+                since: Some(self.last_time),
+                ..self.rejoin()?
+            }),
+        }
+    }
+
+    /// Connects to the same room again, until it asks for the name
+    fn reconnect(&self) -> io::Result<Connection> {
+        let mut connection = Connection::connect_timeout(&self.peer_addr, RESPONSE_WAIT)?;
+        if connection.capabilities & protocol::CAP_ROOMS != 0 {
+            connection.pick_room(&self.room)?;
+        }
+        Ok(connection)
+    }
+
+    /// Asks the room to resume the session of `token`, `None` if it can't
+    fn resumed(&self, token: Vec<u8>) -> io::Result<Option<Session>> {
+        let mut connection = self.reconnect()?;
+        if connection.capabilities & protocol::CAP_RESUME == 0 {
+            return Ok(None);
+        }
+        connection.stream.write(Code::Resume(self.last_id, token))?;
+        match connection.stream.read()? {
+            // The name can still be taken back like the first time
+            Code::Rejected(Reason::SessionExpired | Reason::TlsRequired) => Ok(None),
+            code => match connection.respond(code, self.name.clone())? {
+                Join::Joined(session) => Ok(Some(session)),
+                _ => Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Chattest error: the room didn't resume the session!",
+                )),
+            },
+        }
+    }
+
+    /// Joins the room again with the same name, like the first time
+    fn rejoin(&self) -> io::Result<Session> {
+        let joined = self.reconnect()?.join(self.name.clone());
+        // The room refused him, like it does if the name needs a password
+        // that can't be sent, trying again won't change it
+        let joined = joined.map_err(|error| match error.kind() {
+            ErrorKind::ConnectionRefused => {
+                io::Error::new(ErrorKind::PermissionDenied, error.to_string())
+            }
+            _ => error,
+        })?;
+        match joined {
+            Join::Joined(session) => Ok(session),
+            Join::NameTaken(_) => Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                "Chattest error: someone else took your name meanwhile!",
+            )),
+            Join::PasswordRequired(_) => Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "Chattest error: the room asks for the password again!",
+            )),
+            Join::AuthRequired(_) => Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "Chattest error: the room asks to prove that the name is yours again!",
+            )),
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use crate::protocol::{self, Code};
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// A room that responded to DISCOVER
+#[derive(Clone, Debug, PartialEq)]
+pub struct Found {
+    /// Where the server that hosts it listens
+    pub addr: SocketAddr,
+    pub room: String,
+    pub admin: String,
+}
+
+/// Looks for the servers that listen on `port` in the network, and on this
+/// computer, waiting `wait` for them to respond
+pub fn discover(port: u16, wait: Duration) -> io::Result<Vec<Found>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    let mut probe = Vec::new();
+    protocol::encode(&Code::Discover, &mut probe);
+    // The broadcast doesn't always come back to this computer
+    let mut sent = false;
+    for target in [Ipv4Addr::BROADCAST, Ipv4Addr::LOCALHOST] {
+        sent |= socket.send_to(&probe, (target, port)).is_ok();
+    }
+    if !sent {
+        return Err(io::Error::new(
+            ErrorKind::AddrNotAvailable,
+            "Chattest discovery error: couldn't send the request!",
+        ));
+    }
+
+    let mut found: Vec<Found> = Vec::new();
+    let end = Instant::now() + wait;
+    let mut buffer = [0u8; 2048];
+    loop {
+        let left = end.saturating_duration_since(Instant::now());
+        if left == Duration::ZERO {
+            break;
+        }
+        socket.set_read_timeout(Some(left))?;
+        let (len, from) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(error)
+                if error.kind() == ErrorKind::WouldBlock || error.kind() == ErrorKind::TimedOut =>
+            {
+                break
+            }
+            Err(error) => return Err(error),
+        };
+        // Skip what is not an answer, like the request itself
+        if let Ok(Code::Announce(port, room, admin)) = protocol::decode(&buffer[..len]) {
+            let addr = SocketAddr::new(from.ip(), port);
+            // A server on this computer responds to the broadcast too, keep
+            // the address that the others can use
+            let same = found.iter_mut().find(|other| {
+                other.room == room
+                    && other.admin == admin
+                    && other.addr.port() == port
+                    && (other.addr.ip() == addr.ip()
+                        || other.addr.ip().is_loopback()
+                        || addr.ip().is_loopback())
+            });
+            match same {
+                Some(other) if other.addr.ip().is_loopback() => other.addr = addr,
+                Some(_) => (),
+                None => found.push(Found { addr, room, admin }),
+            }
+        }
+    }
+    Ok(found)
+}
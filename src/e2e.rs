@@ -0,0 +1,346 @@
+use crate::history::{escape, unescape};
+use crate::protocol::Code;
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Size of the public keys and of the group keys
+pub const KEY_LEN: usize = 32;
+/// How many group keys are kept, to read the messages sent with the older
+/// ones while a new one is being shared
+const KEPT_EPOCHS: usize = 4;
+
+fn e2e_error(what: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("Chattest E2E error: {}!", what),
+    )
+}
+
+/// Random bytes
+fn random(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("the system has random numbers");
+    bytes
+}
+
+/// Seals `data` with `key`, the random nonce is put before the ciphertext
+fn seal(key: &[u8], aad: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+    let key = UnboundKey::new(&CHACHA20_POLY1305, key).map_err(|_| e2e_error("bad key"))?;
+    let nonce = random(aead::NONCE_LEN);
+    let mut sealed = data.to_vec();
+    LessSafeKey::new(key)
+        .seal_in_place_append_tag(
+            Nonce::try_assume_unique_for_key(&nonce).map_err(|_| e2e_error("bad nonce"))?,
+            Aad::from(aad),
+            &mut sealed,
+        )
+        .map_err(|_| e2e_error("can't seal"))?;
+    Ok([nonce, sealed].concat())
+}
+
+/// Opens what `seal` made with the same `key` and `aad`
+fn open(key: &[u8], aad: &[u8], sealed: &[u8]) -> io::Result<Vec<u8>> {
+    let key = UnboundKey::new(&CHACHA20_POLY1305, key).map_err(|_| e2e_error("bad key"))?;
+    if sealed.len() < aead::NONCE_LEN {
+        return Err(e2e_error("the message is too short"));
+    }
+    let (nonce, sealed) = sealed.split_at(aead::NONCE_LEN);
+    let mut data = sealed.to_vec();
+    let len = LessSafeKey::new(key)
+        .open_in_place(
+            Nonce::try_assume_unique_for_key(nonce).map_err(|_| e2e_error("bad nonce"))?,
+            Aad::from(aad),
+            &mut data,
+        )
+        .map_err(|_| e2e_error("the message was changed or the key is wrong"))?
+        .len();
+    data.truncate(len);
+    Ok(data)
+}
+
+/// First half of the SHA-256 of a public key, written as pairs of hex digits
+/// divided by `:`, short enough to be read aloud
+pub fn fingerprint(public_key: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, public_key).as_ref()[..16]
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// The X25519 key of a client, the other clients send it the group keys
+pub struct KeyPair {
+    secret: StaticSecret,
+}
+
+impl KeyPair {
+    /// File where the client keeps its key by default
+    pub fn default_path() -> PathBuf {
+        crate::data_dir().join("e2e.key")
+    }
+
+    /// Loads the key saved at `path`, or makes a new one and saves it there
+    pub fn open_or_create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == ErrorKind::NotFound => {
+                let bytes = random(KEY_LEN);
+                crate::write_secret(path, &bytes)?;
+                bytes
+            }
+            Err(error) => return Err(error),
+        };
+        let bytes: [u8; KEY_LEN] = bytes
+            .try_into()
+            .map_err(|_| e2e_error("the key is not valid"))?;
+        Ok(KeyPair {
+            secret: StaticSecret::from(bytes),
+        })
+    }
+
+    /// The public key, sent with PUBLIC_KEY
+    pub fn public_key(&self) -> Vec<u8> {
+        PublicKey::from(&self.secret).as_bytes().to_vec()
+    }
+
+    /// Key that seals the group key of `epoch` sent between this client and
+    /// the one with `public_key`
+    fn shared(&self, public_key: &[u8], epoch: u32) -> io::Result<Vec<u8>> {
+        let public_key: [u8; KEY_LEN] = public_key
+            .try_into()
+            .map_err(|_| e2e_error("the public key is not valid"))?;
+        let secret = self.secret.diffie_hellman(&PublicKey::from(public_key));
+        // Keys that give the same secret with everyone are useless
+        if !secret.was_contributory() {
+            return Err(e2e_error("the public key is not valid"));
+        }
+        let mut key = vec![0u8; KEY_LEN];
+        Salt::new(HKDF_SHA256, b"chattest group key")
+            .extract(secret.as_bytes())
+            .expand(&[&epoch.to_be_bytes()], HKDF_SHA256)
+            .and_then(|okm| okm.fill(&mut key))
+            .map_err(|_| e2e_error("can't derive the key"))?;
+        Ok(key)
+    }
+}
+
+/// What a client of an end-to-end encrypted room knows: the public keys of
+/// the others and the group keys
+pub struct Group {
+    keys: KeyPair,
+    /// Name of the client, the texts it sends are bound to it
+    name: String,
+    /// Public keys of the other clients
+    members: HashMap<String, Vec<u8>>,
+    /// Names of the clients in the room, as the list of the users says:
+    /// the server could send the key of someone that isn't there
+    roster: HashSet<String>,
+    /// Group keys and their epochs, the last one is used to send
+    epochs: Vec<(u32, Vec<u8>)>,
+}
+
+impl Group {
+    pub fn new(keys: KeyPair, name: String) -> Self {
+        Group {
+            keys,
+            name,
+            members: HashMap::new(),
+            roster: HashSet::new(),
+            epochs: Vec::new(),
+        }
+    }
+
+    /// The message that tells the others the public key of this client
+    pub fn announce(&self) -> Code {
+        Code::PublicKey(self.keys.public_key())
+    }
+
+    /// Fingerprint of the public key of this client
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.keys.public_key())
+    }
+
+    /// Fingerprint of the public key of the client called `name`
+    pub fn member_fingerprint(&self, name: &str) -> Option<String> {
+        self.members.get(name).map(|key| fingerprint(key))
+    }
+
+    /// Whether there is a group key to send texts with
+    pub fn ready(&self) -> bool {
+        !self.epochs.is_empty()
+    }
+
+    /// Remembers the public key of the client called `name`
+    pub fn add_member(&mut self, name: String, public_key: Vec<u8>) {
+        self.members.insert(name, public_key);
+    }
+
+    pub fn remove_member(&mut self, name: &str) {
+        self.members.remove(name);
+        self.roster.remove(name);
+    }
+
+    /// Replaces the names of the clients in the room with `names`
+    pub fn set_roster(&mut self, names: Vec<String>) {
+        self.roster = names.into_iter().collect();
+    }
+
+    /// The client called `name` joined the room
+    pub fn joined(&mut self, name: String) {
+        self.roster.insert(name);
+    }
+
+    pub fn rename(&mut self, old: &str, new: String) {
+        if self.roster.remove(old) {
+            self.roster.insert(new.clone());
+        }
+        if old == self.name {
+            self.name = new;
+        } else if let Some(key) = self.members.remove(old) {
+            self.members.insert(new, key);
+        }
+    }
+
+    /// Keeps the group key of `epoch`, forgetting the oldest ones
+    fn keep(&mut self, epoch: u32, key: Vec<u8>) {
+        self.epochs.retain(|(other, _)| *other != epoch);
+        self.epochs.push((epoch, key));
+        self.epochs.sort_by_key(|(epoch, _)| *epoch);
+        if self.epochs.len() > KEPT_EPOCHS {
+            self.epochs.remove(0);
+        }
+    }
+
+    /// Makes the group key of `epoch`, returns the messages that send it to
+    /// the other clients in the room
+    pub fn rekey(&mut self, epoch: u32) -> Vec<Code> {
+        let key = random(KEY_LEN);
+        let codes = self
+            .members
+            .iter()
+            .filter(|(name, _)| self.roster.contains(*name))
+            .filter_map(|(name, public_key)| {
+                // Who sent a broken key doesn't get the group key
+                let shared = self.keys.shared(public_key, epoch).ok()?;
+                let sealed = seal(&shared, &epoch.to_be_bytes(), &key).ok()?;
+                Some(Code::GroupKeyTo(name.clone(), epoch, sealed))
+            })
+            .collect();
+        self.keep(epoch, key);
+        codes
+    }
+
+    /// Opens the group key of `epoch` sent by the client called `from`
+    pub fn receive_key(&mut self, from: &str, epoch: u32, sealed: &[u8]) -> io::Result<()> {
+        let public_key = self
+            .members
+            .get(from)
+            .filter(|_| self.roster.contains(from))
+            .ok_or_else(|| e2e_error("the key of the sender is not known"))?;
+        let shared = self.keys.shared(public_key, epoch)?;
+        let key = open(&shared, &epoch.to_be_bytes(), sealed)?;
+        if key.len() != KEY_LEN {
+            return Err(e2e_error("the group key is not valid"));
+        }
+        self.keep(epoch, key);
+        Ok(())
+    }
+
+    /// Seals `text` with the last group key
+    pub fn encrypt(&self, text: &str) -> io::Result<Code> {
+        let (epoch, key) = self.epochs.last().ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::WouldBlock,
+                "Chattest E2E error: the key of the group didn't arrive yet!",
+            )
+        })?;
+        let sealed = seal(key, self.name.as_bytes(), text.as_bytes())?;
+        Ok(Code::EncryptedTo(*epoch, sealed))
+    }
+
+    /// Opens a text sealed by the client called `from`
+    pub fn decrypt(&self, from: &str, epoch: u32, sealed: &[u8]) -> io::Result<String> {
+        let key = self
+            .epochs
+            .iter()
+            .find(|(other, _)| *other == epoch)
+            .map(|(_, key)| key)
+            .ok_or_else(|| e2e_error("the key of the message is not known"))?;
+        let text = open(key, from.as_bytes(), sealed)?;
+        String::from_utf8(text).map_err(|_| e2e_error("the text is not valid UTF-8"))
+    }
+}
+
+/// What `KnownKeys::pin` knew about a key
+#[derive(Debug, PartialEq)]
+pub enum Pinned {
+    /// It was already saved
+    Known,
+    /// It's the first time that client is seen, now it's saved
+    New,
+    /// The client had the key with this fingerprint before
+    Changed(String),
+}
+
+/// The fingerprints of the keys of the clients met in the rooms, kept in a
+/// file where every line is `address<TAB>name<TAB>fingerprint`
+pub struct KnownKeys {
+    path: PathBuf,
+}
+
+impl KnownKeys {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        KnownKeys { path: path.into() }
+    }
+
+    /// The file used by the client by default
+    pub fn default_path() -> PathBuf {
+        crate::data_dir().join("known_keys")
+    }
+
+    /// Remembers the `fingerprint` of `name` in the room at `host` the first
+    /// time it's seen
+    pub fn pin(&self, host: &str, name: &str, fingerprint: &str) -> io::Result<Pinned> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(error) if error.kind() == ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(error),
+        };
+        let known = text.lines().find_map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some(known), Some(other), Some(fingerprint))
+                    if known == host && unescape(other) == name =>
+                {
+                    Some(fingerprint.to_string())
+                }
+                _ => None,
+            }
+        });
+        match known {
+            Some(known) if known == fingerprint => Ok(Pinned::Known),
+            Some(known) => Ok(Pinned::Changed(known)),
+            None => {
+                if let Some(dir) = self.path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                let mut file = OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(&self.path)?;
+                writeln!(file, "{}\t{}\t{}", host, escape(name), fingerprint)?;
+                Ok(Pinned::New)
+            }
+        }
+    }
+}
@@ -0,0 +1,368 @@
+use crate::protocol::{self, Code};
+use ring::digest::{Context, SHA256};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+fn file_error(what: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidInput,
+        format!("Chattest file error: {}!", what),
+    )
+}
+
+/// Size and SHA-256 of the file at `path`
+pub fn checksum<P: AsRef<Path>>(path: P) -> io::Result<(u64, Vec<u8>)> {
+    let mut file = File::open(path)?;
+    let mut context = Context::new(&SHA256);
+    let mut buffer = vec![0u8; protocol::CHUNK_SIZE];
+    let mut size = 0;
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        context.update(&buffer[..read]);
+        size += read as u64;
+    }
+    Ok((size, context.finish().as_ref().to_vec()))
+}
+
+/// The last part of a file name sent by someone else, so that the file
+/// can't be saved outside of the folder chosen for it
+pub fn safe_name(name: &str) -> String {
+    match name.rsplit(['/', '\\']).next() {
+        Some(name) if !name.is_empty() && name != "." && name != ".." => name.to_string(),
+        _ => "file".to_string(),
+    }
+}
+
+/// Directory where the client saves the files it receives by default
+pub fn default_dir() -> PathBuf {
+    crate::data_dir().join("downloads")
+}
+
+/// Creates a new file called `name` in `dir`, adding a number to the name
+/// if there is already one
+fn create(dir: &Path, name: &str) -> io::Result<(File, PathBuf)> {
+    fs::create_dir_all(dir)?;
+    let mut path = dir.join(name);
+    for number in 1.. {
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((file, path)),
+            Err(error) if error.kind() == ErrorKind::AlreadyExists => {
+                path = dir.join(format!("{}.{}", name, number));
+            }
+            Err(error) => return Err(error),
+        }
+    }
+    unreachable!("there is always a free name")
+}
+
+/// A file offered by this client
+struct Offer {
+    id: u32,
+    path: PathBuf,
+    size: u64,
+}
+
+/// A file that this client sends
+struct Upload {
+    transfer: u64,
+    file: File,
+    size: u64,
+    /// Bytes sent and bytes that the recipient received
+    sent: u64,
+    received: u64,
+}
+
+/// A file offered to this client, it has a file once it's accepted
+struct Download {
+    transfer: u64,
+    name: String,
+    size: u64,
+    sha256: Vec<u8>,
+    file: Option<(File, PathBuf)>,
+    context: Context,
+    received: u64,
+}
+
+/// The files that a client sends and receives, their parts are sent a few
+/// at a time so that the other messages don't wait for them
+#[derive(Default)]
+pub struct Files {
+    last_offer: u32,
+    offers: Vec<Offer>,
+    uploads: Vec<Upload>,
+    downloads: Vec<Download>,
+    /// Messages about the files to show to the user
+    events: VecDeque<Code>,
+}
+
+impl Files {
+    pub fn new() -> Self {
+        Files::default()
+    }
+
+    /// Offers the file at `path` to `to`, or to everyone if it's empty:
+    /// returns the id of the offer and the message that makes it
+    pub fn offer(&mut self, to: String, path: &Path) -> io::Result<(u32, Code)> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| file_error("the path is not a file"))?
+            .to_string();
+        let (size, sha256) = checksum(path)?;
+        self.last_offer += 1;
+        self.offers.push(Offer {
+            id: self.last_offer,
+            path: path.to_path_buf(),
+            size,
+        });
+        Ok((
+            self.last_offer,
+            Code::FileOffer(self.last_offer, to, name, size, sha256),
+        ))
+    }
+
+    /// Accepts the file of `transfer` saving it in `dir`: returns where it's
+    /// saved and the message that accepts it
+    pub fn accept(&mut self, transfer: u64, dir: &Path) -> io::Result<(PathBuf, Code)> {
+        let download = self
+            .downloads
+            .iter_mut()
+            .find(|download| download.transfer == transfer && download.file.is_none())
+            .ok_or_else(|| file_error("there is no such file offered"))?;
+        let (file, path) = create(dir, &download.name)?;
+        download.file = Some((file, path.clone()));
+        Ok((path, Code::FileAnswer(transfer, true)))
+    }
+
+    /// Refuses the file of `transfer`, returns the message that refuses it
+    pub fn refuse(&mut self, transfer: u64) -> Option<Code> {
+        let idx = self
+            .downloads
+            .iter()
+            .position(|download| download.transfer == transfer && download.file.is_none())?;
+        self.downloads.remove(idx);
+        Some(Code::FileAnswer(transfer, false))
+    }
+
+    /// Stops sending or receiving the file of `transfer`, returns the message
+    /// that tells it to the other side
+    pub fn cancel(&mut self, transfer: u64) -> Option<Code> {
+        self.stop(transfer)
+            .then(|| Code::FileCancel(transfer, "it was cancelled".to_string()))
+    }
+
+    /// Forgets the file of `transfer`, a file that was being received is
+    /// deleted. Returns `false` if it's not known
+    fn stop(&mut self, transfer: u64) -> bool {
+        if let Some(idx) = self
+            .uploads
+            .iter()
+            .position(|upload| upload.transfer == transfer)
+        {
+            self.uploads.remove(idx);
+            return true;
+        }
+        if let Some(idx) = self
+            .downloads
+            .iter()
+            .position(|download| download.transfer == transfer)
+        {
+            if let Some((file, path)) = self.downloads.remove(idx).file {
+                drop(file);
+                let _ = fs::remove_file(path);
+            }
+            return true;
+        }
+        false
+    }
+
+    /// Stops every file because the connection was lost, the room stopped
+    /// them too. The user is told with `event`
+    pub fn lost(&mut self) {
+        let transfers: Vec<u64> = self
+            .uploads
+            .iter()
+            .map(|upload| upload.transfer)
+            .chain(self.downloads.iter().map(|download| download.transfer))
+            .collect();
+        for transfer in transfers {
+            self.stop(transfer);
+            let reason = "the connection was lost".to_string();
+            self.events.push_back(Code::FileCancel(transfer, reason));
+        }
+        self.offers.clear();
+    }
+
+    /// The next message about the files to show to the user
+    pub fn event(&mut self) -> Option<Code> {
+        self.events.pop_front()
+    }
+
+    /// The parts of the files that can be sent now, without having too many
+    /// of them on their way
+    pub fn chunks(&mut self) -> Vec<Code> {
+        let window = protocol::FILE_WINDOW * protocol::CHUNK_SIZE as u64;
+        let mut codes = Vec::new();
+        let mut failed = Vec::new();
+        for upload in &mut self.uploads {
+            while upload.sent < upload.size && upload.sent.saturating_sub(upload.received) < window
+            {
+                let len = (upload.size - upload.sent).min(protocol::CHUNK_SIZE as u64);
+                let mut chunk = vec![0u8; len as usize];
+                match upload.file.read_exact(&mut chunk) {
+                    Ok(()) => {
+                        upload.sent += len;
+                        codes.push(Code::FileChunk(upload.transfer, chunk));
+                    }
+                    // The file changed since it was offered
+                    Err(error) => {
+                        failed.push((upload.transfer, error.to_string()));
+                        break;
+                    }
+                }
+            }
+        }
+        for (transfer, reason) in failed {
+            self.stop(transfer);
+            codes.push(Code::FileCancel(transfer, reason.clone()));
+            self.events.push_back(Code::FileCancel(transfer, reason));
+        }
+        codes
+    }
+
+    /// Handles a message of the room about the files, returns the messages
+    /// to send back. What the user must know can be taken with `event`
+    pub fn received(&mut self, code: Code) -> Vec<Code> {
+        match code {
+            Code::FileOffered(transfer, from, name, size, sha256) => {
+                let name = safe_name(&name);
+                self.downloads.push(Download {
+                    transfer,
+                    name: name.clone(),
+                    size,
+                    sha256: sha256.clone(),
+                    file: None,
+                    context: Context::new(&SHA256),
+                    received: 0,
+                });
+                self.events
+                    .push_back(Code::FileOffered(transfer, from, name, size, sha256));
+                Vec::new()
+            }
+            Code::FileAnswered(offer, transfer, true, name) => {
+                self.events
+                    .push_back(Code::FileAnswered(offer, transfer, true, name));
+                let file = self
+                    .offers
+                    .iter()
+                    .find(|other| other.id == offer)
+                    .ok_or_else(|| file_error("the file wasn't offered"))
+                    .and_then(|offer| Ok((File::open(&offer.path)?, offer.size)));
+                match file {
+                    Ok((file, size)) => {
+                        self.uploads.push(Upload {
+                            transfer,
+                            file,
+                            size,
+                            sent: 0,
+                            received: 0,
+                        });
+                        // An empty file has no parts, but the recipient must
+                        // know that it's complete
+                        if size == 0 {
+                            vec![Code::FileChunk(transfer, Vec::new())]
+                        } else {
+                            Vec::new()
+                        }
+                    }
+                    Err(error) => {
+                        let reason = error.to_string();
+                        self.events
+                            .push_back(Code::FileCancel(transfer, reason.clone()));
+                        vec![Code::FileCancel(transfer, reason)]
+                    }
+                }
+            }
+            Code::FileChunk(transfer, data) => self.chunk(transfer, data),
+            Code::FileReceived(transfer, bytes) => {
+                if let Some(idx) = self
+                    .uploads
+                    .iter()
+                    .position(|upload| upload.transfer == transfer)
+                {
+                    // The other side can't have received what wasn't sent
+                    let bytes = bytes.min(self.uploads[idx].sent);
+                    self.uploads[idx].received = bytes;
+                    if bytes >= self.uploads[idx].size {
+                        self.uploads.remove(idx);
+                    }
+                    self.events.push_back(Code::FileReceived(transfer, bytes));
+                }
+                Vec::new()
+            }
+            Code::FileCancel(transfer, reason) => {
+                self.stop(transfer);
+                self.events.push_back(Code::FileCancel(transfer, reason));
+                Vec::new()
+            }
+            code => {
+                self.events.push_back(code);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Saves a part of the file of `transfer`, the whole file must have the
+    /// SHA-256 that was offered
+    fn chunk(&mut self, transfer: u64, data: Vec<u8>) -> Vec<Code> {
+        let idx = match self
+            .downloads
+            .iter()
+            .position(|download| download.transfer == transfer)
+        {
+            Some(idx) => idx,
+            // It was cancelled meanwhile
+            None => return Vec::new(),
+        };
+        let download = &mut self.downloads[idx];
+        let written = match &mut download.file {
+            Some(_) if download.received + data.len() as u64 > download.size => {
+                Err(file_error("the file is longer than it should be"))
+            }
+            Some((file, _)) => file.write_all(&data),
+            None => Err(file_error("the file wasn't accepted")),
+        };
+        download.context.update(&data);
+        download.received += data.len() as u64;
+        let done = download.received == download.size;
+        let result = written.and_then(|()| {
+            if done && download.context.clone().finish().as_ref() != download.sha256.as_slice() {
+                return Err(file_error("the file doesn't have the right SHA-256"));
+            }
+            Ok(download.received)
+        });
+        match result {
+            Ok(received) => {
+                if done {
+                    // The file is complete, keep it
+                    self.downloads.remove(idx);
+                }
+                self.events
+                    .push_back(Code::FileReceived(transfer, received));
+                vec![Code::FileReceived(transfer, received)]
+            }
+            Err(error) => {
+                self.stop(transfer);
+                let reason = error.to_string();
+                self.events
+                    .push_back(Code::FileCancel(transfer, reason.clone()));
+                vec![Code::FileCancel(transfer, reason)]
+            }
+        }
+    }
+}
@@ -0,0 +1,300 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A message said in a room, or a change to one
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    /// Id given by the room to the message, 0 for the ones saved before
+    /// the messages had an id
+    pub id: u64,
+    /// Name of who sent the message, or of who changed it
+    pub sender: String,
+    /// Seconds since the UNIX epoch (UTC)
+    pub time: u64,
+    pub text: String,
+    pub change: Change,
+}
+
+/// What an `Entry` is
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Change {
+    /// A new message
+    Sent,
+    /// A new text for the message with the same id
+    Edited,
+    /// The message with the same id was deleted, the text is empty
+    Deleted,
+}
+
+impl Change {
+    /// How it's written in the stores
+    fn as_str(self) -> &'static str {
+        match self {
+            Change::Sent => "sent",
+            Change::Edited => "edited",
+            Change::Deleted => "deleted",
+        }
+    }
+
+    fn parse(val: &str) -> Option<Self> {
+        match val {
+            "sent" => Some(Change::Sent),
+            "edited" => Some(Change::Edited),
+            "deleted" => Some(Change::Deleted),
+            _ => None,
+        }
+    }
+}
+
+/// Seconds since the UNIX epoch (UTC)
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+/// Formats the `time` of an `Entry` as `HH:MM` (UTC)
+pub fn clock(time: u64) -> String {
+    format!("{:02}:{:02}", time / 3600 % 24, time / 60 % 60)
+}
+
+impl Entry {
+    /// A message sent right now
+    pub fn now(id: u64, sender: String, text: String) -> Self {
+        Entry {
+            id,
+            sender,
+            time: now(),
+            text,
+            change: Change::Sent,
+        }
+    }
+}
+
+/// Applies `entry` to the messages in `history`: a new message is added,
+/// an edit changes the text of the message with its id (that keeps its
+/// sender and its time) and a deletion removes it
+pub fn apply(history: &mut Vec<Entry>, entry: Entry) {
+    let idx = history
+        .iter()
+        .position(|other| other.id != 0 && other.id == entry.id);
+    match (entry.change, idx) {
+        (Change::Sent, _) => history.push(entry),
+        (Change::Edited, Some(idx)) => {
+            history[idx].text = entry.text;
+            history[idx].change = Change::Edited;
+        }
+        (Change::Deleted, Some(idx)) => {
+            history.remove(idx);
+        }
+        // The message is not there anymore
+        (_, None) => (),
+    }
+}
+
+/// Where the messages of a room are kept when the room is closed
+pub trait Store: Send {
+    /// Saves a new message
+    fn append(&mut self, entry: &Entry) -> io::Result<()>;
+    /// Returns all the saved messages, oldest first
+    fn load(&mut self) -> io::Result<Vec<Entry>>;
+}
+
+/// File where the host keeps the messages of `room` by default
+pub fn default_path(admin: &str, room: &str) -> PathBuf {
+    crate::room_file("history", admin, room, "log")
+}
+
+/// Escapes the characters that separate the fields of a `LogFile`
+pub(crate) fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+pub(crate) fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => match chars.next() {
+                Some('t') => result.push('\t'),
+                Some('n') => result.push('\n'),
+                Some(ch) => result.push(ch),
+                None => (),
+            },
+            ch => result.push(ch),
+        }
+    }
+    result
+}
+
+/// A text file where every message is appended as a line:
+/// `time<TAB>sender<TAB>text<TAB>id<TAB>change`, the lines written before
+/// the messages had an id end after the text
+pub struct LogFile {
+    file: File,
+}
+
+impl LogFile {
+    /// Opens the file at `path`, it's created if it doesn't exist
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        if let Some(dir) = path.as_ref().parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        Ok(LogFile { file })
+    }
+}
+
+impl Store for LogFile {
+    fn append(&mut self, entry: &Entry) -> io::Result<()> {
+        writeln!(
+            self.file,
+            "{}\t{}\t{}\t{}\t{}",
+            entry.time,
+            escape(&entry.sender),
+            escape(&entry.text),
+            entry.id,
+            entry.change.as_str()
+        )?;
+        self.file.flush()
+    }
+
+    fn load(&mut self) -> io::Result<Vec<Entry>> {
+        self.file.seek(SeekFrom::Start(0))?;
+        let mut entries = Vec::new();
+        for line in BufReader::new(&self.file).lines() {
+            let line = line?;
+            let fields: Vec<&str> = line.split('\t').collect();
+            // Lines that are not complete (like a write interrupted by a
+            // crash) are skipped
+            let (time, sender, text, id, change) = match fields[..] {
+                [time, sender, text] => (time, sender, text, Some(0), Some(Change::Sent)),
+                [time, sender, text, id, change] => {
+                    (time, sender, text, id.parse().ok(), Change::parse(change))
+                }
+                _ => continue,
+            };
+            if let (Ok(time), Some(id), Some(change)) = (time.parse(), id, change) {
+                entries.push(Entry {
+                    id,
+                    sender: unescape(sender),
+                    time,
+                    text: unescape(text),
+                    change,
+                });
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// An SQLite database with a `messages` table
+#[cfg(feature = "sqlite")]
+pub struct Sqlite {
+    connection: rusqlite::Connection,
+}
+
+#[cfg(feature = "sqlite")]
+fn sqlite_error(error: rusqlite::Error) -> io::Error {
+    io::Error::other(error)
+}
+
+#[cfg(feature = "sqlite")]
+impl Sqlite {
+    /// Opens the database at `path`, it's created if it doesn't exist
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        if let Some(dir) = path.as_ref().parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let connection = rusqlite::Connection::open(path).map_err(sqlite_error)?;
+        connection
+            .execute(
+                "CREATE TABLE IF NOT EXISTS messages (
+                    id INTEGER PRIMARY KEY,
+                    sender TEXT NOT NULL,
+                    time INTEGER NOT NULL,
+                    text TEXT NOT NULL,
+                    message INTEGER NOT NULL DEFAULT 0,
+                    change TEXT NOT NULL DEFAULT 'sent'
+                )",
+                [],
+            )
+            .map_err(sqlite_error)?;
+        // The databases made before the messages had an id miss the columns
+        let columns: i64 = connection
+            .query_row(
+                "SELECT COUNT(*) FROM pragma_table_info('messages') WHERE name = 'message'",
+                [],
+                |row| row.get(0),
+            )
+            .map_err(sqlite_error)?;
+        if columns == 0 {
+            connection
+                .execute_batch(
+                    "ALTER TABLE messages ADD COLUMN message INTEGER NOT NULL DEFAULT 0;
+                    ALTER TABLE messages ADD COLUMN change TEXT NOT NULL DEFAULT 'sent';",
+                )
+                .map_err(sqlite_error)?;
+        }
+        Ok(Sqlite { connection })
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl Store for Sqlite {
+    fn append(&mut self, entry: &Entry) -> io::Result<()> {
+        self.connection
+            .execute(
+                "INSERT INTO messages (sender, time, text, message, change)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+                rusqlite::params![
+                    entry.sender,
+                    entry.time as i64,
+                    entry.text,
+                    entry.id as i64,
+                    entry.change.as_str()
+                ],
+            )
+            .map_err(sqlite_error)?;
+        Ok(())
+    }
+
+    fn load(&mut self) -> io::Result<Vec<Entry>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT sender, time, text, message, change FROM messages ORDER BY id")
+            .map_err(sqlite_error)?;
+        let rows = statement
+            .query_map([], |row| {
+                Ok((
+                    Entry {
+                        id: row.get::<_, i64>(3)? as u64,
+                        sender: row.get(0)?,
+                        time: row.get::<_, i64>(1)? as u64,
+                        text: row.get(2)?,
+                        change: Change::Sent,
+                    },
+                    row.get::<_, String>(4)?,
+                ))
+            })
+            .map_err(sqlite_error)?;
+        let mut entries = Vec::new();
+        for row in rows {
+            let (mut entry, change) = row.map_err(sqlite_error)?;
+            // Rows with a change that isn't known are skipped
+            if let Some(change) = Change::parse(&change) {
+                entry.change = change;
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+}
@@ -0,0 +1,508 @@
+use crate::lobby::Lobby;
+use crate::protocol::{self, Code};
+use crate::room::{Action, Id, Moderation};
+use mio::net::{TcpListener, TcpStream, UdpSocket};
+use mio::{Events, Interest, Poll, Token, Waker};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+const DISCOVERY: Token = Token(2);
+/// Tokens of the clients start after the ones above
+const FIRST_CLIENT: Id = 3;
+/// Most bytes kept for a client that doesn't read them, then it's dropped
+const MAX_OUTGOING: usize = 4 * protocol::MAX_FRAME;
+
+/// What the host of the room asks to the thread serving it, the commands
+/// of an admin start with the name of his room
+enum Command {
+    Say(String, String),
+    Whisper(String, String, String),
+    Moderate(String, Moderation),
+    Stop,
+}
+
+/// One or more rooms being served by a background thread, it only wakes up
+/// when a socket is ready, the host sends a message or the name of someone
+/// that lost the connection must be released
+pub struct Host {
+    commands: Sender<Command>,
+    waker: Arc<Waker>,
+    events: Receiver<String>,
+}
+
+impl Host {
+    /// Starts serving `rooms` (a `Room` or a `Lobby`) with the clients that
+    /// connect to `listener`
+    pub fn start(listener: std::net::TcpListener, rooms: impl Into<Lobby>) -> io::Result<Self> {
+        Self::spawn(
+            listener,
+            rooms.into(),
+            #[cfg(feature = "tls")]
+            None,
+        )
+    }
+
+    /// Like `start`, but the clients can encrypt the connection with TLS
+    /// using the certificate in `config`
+    #[cfg(feature = "tls")]
+    pub fn start_tls(
+        listener: std::net::TcpListener,
+        rooms: impl Into<Lobby>,
+        config: Arc<rustls::ServerConfig>,
+    ) -> io::Result<Self> {
+        let mut lobby = rooms.into();
+        lobby.set_tls(true);
+        Self::spawn(listener, lobby, Some(config))
+    }
+
+    fn spawn(
+        listener: std::net::TcpListener,
+        lobby: Lobby,
+        #[cfg(feature = "tls")] tls: Option<Arc<rustls::ServerConfig>>,
+    ) -> io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        let mut listener = TcpListener::from_std(listener);
+        let poll = Poll::new()?;
+        poll.registry()
+            .register(&mut listener, LISTENER, Interest::READABLE)?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let (commands, commands_rx) = mpsc::channel();
+        let (events_tx, events) = mpsc::channel();
+        // Respond to DISCOVER on the same address, the rooms can still be
+        // joined without it
+        let addr = listener.local_addr()?;
+        let discovery = match UdpSocket::bind(addr) {
+            Ok(mut socket) => {
+                poll.registry()
+                    .register(&mut socket, DISCOVERY, Interest::READABLE)?;
+                Some(socket)
+            }
+            Err(error) => {
+                let _ = events_tx.send(format!("  Discovery error: {}", error));
+                None
+            }
+        };
+
+        let errors = events_tx.clone();
+        let mut reactor = Reactor {
+            poll,
+            listener,
+            discovery,
+            port: addr.port(),
+            lobby,
+            connections: HashMap::new(),
+            next: FIRST_CLIENT,
+            commands: commands_rx,
+            events: events_tx,
+            #[cfg(feature = "tls")]
+            tls,
+        };
+        thread::spawn(move || {
+            if let Err(error) = reactor.run() {
+                let _ = errors.send(format!("  Host error: {}", error));
+            }
+        });
+        Ok(Host {
+            commands,
+            waker,
+            events,
+        })
+    }
+
+    /// Sends a message of the admin of the room called `room`
+    pub fn say(&self, room: &str, text: String) -> io::Result<()> {
+        self.send(Command::Say(room.to_string(), text))
+    }
+
+    /// Sends a private message of the admin of the room called `room` to
+    /// the participant `to`
+    pub fn whisper(&self, room: &str, to: String, text: String) -> io::Result<()> {
+        self.send(Command::Whisper(room.to_string(), to, text))
+    }
+
+    /// Kicks, bans or mutes someone in the room called `room`
+    pub fn moderate(&self, room: &str, command: Moderation) -> io::Result<()> {
+        self.send(Command::Moderate(room.to_string(), command))
+    }
+
+    /// Lines that describe what happens in the room
+    pub fn events(&self) -> &Receiver<String> {
+        &self.events
+    }
+
+    /// Gives `command` to the thread, it's an error if it can't be woken up
+    fn send(&self, command: Command) -> io::Result<()> {
+        // The thread is gone if nobody receives the commands
+        if self.commands.send(command).is_ok() {
+            self.waker.wake()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Host {
+    fn drop(&mut self) {
+        // The thread stops anyway once the commands are dropped
+        let _ = self.send(Command::Stop);
+    }
+}
+
+/// A client connected to the room
+struct Connection {
+    stream: TcpStream,
+    decoder: protocol::Decoder,
+    /// Bytes waiting for the socket to be writable
+    outgoing: Vec<u8>,
+    /// The connection is closed once `outgoing` is empty
+    closing: bool,
+    /// TLS on top of the socket, once the client asked for it
+    #[cfg(feature = "tls")]
+    tls: Option<Box<rustls::ServerConnection>>,
+}
+
+impl Connection {
+    /// Adds `code` to the bytes to send, it's encrypted if there is TLS
+    fn queue(&mut self, code: &Code) {
+        #[cfg(feature = "tls")]
+        {
+            if let Some(tls) = &mut self.tls {
+                let mut plain = Vec::new();
+                protocol::encode(code, &mut plain);
+                // What can't be encrypted yet is kept until the handshake ends
+                let _ = tls.writer().write_all(&plain);
+                self.seal();
+                return;
+            }
+        }
+        protocol::encode(code, &mut self.outgoing);
+    }
+
+    /// Moves what TLS has to send (encrypted messages and the handshake) to
+    /// `outgoing`
+    #[cfg(feature = "tls")]
+    fn seal(&mut self) {
+        if let Some(tls) = &mut self.tls {
+            while tls.wants_write() {
+                if tls.write_tls(&mut self.outgoing).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Handles the bytes that arrived, they are decrypted first if there is TLS
+    fn incoming(&mut self, bytes: &[u8]) -> io::Result<()> {
+        #[cfg(feature = "tls")]
+        {
+            if let Some(tls) = &mut self.tls {
+                let mut rest = bytes;
+                while !rest.is_empty() {
+                    tls.read_tls(&mut rest)?;
+                    let state = match tls.process_new_packets() {
+                        Ok(state) => state,
+                        Err(error) => {
+                            // Tell the client what went wrong before closing
+                            self.seal();
+                            return Err(crate::tls::tls_error(error));
+                        }
+                    };
+                    let mut plain = vec![0; state.plaintext_bytes_to_read()];
+                    tls.reader().read_exact(&mut plain)?;
+                    self.decoder.extend(&plain);
+                }
+                self.seal();
+                return Ok(());
+            }
+        }
+        self.decoder.extend(bytes);
+        Ok(())
+    }
+
+    /// Writes as much of `outgoing` as the socket accepts
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.outgoing.is_empty() {
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(bytes) => {
+                    self.outgoing.drain(..bytes);
+                }
+                Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(error) if error.kind() == ErrorKind::Interrupted => (),
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads everything that arrived into the decoder, returns `false` if
+    /// the client closed the connection
+    fn receive(&mut self) -> io::Result<bool> {
+        let mut chunk = [0u8; 1024];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Ok(false),
+                Ok(bytes) => self.incoming(&chunk[..bytes])?,
+                Err(error) if error.kind() == ErrorKind::WouldBlock => return Ok(true),
+                Err(error) if error.kind() == ErrorKind::Interrupted => (),
+                Err(error) => return Err(error),
+            }
+        }
+    }
+}
+
+struct Reactor {
+    poll: Poll,
+    listener: TcpListener,
+    /// Socket where the clients send DISCOVER
+    discovery: Option<UdpSocket>,
+    /// Port of `listener`, told to who sends DISCOVER
+    port: u16,
+    lobby: Lobby,
+    connections: HashMap<Id, Connection>,
+    next: Id,
+    commands: Receiver<Command>,
+    events: Sender<String>,
+    /// Certificate used when the clients ask for TLS
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+impl Reactor {
+    fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(128);
+        loop {
+            // Sleep until something happens or someone away must be forgotten
+            if let Err(error) = self.poll.poll(&mut events, self.lobby.next_expiry()) {
+                match error.kind() {
+                    ErrorKind::Interrupted => continue,
+                    _ => return Err(error),
+                }
+            }
+            let actions = self.lobby.expire();
+            self.apply(actions);
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => self.accept(),
+                    DISCOVERY => self.announce(),
+                    WAKER => {
+                        while let Ok(command) = self.commands.try_recv() {
+                            match command {
+                                Command::Say(room, text) => {
+                                    let actions = self.lobby.say(&room, text);
+                                    self.apply(actions);
+                                }
+                                Command::Whisper(room, to, text) => {
+                                    let actions = self.lobby.whisper(&room, to, text);
+                                    self.apply(actions);
+                                }
+                                Command::Moderate(room, command) => {
+                                    let actions = self.lobby.moderate(&room, command);
+                                    self.apply(actions);
+                                }
+                                Command::Stop => return Ok(()),
+                            }
+                        }
+                    }
+                    Token(id) => {
+                        if event.is_readable() {
+                            self.readable(id);
+                        }
+                        if event.is_writable() {
+                            self.writable(id);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Accepts all the clients that are waiting
+    fn accept(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((mut stream, addr)) => {
+                    let id = self.next;
+                    self.next += 1;
+                    if let Err(error) = self.poll.registry().register(
+                        &mut stream,
+                        Token(id),
+                        Interest::READABLE | Interest::WRITABLE,
+                    ) {
+                        self.log(format!("  Register error: {}", error));
+                        continue;
+                    }
+                    self.connections.insert(
+                        id,
+                        Connection {
+                            stream,
+                            decoder: protocol::Decoder::new(),
+                            outgoing: Vec::new(),
+                            closing: false,
+                            #[cfg(feature = "tls")]
+                            tls: None,
+                        },
+                    );
+                    let actions = self.lobby.connected(id, addr);
+                    self.apply(actions);
+                }
+                Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(error) => {
+                    self.log(format!("  Accept error: {}", error));
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Tells the rooms to who looks for them
+    fn announce(&mut self) {
+        let socket = match &self.discovery {
+            Some(socket) => socket,
+            None => return,
+        };
+        let mut buffer = [0u8; 512];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(error) => {
+                    let _ = self.events.send(format!("  Discovery error: {}", error));
+                    break;
+                }
+            };
+            // Anything else could be sent there, even the responses
+            if let Ok(Code::Discover) = protocol::decode(&buffer[..len]) {
+                for code in self.lobby.announce(self.port) {
+                    let mut datagram = Vec::new();
+                    protocol::encode(&code, &mut datagram);
+                    if let Err(error) = socket.send_to(&datagram, from) {
+                        let _ = self.events.send(format!("  Discovery error: {}", error));
+                    }
+                }
+            }
+        }
+    }
+
+    fn readable(&mut self, id: Id) {
+        let open = match self.connections.get_mut(&id) {
+            Some(connection) => connection.receive(),
+            None => return,
+        };
+        // The messages sent right before closing are still delivered
+        while let Some(connection) = self.connections.get_mut(&id) {
+            match connection.decoder.decode() {
+                Ok(Some(code)) => {
+                    let actions = self.lobby.received(id, code);
+                    self.apply(actions);
+                }
+                Ok(None) => break,
+                // The client sent something that can't be decoded, skip it
+                Err(error) => self.log(format!("  Error with client {}: {}", id, error)),
+            }
+        }
+        match open {
+            Ok(true) => (),
+            Ok(false) => self.drop_connection(id),
+            Err(error) => {
+                self.log(format!(
+                    "  Error with client {}: {}({:?})",
+                    id,
+                    error,
+                    error.kind()
+                ));
+                self.drop_connection(id);
+            }
+        }
+    }
+
+    fn writable(&mut self, id: Id) {
+        let done = match self.connections.get_mut(&id) {
+            Some(connection) => match connection.flush() {
+                // The others must not fill the memory of the host for him
+                Ok(()) if connection.outgoing.len() > MAX_OUTGOING => {
+                    self.log(format!(
+                        "  Error with client {}: it doesn't read what it's sent",
+                        id
+                    ));
+                    true
+                }
+                Ok(()) => connection.closing && connection.outgoing.is_empty(),
+                Err(_) => true,
+            },
+            None => return,
+        };
+        if done {
+            self.drop_connection(id);
+        }
+    }
+
+    /// Forgets the connection `id` and tells the room
+    fn drop_connection(&mut self, id: Id) {
+        if let Some(mut connection) = self.connections.remove(&id) {
+            let _ = self.poll.registry().deregister(&mut connection.stream);
+        }
+        let actions = self.lobby.disconnected(id);
+        self.apply(actions);
+    }
+
+    fn apply(&mut self, actions: Vec<Action>) {
+        for action in actions {
+            match action {
+                Action::Send(id, code) => {
+                    if let Some(connection) = self.connections.get_mut(&id) {
+                        connection.queue(&code);
+                    }
+                    self.writable(id);
+                }
+                Action::Close(id) => {
+                    if let Some(connection) = self.connections.get_mut(&id) {
+                        connection.closing = true;
+                        // Say goodbye to TLS too
+                        #[cfg(feature = "tls")]
+                        {
+                            if let Some(tls) = &mut connection.tls {
+                                tls.send_close_notify();
+                            }
+                            connection.seal();
+                        }
+                    }
+                    self.writable(id);
+                }
+                Action::StartTls(id) => self.start_tls(id),
+                Action::Log(line) => self.log(line),
+            }
+        }
+    }
+
+    /// Tells the host what happened
+    fn log(&self, line: String) {
+        // Nobody is listening if the host is gone
+        let _ = self.events.send(line);
+    }
+
+    /// Encrypts the connection `id`, it's closed if the host can't do it
+    fn start_tls(&mut self, id: Id) {
+        #[cfg(feature = "tls")]
+        {
+            if let (Some(config), Some(connection)) = (&self.tls, self.connections.get_mut(&id)) {
+                match rustls::ServerConnection::new(config.clone()) {
+                    Ok(tls) => {
+                        connection.tls = Some(Box::new(tls));
+                        return;
+                    }
+                    Err(error) => self.log(format!("  TLS error with client {}: {}", id, error)),
+                }
+            }
+        }
+        self.log(format!(
+            "  Client {} asked for TLS but the host can't use it",
+            id
+        ));
+        self.apply(vec![Action::Close(id)]);
+    }
+}
@@ -0,0 +1,60 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub mod accounts;
+pub mod bans;
+pub mod connection;
+pub mod discovery;
+pub mod e2e;
+pub mod files;
+pub mod history;
+pub mod host;
+pub mod lobby;
+pub mod protocol;
+pub mod room;
+
+#[cfg(feature = "async")]
+pub mod asynchronous;
+#[cfg(feature = "tls")]
+pub mod tls;
+
+/// Directory where Chattest keeps its files: `.chattest` in the home of the
+/// user, or in the current directory if there is no home
+pub fn data_dir() -> PathBuf {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map_or_else(PathBuf::new, PathBuf::from)
+        .join(".chattest")
+}
+
+/// File in `kind` inside the data directory where something about `room`,
+/// hosted by `admin`, is kept
+pub(crate) fn room_file(kind: &str, admin: &str, room: &str, extension: &str) -> PathBuf {
+    // Names can contain anything, file names can't
+    let clean = |name: &str| -> String {
+        name.chars()
+            .map(|ch| if ch.is_alphanumeric() { ch } else { '_' })
+            .collect()
+    };
+    data_dir()
+        .join(kind)
+        .join(clean(admin))
+        .join(clean(room) + "." + extension)
+}
+
+/// Saves `bytes`, like a private key, in a new file at `path` that only the
+/// user can read. A file that is already there is never overwritten
+pub(crate) fn write_secret(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(bytes)
+}
@@ -0,0 +1,180 @@
+use crate::protocol::{self, Code, RoomInfo};
+use crate::room::{Action, Id, Moderation, Room};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// The rooms hosted by a server, each one with its own admin, members and
+/// history. Like a `Room` it doesn't touch the network: the clients join the
+/// first room unless they pick another one before joining
+pub struct Lobby {
+    rooms: Vec<Room>,
+    /// The room of every connection and the capabilities it offered with
+    /// HELLO, to tell it the ones of the room it picks
+    connections: HashMap<Id, (usize, u32)>,
+}
+
+impl Lobby {
+    /// Hosts `rooms`, there must be at least one
+    pub fn new(mut rooms: Vec<Room>) -> Self {
+        assert!(!rooms.is_empty(), "a lobby needs a room");
+        for room in &mut rooms {
+            room.set_lobby(true);
+        }
+        Lobby {
+            rooms,
+            connections: HashMap::new(),
+        }
+    }
+
+    /// The rooms hosted, the first one is where the clients go by default
+    pub fn rooms(&self) -> &[Room] {
+        &self.rooms
+    }
+
+    /// The responses to DISCOVER, one for every room, `port` is where the
+    /// server listens
+    pub fn announce(&self, port: u16) -> Vec<Code> {
+        self.rooms
+            .iter()
+            .map(|room| Code::Announce(port, room.name().to_string(), room.admin().to_string()))
+            .collect()
+    }
+
+    /// Lets the clients of every room ask to encrypt the connection
+    pub fn set_tls(&mut self, tls: bool) {
+        for room in &mut self.rooms {
+            room.set_tls(tls);
+        }
+    }
+
+    /// Shows which room the lines to the host are about, if there is more
+    /// than one
+    fn tag(&self, room: usize, actions: Vec<Action>) -> Vec<Action> {
+        if self.rooms.len() == 1 {
+            return actions;
+        }
+        let name = self.rooms[room].name();
+        actions
+            .into_iter()
+            .map(|action| match action {
+                Action::Log(line) => Action::Log(format!("  [{}] {}", name, line.trim_start())),
+                action => action,
+            })
+            .collect()
+    }
+
+    /// A new connection arrived, it's in the first room until it picks one
+    pub fn connected(&mut self, id: Id, addr: SocketAddr) -> Vec<Action> {
+        self.connections.insert(id, (0, 0));
+        let actions = self.rooms[0].connected(id, addr);
+        self.tag(0, actions)
+    }
+
+    /// The connection `id` was closed
+    pub fn disconnected(&mut self, id: Id) -> Vec<Action> {
+        match self.connections.remove(&id) {
+            Some((room, _)) => {
+                let actions = self.rooms[room].disconnected(id);
+                self.tag(room, actions)
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// The connection `id` sent a message
+    pub fn received(&mut self, id: Id, code: Code) -> Vec<Action> {
+        let (room, offered) = match self.connections.get_mut(&id) {
+            Some(connection) => connection,
+            None => return Vec::new(),
+        };
+        match code {
+            // Remember what he can do, in case he goes to another room
+            Code::Hello(_, capabilities) => *offered = capabilities,
+            // If he wants to know the rooms tell him who is in them
+            Code::ListRooms => {
+                let rooms = self.rooms.iter().map(|room| RoomInfo {
+                    name: room.name().to_string(),
+                    members: room.members() as u32,
+                    password: room.has_password(),
+                });
+                return vec![Action::Send(id, Code::Rooms(rooms.collect()))];
+            }
+            // If he picks a room move him there, unless he already joined
+            Code::Room(name) => {
+                let (from, offered) = (*room, *offered);
+                let addr = match self.rooms[from].moved_out(id) {
+                    Some(addr) => addr,
+                    None => return Vec::new(),
+                };
+                return match self.rooms.iter().position(|room| room.name() == name) {
+                    Some(to) => {
+                        self.connections.insert(id, (to, offered));
+                        let actions = self.rooms[to].moved_in(id, addr, offered);
+                        self.tag(to, actions)
+                    }
+                    None => {
+                        self.connections.remove(&id);
+                        vec![
+                            Action::Send(id, Code::Rejected(protocol::Reason::NoSuchRoom)),
+                            Action::Close(id),
+                        ]
+                    }
+                };
+            }
+            _ => (),
+        }
+        let room = *room;
+        let actions = self.rooms[room].received(id, code);
+        self.tag(room, actions)
+    }
+
+    /// Releases the names of who lost the connection and didn't come back
+    /// in time, in every room. `next_expiry` says when it must be called
+    pub fn expire(&mut self) -> Vec<Action> {
+        let mut actions = Vec::new();
+        for room in 0..self.rooms.len() {
+            let expired = self.rooms[room].expire();
+            actions.extend(self.tag(room, expired));
+        }
+        actions
+    }
+
+    /// How long until a room releases the name of someone that lost the
+    /// connection, `None` if nobody is away
+    pub fn next_expiry(&self) -> Option<Duration> {
+        self.rooms.iter().filter_map(Room::next_expiry).min()
+    }
+
+    /// The admin of the room called `room` sent a message
+    pub fn say(&mut self, room: &str, text: String) -> Vec<Action> {
+        self.admin(room, |room| room.say(text))
+    }
+
+    /// The admin of the room called `room` sent a private message to `to`
+    pub fn whisper(&mut self, room: &str, to: String, text: String) -> Vec<Action> {
+        self.admin(room, |room| room.whisper(to, text))
+    }
+
+    /// The admin of the room called `room` kicks, bans or mutes someone
+    pub fn moderate(&mut self, room: &str, command: Moderation) -> Vec<Action> {
+        self.admin(room, |room| room.moderate(command))
+    }
+
+    /// Does what the admin of the room called `room` asked with `act`
+    fn admin(&mut self, room: &str, act: impl FnOnce(&mut Room) -> Vec<Action>) -> Vec<Action> {
+        match self.rooms.iter().position(|other| other.name() == room) {
+            Some(idx) => {
+                let actions = act(&mut self.rooms[idx]);
+                self.tag(idx, actions)
+            }
+            None => vec![Action::Log(format!("  There is no room called {}!", room))],
+        }
+    }
+}
+
+impl From<Room> for Lobby {
+    fn from(room: Room) -> Self {
+        Lobby::new(vec![room])
+    }
+}
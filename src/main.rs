@@ -1,14 +1,24 @@
 #![windows_subsystem = "windows"]
 
+#[cfg(feature = "tui")]
 use pancurses::*;
 
+#[cfg(feature = "tui")]
 mod utilities;
+#[cfg(feature = "tui")]
 use utilities::*;
 
-mod chattest;
+#[cfg(feature = "tui")]
+use chattest::connection;
+use chattest::{accounts, bans, history, host, lobby, protocol, room};
+
+#[cfg(feature = "tui")]
 mod client;
+mod serve;
+#[cfg(feature = "tui")]
 mod server;
 
+#[cfg(feature = "tui")]
 const TITLE: &str = "    ___ _           _   _            _   
    / __\\ |__   __ _| |_| |_ ___  ___| |_ 
   / /  | '_ \\ / _` | __| __/ _ \\/ __| __|
@@ -16,13 +26,34 @@ const TITLE: &str = "    ___ _           _   _            _
  \\____/|_| |_|\\__,_|\\__|\\__\\___||___/\\__|
                               by Rimpampa";
 
+#[cfg(feature = "tui")]
 const TITLE_HEIGTH: i32 = 5;
 
+#[cfg(feature = "tui")]
 const WIDTH: i32 = 42;
+#[cfg(feature = "tui")]
 const HEIGHT: i32 = 20;
+#[cfg(feature = "tui")]
 const LAST: i32 = HEIGHT - TITLE_HEIGTH - 4;
 
 fn main() {
+    // Host a room without the user interface
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("serve") {
+        std::process::exit(serve::main(&args[1..]));
+    }
+    #[cfg(feature = "tui")]
+    tui();
+    #[cfg(not(feature = "tui"))]
+    {
+        eprintln!("This program was built without the tui feature, use chattest serve");
+        std::process::exit(2);
+    }
+}
+
+/// Shows the menu in the terminal until the user exits
+#[cfg(feature = "tui")]
+fn tui() {
     let window = initscr();
     resize_term(20, 42);
     set_title("Chattest");
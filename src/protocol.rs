@@ -0,0 +1,1867 @@
+/*
+ CHATTEST PROTOCOL (port: 7357)
+    Riccardo Ripanti xx/11/2019
+ ===============================================================================
+
+ SPECIFICATION:
+
+ +----+
+ |    | = 1 byte
+ +----+
+
+ Every message starts with a `code` which is an unsigned byte which specifies
+ the contents of the message, followed by the `length` of the contents (4
+ bytes, MSB first) that can't be more than 16 MiB:
+
+ - NAME (code 1)
+      message is a name:
+
+               +----+----+----+----+----+ - - - - - - - +
+               |code|      length       |     name      |
+               +----+----+----+----+----+ - - - - - - - +
+                     MSB            LSB  <---length---->
+
+      Clients send this type of message right after connecting to the server,
+      putting their name inside. Server then responds with a code 2 message or
+      another code 5 message where it tells to the client the name of the room
+      and the name of the admin.
+
+ - ALREADY_HERE (code 2)
+      only the code and a zero length are sent. It is used to tell to the client who is
+      trying to connect to the server that there is already someone with his
+      name.
+
+ - MESSAGE_TO (code 3)
+      the message is some text, it is used by the client to send messages
+
+               +----+----+----+----+----+ - - - - - - - +
+               |code|      length       |      text     |
+               +----+----+----+----+----+ - - - - - - - +
+                     MSB            LSB  <---length---->
+
+ - MESSAGE_FROM (code 4)
+      the message contains the name of the client and the text he sent:
+
+          MSB            LSB  MSB            LSB  <--namelen-->
+    +----+----+----+----+----+----+----+----+----+ - - - - - - + - - - - - +
+    |0x04|      length       |     name_len      |    name     |   text    |
+    +----+----+----+----+----+----+----+----+----+ - - - - - - + - - - - - +
+                              <------------------length------------------->
+
+      It is used by the server to distribute messages to the connected clients.
+      `name_length` is the length of the name, name is the name of the client
+      who sent the message and text is the message itself
+
+ - WELCOME (code 5)
+      This message is similar to MESSAGE_FROM but the name field is the name of
+      the room which the client is connected to and text is the name of the
+      admin of that room. This type of message is sent right after a client
+      connects to a server
+
+ - HELLO (code 6)
+      the message contains the version of the protocol and a bitset of the
+      capabilities of the sender:
+
+               +----+----+----+----+----+----+----+----+----+----+----+
+               |0x06|      length       | version |    capabilities   |
+               +----+----+----+----+----+----+----+----+----+----+----+
+                     MSB            LSB  MSB  LSB  MSB            LSB
+
+      Clients send it right after connecting, before the NAME message, and the
+      server responds with its own HELLO where the capabilities are only the
+      ones both sides support. Clients that start with NAME speak version 1.
+      The capabilities are:
+        1 = names and text are UTF-8
+        2 = HISTORY messages
+        4 = DIRECT_FROM and NO_SUCH_USER messages
+        8 = USER_LIST, USER_JOINED and USER_LEFT messages
+        16 = KICKED, BANNED and MUTED messages
+        32 = RENAMED messages
+        64 = TLS, right after the HELLO of the server both sides start a TLS
+             handshake and every message that follows is encrypted
+        128 = CHALLENGE and ANSWER messages
+        256 = AUTH_REQUIRED, AUTH, REGISTER and VERIFIED messages
+        512 = end-to-end encryption, it's offered only by the rooms where
+              every text must be encrypted by the clients (codes 26 to 32)
+        1024 = STAMPED, ACK and DELIVERED messages
+        2048 = EDIT and DELETE messages
+        4096 = files can be sent (codes 38 to 44)
+        8192 = the server hosts more rooms, with LIST_ROOMS, ROOMS and ROOM
+        16384 = the clients can resume their session with TOKEN and RESUME
+
+ - REJECTED (code 7)
+      the message contains one byte, the `reason` why the server can't serve
+      the client, then the server closes the connection:
+        1 = the protocol version is not supported
+        2 = the client sent a message that wasn't expected
+        3 = the name or the address of the client is banned from the room
+        4 = the answer to the CHALLENGE is wrong
+        5 = the password or the signature sent with AUTH is wrong
+        6 = the room is end-to-end encrypted and the client can't do it
+        7 = there is no room with the name sent with ROOM
+        8 = the token sent with RESUME is not known or it expired
+        9 = the connection is not encrypted, so the password of a registered
+            name or the token sent with RESUME can't be sent through it
+
+ - HISTORY (code 8)
+      the messages said in the room before the client joined, oldest first:
+
+               +----+----+----+----+----+----+----+----+----+ - - - - - - - +
+               |0x08|      length       |       count       |   messages    |
+               +----+----+----+----+----+----+----+----+----+ - - - - - - - +
+
+      `count` messages follow, each one made of the name of the sender (4
+      bytes length followed by the name), the time it was sent (8 bytes,
+      seconds since the UNIX epoch in UTC, MSB first) and the text (4 bytes
+      length followed by the text).
+      Then, for each message in the same order, its id (8 bytes, 0 if it has
+      none, MSB first) and one byte that is 1 if it was edited, so that it
+      can be edited or deleted like a STAMPED one. Older clients ignore them.
+      The server sends it right after WELCOME to the clients with the HISTORY
+      capability.
+
+ - DIRECT_TO (code 9)
+      the message is a private text for one participant, it is used by the
+      client to whisper to someone:
+
+          MSB            LSB  MSB            LSB  <--namelen-->
+    +----+----+----+----+----+----+----+----+----+ - - - - - - + - - - - - +
+    |0x09|      length       |     name_len      |  recipient  |   text    |
+    +----+----+----+----+----+----+----+----+----+ - - - - - - + - - - - - +
+
+      The recipient can also be the admin of the room.
+
+ - DIRECT_FROM (code 10)
+      same as MESSAGE_FROM but the text was sent only to the client who
+      receives it. Clients without the DIRECT capability receive a
+      MESSAGE_FROM where the name is followed by " (private)" instead.
+
+ - NO_SUCH_USER (code 11)
+      the message is the name of the recipient of a DIRECT_TO message that is
+      not in the room, it is sent back to the client who sent it.
+
+ - USER_JOINED (code 12)
+      the message is the name of a client that joined the room.
+
+ - USER_LEFT (code 13)
+      the message is the name of a client that left the room.
+
+ - USER_LIST (code 14)
+      the names of the clients in the room, the admin is not included:
+
+               +----+----+----+----+----+----+----+----+----+ - - - - - - - +
+               |0x0E|      length       |       count       |     names     |
+               +----+----+----+----+----+----+----+----+----+ - - - - - - - +
+
+      `count` names follow, each one preceded by its 4 bytes length.
+      The server sends it right after WELCOME to the clients with the USERS
+      capability, and then keeps them updated with USER_JOINED and USER_LEFT.
+      Clients without the capability are told with a MESSAGE_TO like
+      "User name connected!" instead.
+
+ - KICKED (code 15)
+      only the code and a zero length are sent. It tells to the client that
+      the admin removed him from the room, then the server closes the
+      connection.
+
+ - BANNED (code 16)
+      same as KICKED but the client can't join the room again: his name or his
+      address is banned. Clients that try to join are sent a REJECTED message
+      instead.
+
+ - MUTED (code 17)
+      the message contains for how many seconds (8 bytes, MSB first) the
+      client can't send messages, the server sends it when the admin mutes him
+      and as a reply to the messages he sends while muted. 0 means that he can
+      talk again and 2^64 - 1 that he is muted until the admin says otherwise.
+      Clients without the MODERATION capability receive a MESSAGE_TO that
+      explains what happened instead of these three messages.
+
+ - NICK (code 18)
+      the message is the new name that the client wants to use. If someone
+      else is already using it the server responds with a code 2 message.
+
+ - RENAMED (code 19)
+      same as MESSAGE_FROM but the name field is the old name of a client and
+      text is his new name. It is sent to all the clients, the one who asked
+      too. Clients without the RENAME capability receive a MESSAGE_TO like
+      "User old is now called new" instead.
+
+ - CHALLENGE (code 20)
+      the message is a random `nonce` (32 bytes). The server sends it instead
+      of WELCOME when the room has a password, the client must respond with
+      an ANSWER message.
+
+ - ANSWER (code 21)
+      the message is the HMAC-SHA256 of the nonce, using the password of the
+      room as the key (32 bytes), so that the password is never sent. If it's
+      right the server continues like after a NAME message, otherwise it
+      sends a REJECTED message. Clients without the PASSWORD capability are
+      rejected the same way when the room has a password.
+
+ - AUTH_REQUIRED (code 22)
+      the name asked by the client is registered in the room, so the server
+      sends this instead of WELCOME:
+
+               +----+----+----+----+----+----+ - - - - - - - +
+               |0x16|      length       |meth|     nonce     |
+               +----+----+----+----+----+----+ - - - - - - - +
+
+      `meth` tells how the name is protected: 1 = by a password, 2 = by the
+      public key of a client. `nonce` is random (32 bytes). The client must
+      respond with an AUTH message. Clients without the ACCOUNTS capability
+      are sent a code 2 message instead, as if the name was taken, and the
+      ones that didn't agree on TLS are sent a REJECTED message instead of
+      method 1.
+
+ - AUTH (code 23)
+      the message is `meth` (1 byte) followed by the proof that the name is
+      of the client: the password itself for method 1, so it's sent only on
+      encrypted connections, or the Ed25519 signature of the nonce
+      followed by the name for method 2. If it's right the server sends
+      WELCOME, otherwise it sends a REJECTED message.
+
+ - REGISTER (code 24)
+      the message is `meth` (1 byte) followed by the password (method 1) or
+      the Ed25519 public key (method 2, 32 bytes) that will protect the name
+      of the client from now on. Only clients that joined can send it, if
+      their name is already registered they must have used AUTH to join.
+      Method 1 is refused if the connection is not encrypted.
+      The server responds with VERIFIED, or with a MESSAGE_TO that explains
+      why it can't be done.
+
+ - VERIFIED (code 25)
+      the message is the name of a client that proved the name is his, with
+      AUTH or by registering it. The server sends it after USER_LIST and
+      USER_JOINED for every verified client, a USER_LIST is followed by all
+      of them again. A client that changes name or resumes its session is
+      not verified anymore.
+
+ In the rooms with end-to-end encryption the server never sees the text: the
+ clients share their X25519 public keys through the server, one of them makes
+ a random group key and sends it to every other client sealed with the key
+ they share (X25519 and HKDF-SHA256), and then the text is sealed with the
+ group key. The server asks for a new group key, with a new `epoch`, every
+ time someone joins or leaves. Everything sealed is made of a random nonce
+ (12 bytes) followed by the ChaCha20-Poly1305 ciphertext and its tag.
+ MESSAGE_TO and DIRECT_TO are refused in these rooms, only the admin, who
+ is not in the group, talks in plaintext.
+
+ - PUBLIC_KEY (code 26)
+      the message is the X25519 public key of the client (32 bytes), sent
+      right after WELCOME.
+
+ - MEMBER_KEY (code 27)
+      same as MESSAGE_FROM but the text is the public key of the client with
+      that name. The server sends one for every client in the room after
+      WELCOME and one to the others when a client sends PUBLIC_KEY.
+
+ - REKEY (code 28)
+      the message is a new `epoch` (4 bytes, MSB first). The server sends it
+      to one of the clients: it must make a new group key for that epoch and
+      send it to every client whose key it knows with GROUP_KEY_TO.
+
+ - GROUP_KEY_TO (code 29)
+      the message is the name of the recipient (4 bytes length followed by
+      the name), the epoch (4 bytes) and the sealed group key. The key used
+      to seal it is made with HKDF from the X25519 secret of the sender and
+      the recipient, using "chattest group key" as salt and the epoch as
+      info, the epoch is also the associated data.
+
+ - GROUP_KEY_FROM (code 30)
+      same as GROUP_KEY_TO but the name is the one of the sender, it's what
+      the server sends to the recipient.
+
+ - ENCRYPTED_TO (code 31)
+      the message is the epoch (4 bytes) followed by the text sealed with the
+      group key of that epoch, the name of the sender is the associated
+      data. The server sends it to the other clients as ENCRYPTED_FROM.
+
+ - ENCRYPTED_FROM (code 32)
+      the message is the name of the sender (4 bytes length followed by the
+      name), the epoch and the sealed text.
+
+ - STAMPED (code 33)
+      a MESSAGE_FROM, MESSAGE_TO, DIRECT_FROM, DIRECT_TO or ENCRYPTED_FROM
+      message with the `id` the server gave to it and the `time` it was sent,
+      any other message inside it is not valid:
+
+          MSB                                     LSB
+    +----+----+----+----+----+ - - - - - - - - - - +
+    |0x21|      length       |         id          |
+    +----+----+----+----+----+ - - - - - - - - - - +
+    + - - - - - - - - - - + - - - - - - - - - - - - +
+    |        time         |   the whole message    |
+    + - - - - - - - - - - + - - - - - - - - - - - - +
+     MSB              LSB
+
+      `id` (8 bytes) grows with every message of the room, `time` (8 bytes)
+      is in seconds since the UNIX epoch in UTC. The message follows with its
+      code and its length. The server sends it instead of the message to the
+      clients with the IDS capability, and it sends the texts of a client
+      back to him too, so that he knows they were relayed.
+
+ - ACK (code 34)
+      the message is the id (8 bytes) of a STAMPED message that the client
+      received, clients send it for every STAMPED message except the ones
+      with their own texts.
+
+ - DELIVERED (code 35)
+      the message is the id (8 bytes) of a text followed by the name of a
+      client who acknowledged it, the server sends it to the client who sent
+      the text.
+
+ - EDIT (code 36)
+      the message is the id (8 bytes) of a text followed by its new text.
+      The client sends it to change one of his texts, the server sends it to
+      tell the clients that a text was changed by its sender or by the
+      admin.
+
+ - DELETE (code 37)
+      the message is the id (8 bytes) of a text. The client sends it to
+      delete one of his texts, the server sends it to tell the clients that
+      a text was deleted by its sender or by the admin.
+
+ - FILE_OFFER (code 38)
+      the client offers a file: the message is the `offer` id chosen by the
+      client (4 bytes), the name of the recipient (4 bytes length followed by
+      the name, empty to offer it to everyone), the name of the file (same
+      format), its size (8 bytes) and its SHA-256 (32 bytes). The server
+      refuses the files bigger than its limit.
+
+ - FILE_OFFERED (code 39)
+      the server tells a client that a file is offered to him: the message
+      is the `transfer` id given by the server (8 bytes), the name of who
+      offers it and the name of the file (4 bytes length followed by the
+      name), its size (8 bytes) and its SHA-256 (32 bytes).
+
+ - FILE_ANSWER (code 40)
+      the client accepts (1) or refuses (0) a file: the message is the
+      `transfer` id (8 bytes) followed by one of those bytes.
+
+ - FILE_ANSWERED (code 41)
+      the server tells who offered a file the answer: the message is the
+      `offer` id (4 bytes), the `transfer` id (8 bytes), the answer (1 byte)
+      and the name of who answered.
+
+ - FILE_CHUNK (code 42)
+      a part of the file of the `transfer` (8 bytes id), of at most 16 KiB.
+      Who offered the file sends it once the file is accepted, the server
+      sends it to the recipient.
+
+ - FILE_RECEIVED (code 43)
+      the recipient tells how many bytes of the `transfer` (8 bytes id) he
+      received (8 bytes), after every FILE_CHUNK, the server sends it to who
+      offered the file. There can't be more than 4 FILE_CHUNK sent and not
+      received yet, so that the files don't slow down the other messages.
+      Once the whole file arrived the recipient checks its SHA-256 and
+      sends the last FILE_RECEIVED only if it's right.
+
+ - FILE_CANCEL (code 44)
+      the `transfer` (8 bytes id) is stopped, the rest of the message says
+      why. Both sides can send it, the server sends it to the other one.
+
+ A server can host more rooms, each one with its own admin, members and
+ history. The clients that don't pick one join the first room.
+
+ - LIST_ROOMS (code 45)
+      only the code and a zero length are sent. The client asks for the
+      rooms of the server, it can do it before joining one.
+
+ - ROOMS (code 46)
+      the rooms hosted by the server, it's the response to LIST_ROOMS:
+
+               +----+----+----+----+----+----+----+----+----+ - - - - - - - +
+               |0x2E|      length       |       count       |     rooms     |
+               +----+----+----+----+----+----+----+----+----+ - - - - - - - +
+
+      `count` rooms follow, each one made of its name (4 bytes length
+      followed by the name), how many clients joined it (4 bytes) and one
+      byte that is 1 if it has a password and 0 otherwise.
+
+ - ROOM (code 47)
+      the message is the name of the room the client wants to join, sent
+      after HELLO and before NAME. The server responds with a new HELLO with
+      the capabilities of that room (TLS is not started again), or with
+      REJECTED if there is no such room.
+
+ The servers on the same network can be found without knowing their address:
+ the clients send DISCOVER in a UDP datagram to the broadcast address, on the
+ port used by the servers (7357 by default), and every server responds to
+ the address it came from with an ANNOUNCE datagram for each of its rooms.
+ Every datagram is one whole message.
+
+ - DISCOVER (code 48)
+      only the code and a zero length are sent.
+
+ - ANNOUNCE (code 49)
+      the message is the TCP `port` of the server (2 bytes, MSB first), the
+      name of a room (4 bytes length followed by the name) and the name of
+      its admin.
+
+ A client that loses the connection can come back as if it never left, if it
+ does it before the server gives its name to someone else.
+
+ - TOKEN (code 50)
+      the message is for how many seconds (4 bytes, MSB first) the name of
+      the client stays reserved once the connection is lost, followed by a
+      random `token` (32 bytes). The server sends it after WELCOME to the
+      clients with the RESUME capability, or only to the ones that use TLS
+      if the server does. A new token is sent when the session is resumed,
+      the old one can't be used anymore.
+
+ - RESUME (code 51)
+      the message is the id of the last STAMPED message received (8 bytes)
+      followed by the token, sent after HELLO (and ROOM) instead of NAME.
+      The others don't see the client leave and come back: the server
+      responds like to NAME, without asking for the password or the proof of
+      a registered name (the name is not verified anymore), and the HISTORY
+      contains only the messages with a bigger id. If the token is not known
+      the server sends REJECTED, and it does the same if it uses TLS and the
+      connection is not encrypted.
+
+ Every message, even the ones with no contents, has the 4 bytes `length` right
+ after the `code`, so that a receiver always knows where the next message starts.
+
+ Names and text are encoded in UTF-8 and every length counts bytes, not
+ characters. A message with text that is not valid UTF-8 is rejected.
+
+ Newer versions of the protocol may append fields at the end of a message,
+ the bytes after the known fields are ignored.
+*/
+
+use crate::history::{Change, Entry};
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv6Addr, TcpStream};
+
+/// Port used by the Chattest protocol
+pub const PORT: u16 = 7357;
+
+/// Splits an address like `host`, `host:port`, `::1` or `[::1]:port` in the
+/// host and the port, `default_port` is used if it has none. The host can
+/// be a name, it's resolved when connecting
+pub fn parse_addr(addr: &str, default_port: u16) -> io::Result<(String, u16)> {
+    let addr = addr.trim();
+    let invalid = || {
+        let message = match addr {
+            "" => "Chattest address error: the address is empty!".to_string(),
+            addr => format!("Chattest address error: {} is not a valid address!", addr),
+        };
+        io::Error::new(ErrorKind::InvalidInput, message)
+    };
+    let (host, port) = match addr.strip_prefix('[') {
+        // An IPv6 address between brackets, maybe followed by the port
+        Some(rest) => {
+            let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+            host.parse::<Ipv6Addr>().map_err(|_| invalid())?;
+            match rest {
+                "" => (host, None),
+                rest => (host, Some(rest.strip_prefix(':').ok_or_else(invalid)?)),
+            }
+        }
+        None => match addr.rsplit_once(':') {
+            // With more colons and no brackets it's an IPv6 address alone
+            Some((host, _)) if host.contains(':') => (addr, None),
+            Some((host, port)) => (host, Some(port)),
+            None => (addr, None),
+        },
+    };
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    let port = match port {
+        Some(port) => port.parse().map_err(|_| invalid())?,
+        None => default_port,
+    };
+    Ok((host.to_string(), port))
+}
+
+/// Divide a `u32` into 4 parts (one byte each, MSB first)
+fn uint_to_bytes(val: u32) -> [u8; 4] {
+    // (BIG ENDIAN)
+    [
+        (val >> 24) as u8,
+        (val >> 16) as u8,
+        (val >> 8) as u8,
+        val as u8,
+    ]
+}
+/// Make a `u32` from 4 bytes (MSB first)
+fn bytes_to_uint(val: [u8; 4]) -> u32 {
+    // (BIG ENDIAN)
+    ((val[0] as u32) << 24) + ((val[1] as u32) << 16) + ((val[2] as u32) << 8) + val[3] as u32
+}
+
+/// Divide a `u64` into 8 parts (one byte each, MSB first)
+fn long_to_bytes(val: u64) -> [u8; 8] {
+    // (BIG ENDIAN)
+    val.to_be_bytes()
+}
+/// Make a `u64` from 8 bytes (MSB first)
+fn bytes_to_long(val: [u8; 8]) -> u64 {
+    // (BIG ENDIAN)
+    u64::from_be_bytes(val)
+}
+
+/// Divide a `u16` into 2 parts (one byte each, MSB first)
+fn short_to_bytes(val: u16) -> [u8; 2] {
+    // (BIG ENDIAN)
+    [(val >> 8) as u8, val as u8]
+}
+/// Make a `u16` from 2 bytes (MSB first)
+fn bytes_to_short(val: [u8; 2]) -> u16 {
+    // (BIG ENDIAN)
+    ((val[0] as u16) << 8) + val[1] as u16
+}
+
+/// Version of the protocol implemented here, clients that start with NAME
+/// instead of HELLO speak version 1
+pub const VERSION: u16 = 2;
+/// Oldest version of the protocol that is still served
+pub const MIN_VERSION: u16 = 1;
+
+/// Capability: names and text are UTF-8
+pub const CAP_UTF8: u32 = 1;
+/// Capability: the server sends the HISTORY of the room after WELCOME
+pub const CAP_HISTORY: u32 = 2;
+/// Capability: private messages with DIRECT_FROM and NO_SUCH_USER
+pub const CAP_DIRECT: u32 = 4;
+/// Capability: the list of the users with USER_LIST, USER_JOINED and USER_LEFT
+pub const CAP_USERS: u32 = 8;
+/// Capability: KICKED, BANNED and MUTED messages
+pub const CAP_MODERATION: u32 = 16;
+/// Capability: RENAMED messages
+pub const CAP_RENAME: u32 = 32;
+/// Capability: the connection is encrypted with TLS after the HELLO messages,
+/// it's offered only by the servers that have a certificate
+pub const CAP_TLS: u32 = 64;
+/// Capability: rooms with a password, with CHALLENGE and ANSWER
+pub const CAP_PASSWORD: u32 = 128;
+/// Capability: registered names, with AUTH_REQUIRED, AUTH, REGISTER and
+/// VERIFIED
+pub const CAP_ACCOUNTS: u32 = 256;
+/// Capability: the room is end-to-end encrypted, it's offered only by the
+/// rooms that want it
+pub const CAP_E2E: u32 = 512;
+/// Capability: the texts have an id and a time, with STAMPED, ACK and
+/// DELIVERED
+pub const CAP_IDS: u32 = 1024;
+/// Capability: the texts can be changed or deleted with EDIT and DELETE
+pub const CAP_EDIT: u32 = 2048;
+/// Capability: the clients can send files to each other
+pub const CAP_FILES: u32 = 4096;
+/// Capability: the server hosts more rooms, with LIST_ROOMS, ROOMS and
+/// ROOM, it's offered only by the servers that can do it
+pub const CAP_ROOMS: u32 = 8192;
+/// Capability: the session can be resumed after losing the connection, with
+/// TOKEN and RESUME
+pub const CAP_RESUME: u32 = 16384;
+/// Capabilities supported by this implementation
+pub const CAPABILITIES: u32 = CAP_UTF8
+    | CAP_HISTORY
+    | CAP_DIRECT
+    | CAP_USERS
+    | CAP_MODERATION
+    | CAP_RENAME
+    | CAP_PASSWORD
+    | CAP_ACCOUNTS
+    | CAP_IDS
+    | CAP_EDIT
+    | CAP_FILES
+    | CAP_RESUME;
+
+/// Method of AUTH and REGISTER: the name is protected by a password
+pub const AUTH_PASSWORD: u8 = 1;
+/// Method of AUTH and REGISTER: the name is protected by an Ed25519 key
+pub const AUTH_KEY: u8 = 2;
+
+/// The biggest part of a file in a FILE_CHUNK message
+pub const CHUNK_SIZE: usize = 16 * 1024;
+/// How many FILE_CHUNK messages can be sent before they are received
+pub const FILE_WINDOW: u64 = 4;
+
+/// Seconds in a MUTED message for a client muted until the admin says otherwise
+pub const MUTED_FOREVER: u64 = u64::MAX;
+
+/// Message `Code` used by the Chattest protocol
+#[derive(PartialEq, Debug)]
+pub enum Code {
+    /// Name(name)
+    Name(String),
+    /// AlreadyHere
+    AlreadyHere,
+    /// MessageTo(text)
+    MessageTo(String),
+    /// MessageFrom(name, text)
+    MessageFrom(String, String),
+    /// Welcome(room, admin)
+    Welcome(String, String),
+    /// Hello(version, capabilities)
+    Hello(u16, u32),
+    /// Rejected(reason)
+    Rejected(Reason),
+    /// History(messages)
+    History(Vec<Entry>),
+    /// DirectTo(recipient, text)
+    DirectTo(String, String),
+    /// DirectFrom(name, text)
+    DirectFrom(String, String),
+    /// NoSuchUser(name)
+    NoSuchUser(String),
+    /// UserJoined(name)
+    UserJoined(String),
+    /// UserLeft(name)
+    UserLeft(String),
+    /// UserList(names)
+    UserList(Vec<String>),
+    /// Kicked
+    Kicked,
+    /// Banned
+    Banned,
+    /// Muted(seconds)
+    Muted(u64),
+    /// Nick(name)
+    Nick(String),
+    /// Renamed(old, new)
+    Renamed(String, String),
+    /// Challenge(nonce)
+    Challenge(Vec<u8>),
+    /// Answer(proof)
+    Answer(Vec<u8>),
+    /// AuthRequired(method, nonce)
+    AuthRequired(u8, Vec<u8>),
+    /// Auth(method, proof)
+    Auth(u8, Vec<u8>),
+    /// Register(method, secret)
+    Register(u8, Vec<u8>),
+    /// Verified(name)
+    Verified(String),
+    /// PublicKey(key)
+    PublicKey(Vec<u8>),
+    /// MemberKey(name, key)
+    MemberKey(String, Vec<u8>),
+    /// Rekey(epoch)
+    Rekey(u32),
+    /// GroupKeyTo(recipient, epoch, sealed)
+    GroupKeyTo(String, u32, Vec<u8>),
+    /// GroupKeyFrom(name, epoch, sealed)
+    GroupKeyFrom(String, u32, Vec<u8>),
+    /// EncryptedTo(epoch, sealed)
+    EncryptedTo(u32, Vec<u8>),
+    /// EncryptedFrom(name, epoch, sealed)
+    EncryptedFrom(String, u32, Vec<u8>),
+    /// Stamped(id, time, message)
+    Stamped(u64, u64, Box<Code>),
+    /// Ack(id)
+    Ack(u64),
+    /// Delivered(id, name)
+    Delivered(u64, String),
+    /// Edit(id, text)
+    Edit(u64, String),
+    /// Delete(id)
+    Delete(u64),
+    /// FileOffer(offer, to, file name, size, sha256)
+    FileOffer(u32, String, String, u64, Vec<u8>),
+    /// FileOffered(transfer, from, file name, size, sha256)
+    FileOffered(u64, String, String, u64, Vec<u8>),
+    /// FileAnswer(transfer, accepted)
+    FileAnswer(u64, bool),
+    /// FileAnswered(offer, transfer, accepted, name)
+    FileAnswered(u32, u64, bool, String),
+    /// FileChunk(transfer, data)
+    FileChunk(u64, Vec<u8>),
+    /// FileReceived(transfer, bytes)
+    FileReceived(u64, u64),
+    /// FileCancel(transfer, reason)
+    FileCancel(u64, String),
+    /// ListRooms
+    ListRooms,
+    /// Rooms(rooms)
+    Rooms(Vec<RoomInfo>),
+    /// Room(name)
+    Room(String),
+    /// Discover
+    Discover,
+    /// Announce(port, room, admin)
+    Announce(u16, String, String),
+    /// Token(seconds, token)
+    Token(u32, Vec<u8>),
+    /// Resume(last id, token)
+    Resume(u64, Vec<u8>),
+}
+
+const NAME: u8 = 1;
+const ALREADY_HERE: u8 = 2;
+const MESSAGE_TO: u8 = 3;
+const MESSAGE_FROM: u8 = 4;
+const WELCOME: u8 = 5;
+const HELLO: u8 = 6;
+const REJECTED: u8 = 7;
+const HISTORY: u8 = 8;
+const DIRECT_TO: u8 = 9;
+const DIRECT_FROM: u8 = 10;
+const NO_SUCH_USER: u8 = 11;
+const USER_JOINED: u8 = 12;
+const USER_LEFT: u8 = 13;
+const USER_LIST: u8 = 14;
+const KICKED: u8 = 15;
+const BANNED: u8 = 16;
+const MUTED: u8 = 17;
+const NICK: u8 = 18;
+const RENAMED: u8 = 19;
+const CHALLENGE: u8 = 20;
+const ANSWER: u8 = 21;
+const AUTH_REQUIRED: u8 = 22;
+const AUTH: u8 = 23;
+const REGISTER: u8 = 24;
+const VERIFIED: u8 = 25;
+const PUBLIC_KEY: u8 = 26;
+const MEMBER_KEY: u8 = 27;
+const REKEY: u8 = 28;
+const GROUP_KEY_TO: u8 = 29;
+const GROUP_KEY_FROM: u8 = 30;
+const ENCRYPTED_TO: u8 = 31;
+const ENCRYPTED_FROM: u8 = 32;
+const STAMPED: u8 = 33;
+const ACK: u8 = 34;
+const DELIVERED: u8 = 35;
+const EDIT: u8 = 36;
+const DELETE: u8 = 37;
+const FILE_OFFER: u8 = 38;
+const FILE_OFFERED: u8 = 39;
+const FILE_ANSWER: u8 = 40;
+const FILE_ANSWERED: u8 = 41;
+const FILE_CHUNK: u8 = 42;
+const FILE_RECEIVED: u8 = 43;
+const FILE_CANCEL: u8 = 44;
+const LIST_ROOMS: u8 = 45;
+const ROOMS: u8 = 46;
+const ROOM: u8 = 47;
+const DISCOVER: u8 = 48;
+const ANNOUNCE: u8 = 49;
+const TOKEN: u8 = 50;
+const RESUME: u8 = 51;
+
+/// A room hosted by a server, as told by a ROOMS message
+#[derive(PartialEq, Debug, Clone)]
+pub struct RoomInfo {
+    pub name: String,
+    /// How many clients joined it
+    pub members: u32,
+    /// Whether the clients must know a password to join it
+    pub password: bool,
+}
+
+/// Why the server refused a client, sent in a REJECTED message
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Reason {
+    /// The protocol version of the client is not served
+    UnsupportedVersion,
+    /// The client sent a code that wasn't expected at that moment
+    UnexpectedCode,
+    /// The name or the address of the client is banned
+    Banned,
+    /// The client doesn't know the password of the room
+    WrongPassword,
+    /// The client couldn't prove that the registered name is his
+    AuthFailed,
+    /// The room is end-to-end encrypted and the client can't do it
+    EncryptionRequired,
+    /// There is no room with the name asked by the client
+    NoSuchRoom,
+    /// The session the client wants to resume is not known anymore
+    SessionExpired,
+    /// The connection is not encrypted, so the password of a registered name
+    /// or the token to resume a session can't be sent
+    TlsRequired,
+    /// A reason this version of the protocol doesn't know
+    Other(u8),
+}
+
+impl Reason {
+    fn from_byte(val: u8) -> Self {
+        match val {
+            1 => Reason::UnsupportedVersion,
+            2 => Reason::UnexpectedCode,
+            3 => Reason::Banned,
+            4 => Reason::WrongPassword,
+            5 => Reason::AuthFailed,
+            6 => Reason::EncryptionRequired,
+            7 => Reason::NoSuchRoom,
+            8 => Reason::SessionExpired,
+            9 => Reason::TlsRequired,
+            val => Reason::Other(val),
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            Reason::UnsupportedVersion => 1,
+            Reason::UnexpectedCode => 2,
+            Reason::Banned => 3,
+            Reason::WrongPassword => 4,
+            Reason::AuthFailed => 5,
+            Reason::EncryptionRequired => 6,
+            Reason::NoSuchRoom => 7,
+            Reason::SessionExpired => 8,
+            Reason::TlsRequired => 9,
+            Reason::Other(val) => val,
+        }
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reason::UnsupportedVersion => write!(f, "protocol version not supported"),
+            Reason::UnexpectedCode => write!(f, "unexpected message"),
+            Reason::Banned => write!(f, "you are banned from this room"),
+            Reason::WrongPassword => write!(f, "wrong password"),
+            Reason::AuthFailed => write!(f, "the name is registered by someone else"),
+            Reason::EncryptionRequired => write!(f, "the room is end-to-end encrypted"),
+            Reason::NoSuchRoom => write!(f, "there is no such room"),
+            Reason::SessionExpired => write!(f, "the session expired"),
+            Reason::TlsRequired => write!(f, "the password or the token can't be sent without TLS"),
+            Reason::Other(val) => write!(f, "error code {}", val),
+        }
+    }
+}
+
+/// Size of the nonce of a CHALLENGE message
+pub const NONCE_LEN: usize = 32;
+
+/// A new random nonce for a CHALLENGE message
+pub fn nonce() -> Vec<u8> {
+    let mut nonce = vec![0u8; NONCE_LEN];
+    ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut nonce)
+        .expect("the system has random numbers");
+    nonce
+}
+
+/// Answer to the CHALLENGE `nonce` of a room with `password`
+pub fn prove(password: &str, nonce: &[u8]) -> Vec<u8> {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, password.as_bytes());
+    ring::hmac::sign(&key, nonce).as_ref().to_vec()
+}
+
+/// Checks the answer to a CHALLENGE, it takes the same time when it's wrong
+pub fn verify(password: &str, nonce: &[u8], proof: &[u8]) -> bool {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, password.as_bytes());
+    ring::hmac::verify(&key, nonce, proof).is_ok()
+}
+
+/// Size of the `code` plus the `length` that start every message
+const HEADER: usize = 5;
+/// The longest contents of a message, the bigger ones are not collected
+pub const MAX_FRAME: usize = 16 * 1024 * 1024;
+
+/// Appends a `u16` to the contents of a message
+fn put_short(payload: &mut Vec<u8>, val: u16) {
+    payload.extend_from_slice(&short_to_bytes(val));
+}
+/// Appends a `u32` to the contents of a message
+fn put_uint(payload: &mut Vec<u8>, val: u32) {
+    payload.extend_from_slice(&uint_to_bytes(val));
+}
+/// Appends a `u64` to the contents of a message
+fn put_long(payload: &mut Vec<u8>, val: u64) {
+    payload.extend_from_slice(&long_to_bytes(val));
+}
+/// Appends a string preceded by its length to the contents of a message
+fn put_string(payload: &mut Vec<u8>, val: &str) {
+    put_uint(payload, val.len() as u32);
+    payload.extend_from_slice(val.as_bytes());
+}
+
+/// Appends to `buf` the bytes of `message` as they are sent on the stream
+pub fn encode(message: &Code, buf: &mut Vec<u8>) {
+    let mut payload = Vec::new();
+    let code = match message {
+        Code::Name(name) => {
+            payload.extend_from_slice(name.as_bytes());
+            NAME
+        }
+        Code::AlreadyHere => ALREADY_HERE,
+        Code::MessageTo(text) => {
+            payload.extend_from_slice(text.as_bytes());
+            MESSAGE_TO
+        }
+        Code::MessageFrom(name, text) => {
+            put_string(&mut payload, name);
+            payload.extend_from_slice(text.as_bytes());
+            MESSAGE_FROM
+        }
+        Code::Welcome(room, admin) => {
+            put_string(&mut payload, room);
+            payload.extend_from_slice(admin.as_bytes());
+            WELCOME
+        }
+        Code::Hello(version, capabilities) => {
+            put_short(&mut payload, *version);
+            put_uint(&mut payload, *capabilities);
+            HELLO
+        }
+        Code::Rejected(reason) => {
+            payload.push(reason.to_byte());
+            REJECTED
+        }
+        Code::History(entries) => {
+            put_uint(&mut payload, entries.len() as u32);
+            for entry in entries {
+                put_string(&mut payload, &entry.sender);
+                put_long(&mut payload, entry.time);
+                put_string(&mut payload, &entry.text);
+            }
+            for entry in entries {
+                put_long(&mut payload, entry.id);
+                payload.push((entry.change == Change::Edited) as u8);
+            }
+            HISTORY
+        }
+        Code::DirectTo(name, text) => {
+            put_string(&mut payload, name);
+            payload.extend_from_slice(text.as_bytes());
+            DIRECT_TO
+        }
+        Code::DirectFrom(name, text) => {
+            put_string(&mut payload, name);
+            payload.extend_from_slice(text.as_bytes());
+            DIRECT_FROM
+        }
+        Code::NoSuchUser(name) => {
+            payload.extend_from_slice(name.as_bytes());
+            NO_SUCH_USER
+        }
+        Code::UserJoined(name) => {
+            payload.extend_from_slice(name.as_bytes());
+            USER_JOINED
+        }
+        Code::UserLeft(name) => {
+            payload.extend_from_slice(name.as_bytes());
+            USER_LEFT
+        }
+        Code::UserList(names) => {
+            put_uint(&mut payload, names.len() as u32);
+            for name in names {
+                put_string(&mut payload, name);
+            }
+            USER_LIST
+        }
+        Code::Kicked => KICKED,
+        Code::Banned => BANNED,
+        Code::Muted(seconds) => {
+            put_long(&mut payload, *seconds);
+            MUTED
+        }
+        Code::Nick(name) => {
+            payload.extend_from_slice(name.as_bytes());
+            NICK
+        }
+        Code::Renamed(old, new) => {
+            put_string(&mut payload, old);
+            payload.extend_from_slice(new.as_bytes());
+            RENAMED
+        }
+        Code::Challenge(nonce) => {
+            payload.extend_from_slice(nonce);
+            CHALLENGE
+        }
+        Code::Answer(proof) => {
+            payload.extend_from_slice(proof);
+            ANSWER
+        }
+        Code::AuthRequired(method, nonce) => {
+            payload.push(*method);
+            payload.extend_from_slice(nonce);
+            AUTH_REQUIRED
+        }
+        Code::Auth(method, proof) => {
+            payload.push(*method);
+            payload.extend_from_slice(proof);
+            AUTH
+        }
+        Code::Register(method, secret) => {
+            payload.push(*method);
+            payload.extend_from_slice(secret);
+            REGISTER
+        }
+        Code::Verified(name) => {
+            payload.extend_from_slice(name.as_bytes());
+            VERIFIED
+        }
+        Code::PublicKey(key) => {
+            payload.extend_from_slice(key);
+            PUBLIC_KEY
+        }
+        Code::MemberKey(name, key) => {
+            put_string(&mut payload, name);
+            payload.extend_from_slice(key);
+            MEMBER_KEY
+        }
+        Code::Rekey(epoch) => {
+            put_uint(&mut payload, *epoch);
+            REKEY
+        }
+        Code::GroupKeyTo(name, epoch, sealed) => {
+            put_string(&mut payload, name);
+            put_uint(&mut payload, *epoch);
+            payload.extend_from_slice(sealed);
+            GROUP_KEY_TO
+        }
+        Code::GroupKeyFrom(name, epoch, sealed) => {
+            put_string(&mut payload, name);
+            put_uint(&mut payload, *epoch);
+            payload.extend_from_slice(sealed);
+            GROUP_KEY_FROM
+        }
+        Code::EncryptedTo(epoch, sealed) => {
+            put_uint(&mut payload, *epoch);
+            payload.extend_from_slice(sealed);
+            ENCRYPTED_TO
+        }
+        Code::EncryptedFrom(name, epoch, sealed) => {
+            put_string(&mut payload, name);
+            put_uint(&mut payload, *epoch);
+            payload.extend_from_slice(sealed);
+            ENCRYPTED_FROM
+        }
+        Code::Stamped(id, time, message) => {
+            put_long(&mut payload, *id);
+            put_long(&mut payload, *time);
+            encode(message, &mut payload);
+            STAMPED
+        }
+        Code::Ack(id) => {
+            put_long(&mut payload, *id);
+            ACK
+        }
+        Code::Delivered(id, name) => {
+            put_long(&mut payload, *id);
+            payload.extend_from_slice(name.as_bytes());
+            DELIVERED
+        }
+        Code::Edit(id, text) => {
+            put_long(&mut payload, *id);
+            payload.extend_from_slice(text.as_bytes());
+            EDIT
+        }
+        Code::Delete(id) => {
+            put_long(&mut payload, *id);
+            DELETE
+        }
+        Code::FileOffer(offer, to, file, size, sha256) => {
+            put_uint(&mut payload, *offer);
+            put_string(&mut payload, to);
+            put_string(&mut payload, file);
+            put_long(&mut payload, *size);
+            payload.extend_from_slice(sha256);
+            FILE_OFFER
+        }
+        Code::FileOffered(transfer, from, file, size, sha256) => {
+            put_long(&mut payload, *transfer);
+            put_string(&mut payload, from);
+            put_string(&mut payload, file);
+            put_long(&mut payload, *size);
+            payload.extend_from_slice(sha256);
+            FILE_OFFERED
+        }
+        Code::FileAnswer(transfer, accepted) => {
+            put_long(&mut payload, *transfer);
+            payload.push(*accepted as u8);
+            FILE_ANSWER
+        }
+        Code::FileAnswered(offer, transfer, accepted, name) => {
+            put_uint(&mut payload, *offer);
+            put_long(&mut payload, *transfer);
+            payload.push(*accepted as u8);
+            payload.extend_from_slice(name.as_bytes());
+            FILE_ANSWERED
+        }
+        Code::FileChunk(transfer, data) => {
+            put_long(&mut payload, *transfer);
+            payload.extend_from_slice(data);
+            FILE_CHUNK
+        }
+        Code::FileReceived(transfer, bytes) => {
+            put_long(&mut payload, *transfer);
+            put_long(&mut payload, *bytes);
+            FILE_RECEIVED
+        }
+        Code::FileCancel(transfer, reason) => {
+            put_long(&mut payload, *transfer);
+            payload.extend_from_slice(reason.as_bytes());
+            FILE_CANCEL
+        }
+        Code::ListRooms => LIST_ROOMS,
+        Code::Rooms(rooms) => {
+            put_uint(&mut payload, rooms.len() as u32);
+            for room in rooms {
+                put_string(&mut payload, &room.name);
+                put_uint(&mut payload, room.members);
+                payload.push(room.password as u8);
+            }
+            ROOMS
+        }
+        Code::Room(name) => {
+            payload.extend_from_slice(name.as_bytes());
+            ROOM
+        }
+        Code::Discover => DISCOVER,
+        Code::Announce(port, room, admin) => {
+            put_short(&mut payload, *port);
+            put_string(&mut payload, room);
+            payload.extend_from_slice(admin.as_bytes());
+            ANNOUNCE
+        }
+        Code::Token(seconds, token) => {
+            put_uint(&mut payload, *seconds);
+            payload.extend_from_slice(token);
+            TOKEN
+        }
+        Code::Resume(last_id, token) => {
+            put_long(&mut payload, *last_id);
+            payload.extend_from_slice(token);
+            RESUME
+        }
+    };
+    buf.push(code);
+    buf.extend_from_slice(&uint_to_bytes(payload.len() as u32));
+    buf.extend_from_slice(&payload);
+}
+
+/// Trasforms the bytes into a string, they must be valid UTF-8
+fn chars(bytes: &[u8]) -> io::Result<String> {
+    String::from_utf8(bytes.to_vec()).map_err(|error| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Chattest stream error: text is not valid UTF-8 ({})!",
+                error
+            ),
+        )
+    })
+}
+
+/// Reads the fields of the contents of a message one after the other
+struct Fields<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    /// Takes the next `len` bytes
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        // A field can't exceed the size of the entire message
+        if len > self.bytes.len() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Chattest stream error: Length recived is not correct!",
+            ));
+        }
+        let (field, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(field)
+    }
+    fn byte(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+    fn short(&mut self) -> io::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(bytes_to_short([bytes[0], bytes[1]]))
+    }
+    fn uint(&mut self) -> io::Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(bytes_to_uint([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+    fn long(&mut self) -> io::Result<u64> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(bytes_to_long(bytes))
+    }
+    /// Reads a string preceded by its length
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.uint()? as usize;
+        chars(self.take(len)?)
+    }
+    /// Reads all the remaining bytes as a string
+    fn rest(self) -> io::Result<String> {
+        chars(self.bytes)
+    }
+}
+
+/// Length of the first message in `bytes`, if its header already arrived.
+/// It's an error if the message is bigger than `MAX_FRAME`, so that nobody
+/// has to collect it
+pub fn frame_len(bytes: &[u8]) -> io::Result<Option<usize>> {
+    if bytes.len() < HEADER {
+        return Ok(None);
+    }
+    let length = bytes_to_uint([bytes[1], bytes[2], bytes[3], bytes[4]]) as usize;
+    if length > MAX_FRAME {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Chattest stream error: message of {} bytes is too big!",
+                length
+            ),
+        ));
+    }
+    Ok(Some(HEADER + length))
+}
+
+/// Decodes a whole message, `frame` must be as long as `frame_len` says
+pub fn decode(frame: &[u8]) -> io::Result<Code> {
+    // The bytes could come from anywhere, like a UDP datagram
+    if frame_len(frame)? != Some(frame.len()) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "Chattest stream error: Length recived is not correct!",
+        ));
+    }
+    let mut fields = Fields {
+        bytes: &frame[HEADER..],
+    };
+    let code = match frame[0] {
+        // Code Name(name) is code 1
+        NAME => Code::Name(fields.rest()?),
+        // AlreadyHere is code 2
+        ALREADY_HERE => Code::AlreadyHere,
+        // MessageTo(message) is code 3
+        MESSAGE_TO => Code::MessageTo(fields.rest()?),
+        // MessageFrom(name, message) is code 4
+        MESSAGE_FROM => Code::MessageFrom(fields.string()?, fields.rest()?),
+        // Welcome(room, admin) is code 5
+        WELCOME => Code::Welcome(fields.string()?, fields.rest()?),
+        // Hello(version, capabilities) is code 6
+        HELLO => Code::Hello(fields.short()?, fields.uint()?),
+        // Rejected(reason) is code 7
+        REJECTED => Code::Rejected(Reason::from_byte(fields.byte()?)),
+        // History(messages) is code 8
+        HISTORY => {
+            let count = fields.uint()?;
+            let mut entries = Vec::new();
+            for _ in 0..count {
+                entries.push(Entry {
+                    id: 0,
+                    sender: fields.string()?,
+                    time: fields.long()?,
+                    text: fields.string()?,
+                    change: Change::Sent,
+                });
+            }
+            // Older servers don't send the ids
+            if !fields.bytes.is_empty() {
+                for entry in &mut entries {
+                    entry.id = fields.long()?;
+                    if fields.byte()? == 1 {
+                        entry.change = Change::Edited;
+                    }
+                }
+            }
+            Code::History(entries)
+        }
+        // DirectTo(recipient, text) is code 9
+        DIRECT_TO => Code::DirectTo(fields.string()?, fields.rest()?),
+        // DirectFrom(name, text) is code 10
+        DIRECT_FROM => Code::DirectFrom(fields.string()?, fields.rest()?),
+        // NoSuchUser(name) is code 11
+        NO_SUCH_USER => Code::NoSuchUser(fields.rest()?),
+        // UserJoined(name) is code 12
+        USER_JOINED => Code::UserJoined(fields.rest()?),
+        // UserLeft(name) is code 13
+        USER_LEFT => Code::UserLeft(fields.rest()?),
+        // UserList(names) is code 14
+        USER_LIST => {
+            let count = fields.uint()?;
+            let mut names = Vec::new();
+            for _ in 0..count {
+                names.push(fields.string()?);
+            }
+            Code::UserList(names)
+        }
+        // Kicked is code 15
+        KICKED => Code::Kicked,
+        // Banned is code 16
+        BANNED => Code::Banned,
+        // Muted(seconds) is code 17
+        MUTED => Code::Muted(fields.long()?),
+        // Nick(name) is code 18
+        NICK => Code::Nick(fields.rest()?),
+        // Renamed(old, new) is code 19
+        RENAMED => Code::Renamed(fields.string()?, fields.rest()?),
+        // Challenge(nonce) is code 20
+        CHALLENGE => Code::Challenge(fields.bytes.to_vec()),
+        // Answer(proof) is code 21
+        ANSWER => Code::Answer(fields.bytes.to_vec()),
+        // AuthRequired(method, nonce) is code 22
+        AUTH_REQUIRED => Code::AuthRequired(fields.byte()?, fields.bytes.to_vec()),
+        // Auth(method, proof) is code 23
+        AUTH => Code::Auth(fields.byte()?, fields.bytes.to_vec()),
+        // Register(method, secret) is code 24
+        REGISTER => Code::Register(fields.byte()?, fields.bytes.to_vec()),
+        // Verified(name) is code 25
+        VERIFIED => Code::Verified(fields.rest()?),
+        // PublicKey(key) is code 26
+        PUBLIC_KEY => Code::PublicKey(fields.bytes.to_vec()),
+        // MemberKey(name, key) is code 27
+        MEMBER_KEY => Code::MemberKey(fields.string()?, fields.bytes.to_vec()),
+        // Rekey(epoch) is code 28
+        REKEY => Code::Rekey(fields.uint()?),
+        // GroupKeyTo(recipient, epoch, sealed) is code 29
+        GROUP_KEY_TO => Code::GroupKeyTo(fields.string()?, fields.uint()?, fields.bytes.to_vec()),
+        // GroupKeyFrom(name, epoch, sealed) is code 30
+        GROUP_KEY_FROM => {
+            Code::GroupKeyFrom(fields.string()?, fields.uint()?, fields.bytes.to_vec())
+        }
+        // EncryptedTo(epoch, sealed) is code 31
+        ENCRYPTED_TO => Code::EncryptedTo(fields.uint()?, fields.bytes.to_vec()),
+        // EncryptedFrom(name, epoch, sealed) is code 32
+        ENCRYPTED_FROM => {
+            Code::EncryptedFrom(fields.string()?, fields.uint()?, fields.bytes.to_vec())
+        }
+        // Stamped(id, time, message) is code 33
+        STAMPED => {
+            let (id, time) = (fields.long()?, fields.long()?);
+            // Only a text can be inside, so it can't be nested
+            match fields.bytes.first() {
+                Some(&(MESSAGE_TO | MESSAGE_FROM | DIRECT_TO | DIRECT_FROM | ENCRYPTED_FROM)) => {
+                    Code::Stamped(id, time, Box::new(decode(fields.bytes)?))
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "Chattest stream error: stamped message not valid!",
+                    ))
+                }
+            }
+        }
+        // Ack(id) is code 34
+        ACK => Code::Ack(fields.long()?),
+        // Delivered(id, name) is code 35
+        DELIVERED => Code::Delivered(fields.long()?, fields.rest()?),
+        // Edit(id, text) is code 36
+        EDIT => Code::Edit(fields.long()?, fields.rest()?),
+        // Delete(id) is code 37
+        DELETE => Code::Delete(fields.long()?),
+        // FileOffer(offer, to, file name, size, sha256) is code 38
+        FILE_OFFER => Code::FileOffer(
+            fields.uint()?,
+            fields.string()?,
+            fields.string()?,
+            fields.long()?,
+            fields.bytes.to_vec(),
+        ),
+        // FileOffered(transfer, from, file name, size, sha256) is code 39
+        FILE_OFFERED => Code::FileOffered(
+            fields.long()?,
+            fields.string()?,
+            fields.string()?,
+            fields.long()?,
+            fields.bytes.to_vec(),
+        ),
+        // FileAnswer(transfer, accepted) is code 40
+        FILE_ANSWER => Code::FileAnswer(fields.long()?, fields.byte()? != 0),
+        // FileAnswered(offer, transfer, accepted, name) is code 41
+        FILE_ANSWERED => Code::FileAnswered(
+            fields.uint()?,
+            fields.long()?,
+            fields.byte()? != 0,
+            fields.rest()?,
+        ),
+        // FileChunk(transfer, data) is code 42
+        FILE_CHUNK => Code::FileChunk(fields.long()?, fields.bytes.to_vec()),
+        // FileReceived(transfer, bytes) is code 43
+        FILE_RECEIVED => Code::FileReceived(fields.long()?, fields.long()?),
+        // FileCancel(transfer, reason) is code 44
+        FILE_CANCEL => Code::FileCancel(fields.long()?, fields.rest()?),
+        // ListRooms is code 45
+        LIST_ROOMS => Code::ListRooms,
+        // Rooms(rooms) is code 46
+        ROOMS => {
+            let count = fields.uint()?;
+            let mut rooms = Vec::new();
+            for _ in 0..count {
+                rooms.push(RoomInfo {
+                    name: fields.string()?,
+                    members: fields.uint()?,
+                    password: fields.byte()? != 0,
+                });
+            }
+            Code::Rooms(rooms)
+        }
+        // Room(name) is code 47
+        ROOM => Code::Room(fields.rest()?),
+        // Discover is code 48
+        DISCOVER => Code::Discover,
+        // Announce(port, room, admin) is code 49
+        ANNOUNCE => Code::Announce(fields.short()?, fields.string()?, fields.rest()?),
+        // Token(seconds, token) is code 50
+        TOKEN => Code::Token(fields.uint()?, fields.bytes.to_vec()),
+        // Resume(last id, token) is code 51
+        RESUME => Code::Resume(fields.long()?, fields.bytes.to_vec()),
+        // Other codes are not suppored
+        code => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Chattest stream error: code {} not supported!", code),
+            ))
+        }
+    };
+    Ok(code)
+}
+
+/// Collects the bytes that arrive from a stream and turns them into `Code`s
+pub struct Decoder {
+    buffer: Vec<u8>,
+    /// Bytes of a message too big to be collected that didn't arrive yet
+    skip: usize,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder {
+            buffer: Vec::new(),
+            skip: 0,
+        }
+    }
+
+    /// Adds the recived `bytes` at the end of the ones already collected
+    pub fn extend(&mut self, bytes: &[u8]) {
+        let skipped = self.skip.min(bytes.len());
+        self.skip -= skipped;
+        self.buffer.extend_from_slice(&bytes[skipped..]);
+    }
+
+    /// Removes the first message from the collected bytes and returns it,
+    /// or returns `None` if it didn't arrive completely yet
+    pub fn decode(&mut self) -> io::Result<Option<Code>> {
+        let end = match frame_len(&self.buffer) {
+            Ok(Some(len)) if len <= self.buffer.len() => len,
+            Ok(_) => return Ok(None),
+            Err(error) => {
+                // Throw the message away as it arrives instead of keeping it
+                let b = &self.buffer;
+                let whole = HEADER + bytes_to_uint([b[1], b[2], b[3], b[4]]) as usize;
+                let len = whole.min(self.buffer.len());
+                self.buffer.drain(..len);
+                self.skip = whole - len;
+                return Err(error);
+            }
+        };
+        // Take out the whole message so the next one starts at the beginning
+        let frame: Vec<u8> = self.buffer.drain(..end).collect();
+        decode(&frame).map(Some)
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Encodes `message` and sends it through the stream
+fn send<W: Write>(stream: &mut W, message: &Code) -> io::Result<()> {
+    let mut buf = Vec::new();
+    encode(message, &mut buf);
+    stream.write_all(&buf)?;
+    stream.flush()
+}
+
+/// The connection under a stream, with TLS on top of it or not
+enum Socket {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<rustls::StreamOwned<rustls::ClientConnection, TcpStream>>),
+}
+
+impl Socket {
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            Socket::Plain(stream) => stream,
+            #[cfg(feature = "tls")]
+            Socket::Tls(stream) => &stream.sock,
+        }
+    }
+
+    /// Fingerprint of the certificate of the server, if there is TLS
+    fn fingerprint(&self) -> Option<String> {
+        match self {
+            Socket::Plain(_) => None,
+            #[cfg(feature = "tls")]
+            Socket::Tls(stream) => stream
+                .conn
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .map(|cert| crate::tls::fingerprint(cert)),
+        }
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Plain(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Socket::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Plain(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Socket::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Socket::Plain(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Socket::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// A wrapper around the `TcpStream` that uses the Chattest protocol
+pub struct BlockingStream {
+    socket: Socket,
+    decoder: Decoder,
+}
+
+impl BlockingStream {
+    pub fn new(stream: TcpStream) -> Self {
+        Self::with_decoder(Socket::Plain(stream), Decoder::new())
+    }
+
+    fn with_decoder(socket: Socket, decoder: Decoder) -> Self {
+        socket.tcp().set_nonblocking(false).unwrap();
+        BlockingStream { socket, decoder }
+    }
+
+    /// Read from the stream a message and returns it
+    pub fn read(&mut self) -> io::Result<Code> {
+        let mut chunk = [0u8; 1024];
+        loop {
+            // A previous read could have already got the whole message
+            if let Some(code) = self.decoder.decode()? {
+                return Ok(code);
+            }
+            let bytes = self
+                .socket
+                .read(&mut chunk)
+                .map_err(|error| match error.kind() {
+                    // The socket has a read timeout and it passed
+                    ErrorKind::WouldBlock => io::Error::new(
+                        ErrorKind::TimedOut,
+                        "Chattest stream error: the other side doesn't respond!",
+                    ),
+                    _ => error,
+                })?;
+            if bytes == 0 {
+                return Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "Chattest stream error: connection closed!",
+                ));
+            }
+            self.decoder.extend(&chunk[..bytes]);
+        }
+    }
+
+    /// Sends a `message` through the stream:
+    pub fn write(&mut self, message: Code) -> io::Result<()> {
+        send(&mut self.socket, &message)
+    }
+
+    /// Encrypts the stream from now on, the server is trusted if `config`
+    /// says so. The handshake is done right away
+    #[cfg(feature = "tls")]
+    pub fn start_tls(self, config: std::sync::Arc<rustls::ClientConfig>) -> io::Result<Self> {
+        use std::convert::TryFrom;
+        let mut stream = match self.socket {
+            Socket::Plain(stream) => stream,
+            Socket::Tls(_) => return Ok(self),
+        };
+        let name = rustls::pki_types::ServerName::try_from(crate::tls::SERVER_NAME)
+            .expect("the name of the server is valid");
+        let mut connection =
+            rustls::ClientConnection::new(config, name).map_err(crate::tls::tls_error)?;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut stream)?;
+        }
+        Ok(BlockingStream {
+            socket: Socket::Tls(Box::new(rustls::StreamOwned::new(connection, stream))),
+            decoder: self.decoder,
+        })
+    }
+
+    /// Fingerprint of the certificate of the server, if the stream is encrypted
+    pub fn fingerprint(&self) -> Option<String> {
+        self.socket.fingerprint()
+    }
+
+    /// Address of the other side of the stream
+    pub fn peer_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.socket.tcp().peer_addr()
+    }
+
+    /// Set the stream
+    pub fn non_blocking(self) -> NonBlockingStream {
+        NonBlockingStream::with_decoder(self.socket, self.decoder)
+    }
+}
+
+/// A non blocking stream that only blocks on writing
+pub struct NonBlockingStream {
+    socket: Socket,
+    decoder: Decoder,
+}
+
+impl NonBlockingStream {
+    fn with_decoder(socket: Socket, decoder: Decoder) -> Self {
+        socket.tcp().set_nonblocking(true).unwrap();
+        NonBlockingStream { socket, decoder }
+    }
+
+    pub fn try_read(&mut self) -> io::Result<Option<Code>> {
+        // A previous read could have already got the whole message
+        if let Some(code) = self.decoder.decode()? {
+            return Ok(Some(code));
+        }
+        // Read from the stream some bytes and append them in the buffer
+        let mut chunk = [0u8; 1024];
+        match self.socket.read(&mut chunk) {
+            // The other side closed the connection
+            Ok(0) => Err(io::Error::new(
+                ErrorKind::ConnectionReset,
+                "Chattest stream error: connection closed!",
+            )),
+            Ok(bytes) => {
+                self.decoder.extend(&chunk[..bytes]);
+                self.decoder.decode()
+            }
+            Err(error) => match error.kind() {
+                ErrorKind::WouldBlock => Ok(None),
+                // TLS tells when the other side went away without saying it
+                ErrorKind::UnexpectedEof => Err(io::Error::new(
+                    ErrorKind::ConnectionReset,
+                    "Chattest stream error: connection closed!",
+                )),
+                _ => Err(error),
+            },
+        }
+    }
+
+    pub fn write(&mut self, message: Code) -> io::Result<()> {
+        // Wait until the whole message is sent
+        self.socket.tcp().set_nonblocking(false)?;
+        let result = send(&mut self.socket, &message);
+        self.socket.tcp().set_nonblocking(true)?;
+        result
+    }
+
+    /// Fingerprint of the certificate of the server, if the stream is encrypted
+    pub fn fingerprint(&self) -> Option<String> {
+        self.socket.fingerprint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One message for every code
+    fn every_code() -> Vec<Code> {
+        let text = || "héllo wörld".to_string();
+        let bytes = || vec![0, 1, 2, 254, 255];
+        let entry = |id, change| Entry {
+            id,
+            sender: "alice".to_string(),
+            time: 1_600_000_000,
+            text: text(),
+            change,
+        };
+        vec![
+            Code::Name("alice".to_string()),
+            Code::AlreadyHere,
+            Code::MessageTo(text()),
+            Code::MessageFrom("alice".to_string(), text()),
+            Code::Welcome("Lobby".to_string(), "server".to_string()),
+            Code::Hello(VERSION, CAPABILITIES),
+            Code::Rejected(Reason::Banned),
+            Code::Rejected(Reason::TlsRequired),
+            Code::Rejected(Reason::Other(200)),
+            Code::History(vec![entry(1, Change::Sent), entry(3, Change::Edited)]),
+            Code::History(Vec::new()),
+            Code::DirectTo("bob".to_string(), text()),
+            Code::DirectFrom("alice".to_string(), text()),
+            Code::NoSuchUser("carol".to_string()),
+            Code::UserJoined("bob".to_string()),
+            Code::UserLeft("bob".to_string()),
+            Code::UserList(vec!["alice".to_string(), "bob".to_string()]),
+            Code::Kicked,
+            Code::Banned,
+            Code::Muted(MUTED_FOREVER),
+            Code::Nick("alice2".to_string()),
+            Code::Renamed("alice".to_string(), "alice2".to_string()),
+            Code::Challenge(bytes()),
+            Code::Answer(bytes()),
+            Code::AuthRequired(AUTH_KEY, bytes()),
+            Code::Auth(AUTH_KEY, bytes()),
+            Code::Register(AUTH_KEY, bytes()),
+            Code::Verified("alice".to_string()),
+            Code::PublicKey(bytes()),
+            Code::MemberKey("bob".to_string(), bytes()),
+            Code::Rekey(7),
+            Code::GroupKeyTo("bob".to_string(), 7, bytes()),
+            Code::GroupKeyFrom("alice".to_string(), 7, bytes()),
+            Code::EncryptedTo(7, bytes()),
+            Code::EncryptedFrom("alice".to_string(), 7, bytes()),
+            Code::Stamped(
+                u64::MAX,
+                1_600_000_000,
+                Box::new(Code::MessageFrom("alice".to_string(), text())),
+            ),
+            Code::Stamped(
+                2,
+                0,
+                Box::new(Code::EncryptedFrom("a".to_string(), 1, bytes())),
+            ),
+            Code::Ack(2),
+            Code::Delivered(2, "bob".to_string()),
+            Code::Edit(2, text()),
+            Code::Delete(2),
+            Code::FileOffer(1, "bob".to_string(), "a.txt".to_string(), 1 << 40, bytes()),
+            Code::FileOffered(1, "alice".to_string(), "a.txt".to_string(), 10, bytes()),
+            Code::FileAnswer(1, true),
+            Code::FileAnswered(1, 2, false, "bob".to_string()),
+            Code::FileChunk(1, bytes()),
+            Code::FileReceived(1, 5),
+            Code::FileCancel(1, "no space".to_string()),
+            Code::ListRooms,
+            Code::Rooms(vec![
+                RoomInfo {
+                    name: "Lobby".to_string(),
+                    members: 3,
+                    password: false,
+                },
+                RoomInfo {
+                    name: "Games".to_string(),
+                    members: 0,
+                    password: true,
+                },
+            ]),
+            Code::Room("Games".to_string()),
+            Code::Discover,
+            Code::Announce(PORT, "Lobby".to_string(), "server".to_string()),
+            Code::Token(60, bytes()),
+            Code::Resume(9, bytes()),
+        ]
+    }
+
+    #[test]
+    fn decodes_what_is_encoded() {
+        for code in every_code() {
+            let mut frame = Vec::new();
+            encode(&code, &mut frame);
+            assert_eq!(frame_len(&frame).unwrap(), Some(frame.len()), "{:?}", code);
+            assert_eq!(decode(&frame).unwrap(), code);
+        }
+    }
+
+    #[test]
+    fn collects_the_messages_as_they_arrive() {
+        let mut stream = Vec::new();
+        for code in every_code() {
+            encode(&code, &mut stream);
+        }
+        // One byte at a time, like the slowest of the connections
+        let mut decoder = Decoder::new();
+        let mut decoded = Vec::new();
+        for byte in stream {
+            decoder.extend(&[byte]);
+            while let Some(code) = decoder.decode().unwrap() {
+                decoded.push(code);
+            }
+        }
+        assert_eq!(decoded, every_code());
+    }
+
+    #[test]
+    fn skips_the_messages_too_big() {
+        let too_big = MAX_FRAME + 1;
+        let mut stream = vec![MESSAGE_TO];
+        stream.extend_from_slice(&uint_to_bytes(too_big as u32));
+        stream.resize(HEADER + too_big, b'x');
+        encode(&Code::MessageTo("after".to_string()), &mut stream);
+
+        let mut decoder = Decoder::new();
+        let mut decoded = Vec::new();
+        let mut errors = 0;
+        for chunk in stream.chunks(1 << 20) {
+            decoder.extend(chunk);
+            loop {
+                match decoder.decode() {
+                    Ok(Some(code)) => decoded.push(code),
+                    Ok(None) => break,
+                    Err(_) => errors += 1,
+                }
+            }
+            // What is thrown away is never collected
+            assert!(decoder.buffer.len() <= 1 << 20);
+        }
+        assert_eq!(errors, 1);
+        assert_eq!(decoded, vec![Code::MessageTo("after".to_string())]);
+        assert!(decode(&stream[..HEADER]).is_err());
+    }
+
+    #[test]
+    fn parses_the_addresses() {
+        let parse = |addr| parse_addr(addr, PORT).ok();
+        assert_eq!(
+            parse("example.com"),
+            Some(("example.com".to_string(), PORT))
+        );
+        assert_eq!(
+            parse(" 10.0.0.1:7400 "),
+            Some(("10.0.0.1".to_string(), 7400))
+        );
+        // IPv6 with the port must be between brackets
+        assert_eq!(parse("[::1]:7400"), Some(("::1".to_string(), 7400)));
+        assert_eq!(parse("[fe80::1]"), Some(("fe80::1".to_string(), PORT)));
+        assert_eq!(parse("::1"), Some(("::1".to_string(), PORT)));
+        assert_eq!(
+            parse("fe80::1:7400"),
+            Some(("fe80::1:7400".to_string(), PORT))
+        );
+    }
+
+    #[test]
+    fn refuses_the_invalid_addresses() {
+        for addr in [
+            "",
+            ":7400",
+            "host:",
+            "host:port",
+            "host:65536",
+            "host:-1",
+            "[::1",
+            "[::1]7400",
+            "[::1]:",
+            "[::1]:99999",
+            "[host]:7400",
+            "[]:7400",
+        ] {
+            let error = parse_addr(addr, PORT).err();
+            assert_eq!(
+                error.map(|error| error.kind()),
+                Some(ErrorKind::InvalidInput),
+                "{}",
+                addr
+            );
+        }
+    }
+}
@@ -0,0 +1,1682 @@
+use crate::accounts::{Accounts, Credential};
+use crate::bans::{Ban, BanList};
+use crate::e2e;
+use crate::history::{self, Change, Entry, Store};
+use crate::protocol::{self, Code};
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Identifies a connection to the room
+pub type Id = usize;
+
+/// How many texts the room remembers the sender of, to tell him who
+/// received them
+const SENDERS_KEPT: usize = 1000;
+/// Biggest file that can be sent by default, 16 MiB
+pub const MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+/// How long the name of who lost the connection is kept for him by default
+pub const GRACE: Duration = Duration::from_secs(60);
+
+/// What the room asks to whoever moves its bytes
+#[derive(Debug)]
+pub enum Action {
+    /// Send a message to a connection
+    Send(Id, Code),
+    /// Close a connection once its messages have been sent
+    Close(Id),
+    /// Show a line to the host of the room
+    Log(String),
+    /// Encrypt the connection with TLS, after the messages before this one
+    /// have been sent
+    StartTls(Id),
+}
+
+/// Which of the past messages are sent to the clients that join
+#[derive(Clone, Copy, Debug)]
+pub enum Backlog {
+    /// The last messages
+    Last(usize),
+    /// The messages sent in the last period of time
+    Within(Duration),
+}
+
+/// What the admin of the room can do to the other members
+#[derive(Clone, Debug)]
+pub enum Moderation {
+    /// Remove a member from the room
+    Kick(String),
+    /// Remove the members with a name or an IP address and don't let them
+    /// join again
+    Ban(String),
+    /// Let a name or an IP address join again
+    Unban(String),
+    /// Don't let a member send messages for a while, or until he's unmuted
+    Mute(String, Option<Duration>),
+    /// Let a member send messages again
+    Unmute(String),
+    /// Change the text of a message, given its id
+    Edit(u64, String),
+    /// Delete a message, given its id
+    Delete(u64),
+}
+
+/// A connection to the room, it has a name once it has joined
+struct Member {
+    id: Id,
+    addr: SocketAddr,
+    name: Option<String>,
+    /// Capabilities agreed with the HELLO message
+    capabilities: u32,
+    /// Whether he sent something already, HELLO can only be the first
+    /// message
+    greeted: bool,
+    /// Nonce of the CHALLENGE sent to him and the name he asked for
+    challenge: Option<(Vec<u8>, String)>,
+    /// Nonce of the AUTH_REQUIRED sent to him and the registered name he
+    /// asked for
+    auth: Option<(Vec<u8>, String)>,
+    /// Whether he proved that his name is his
+    verified: bool,
+    /// His X25519 key, in the rooms with end-to-end encryption
+    public_key: Option<Vec<u8>>,
+    /// Token to resume the session, if he can do it
+    token: Option<Vec<u8>>,
+}
+
+/// A member that lost the connection, his name is kept until he comes back
+/// with the token or the grace period ends
+struct Away {
+    name: String,
+    addr: SocketAddr,
+    token: Vec<u8>,
+    /// When his name is released
+    until: u64,
+    verified: bool,
+    /// Ids of the texts he sent that the room still remembers
+    texts: Vec<u64>,
+}
+
+/// A file being sent from a member to another through the room
+struct Transfer {
+    id: u64,
+    from: Id,
+    to: Id,
+    /// Id given to the offer by who sent it
+    offer: u32,
+    file: String,
+    size: u64,
+    accepted: bool,
+    /// Bytes sent by `from` and bytes received by `to`
+    sent: u64,
+    received: u64,
+}
+
+/// The state of a Chattest room, it doesn't touch the network: every event
+/// is turned into the `Action`s that must follow it
+pub struct Room {
+    name: String,
+    admin: String,
+    members: Vec<Member>,
+    /// Messages said in the room, oldest first
+    history: Vec<Entry>,
+    store: Option<Box<dyn Store>>,
+    backlog: Backlog,
+    bans: BanList,
+    /// Names of the muted members and until when they are muted
+    mutes: Vec<(String, u64)>,
+    /// Whether the clients can ask to use TLS
+    tls: bool,
+    /// Password the clients must know to join
+    password: Option<String>,
+    /// Registered names, `None` if nobody can register
+    accounts: Option<Accounts>,
+    /// Whether the texts are encrypted end-to-end by the clients
+    e2e: bool,
+    /// Epoch of the last group key that was asked for
+    epoch: u32,
+    /// Id of the last text that was relayed
+    last_id: u64,
+    /// Ids of the last texts and the connections that sent them
+    senders: VecDeque<(u64, Id)>,
+    /// Ids of the last texts and the connections they were stamped for, that
+    /// didn't acknowledge them yet
+    recipients: VecDeque<(u64, Vec<Id>)>,
+    /// Files being offered or sent
+    transfers: Vec<Transfer>,
+    /// Id of the last transfer that was offered
+    last_transfer: u64,
+    /// Biggest file that can be sent, in bytes
+    max_file_size: u64,
+    /// Whether the room is hosted with others by a `Lobby`
+    lobby: bool,
+    /// Members that lost the connection and can still come back
+    away: Vec<Away>,
+    /// How long they can take to come back
+    grace: Duration,
+}
+
+/// Checks if someone in the room already uses the name `val`
+fn find_string(members: &[Member], val: &str) -> bool {
+    members
+        .iter()
+        .any(|member| member.name.as_deref() == Some(val))
+}
+
+impl Room {
+    pub fn new(name: String, admin: String) -> Self {
+        Room {
+            name,
+            admin,
+            members: Vec::new(),
+            history: Vec::new(),
+            store: None,
+            backlog: Backlog::Last(20),
+            bans: BanList::new(),
+            mutes: Vec::new(),
+            tls: false,
+            password: None,
+            accounts: None,
+            e2e: false,
+            epoch: 0,
+            last_id: 0,
+            senders: VecDeque::new(),
+            recipients: VecDeque::new(),
+            transfers: Vec::new(),
+            last_transfer: 0,
+            max_file_size: MAX_FILE_SIZE,
+            lobby: false,
+            away: Vec::new(),
+            grace: GRACE,
+        }
+    }
+
+    /// A room that saves its messages in `store`, the ones already there
+    /// are loaded back
+    pub fn with_store(name: String, admin: String, mut store: Box<dyn Store>) -> io::Result<Self> {
+        let entries = store.load()?;
+        // The ids go on from the last one that was given
+        let last_id = entries.iter().map(|entry| entry.id).max().unwrap_or(0);
+        let mut history = Vec::new();
+        for entry in entries {
+            history::apply(&mut history, entry);
+        }
+        Ok(Room {
+            history,
+            store: Some(store),
+            last_id,
+            ..Room::new(name, admin)
+        })
+    }
+
+    /// Name of the room
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Name of the admin of the room
+    pub fn admin(&self) -> &str {
+        &self.admin
+    }
+
+    /// How many clients joined the room
+    pub fn members(&self) -> usize {
+        self.members
+            .iter()
+            .filter(|member| member.name.is_some())
+            .count()
+    }
+
+    /// Whether the clients must know a password to join
+    pub fn has_password(&self) -> bool {
+        self.password.is_some()
+    }
+
+    /// Messages said in the room, oldest first
+    pub fn history(&self) -> &[Entry] {
+        &self.history
+    }
+
+    /// Changes which past messages are sent to the clients that join
+    pub fn set_backlog(&mut self, backlog: Backlog) {
+        self.backlog = backlog;
+    }
+
+    /// Changes who can't join the room
+    pub fn set_bans(&mut self, bans: BanList) {
+        self.bans = bans;
+    }
+
+    /// Who can't join the room
+    pub fn bans(&self) -> &[Ban] {
+        self.bans.bans()
+    }
+
+    /// Lets the clients ask to encrypt the connection, the host must be
+    /// able to do it when it receives `Action::StartTls`
+    pub fn set_tls(&mut self, tls: bool) {
+        self.tls = tls;
+    }
+
+    /// Asks the clients that join to prove that they know `password`
+    pub fn set_password(&mut self, password: Option<String>) {
+        self.password = password;
+    }
+
+    /// Lets the clients register their names in `accounts`
+    pub fn set_accounts(&mut self, accounts: Accounts) {
+        self.accounts = Some(accounts);
+    }
+
+    /// Lets in only the clients that encrypt their texts end-to-end, so that
+    /// the room can't read them
+    pub fn set_e2e(&mut self, e2e: bool) {
+        self.e2e = e2e;
+    }
+
+    /// Changes the biggest file that the members can send, in bytes
+    pub fn set_max_file_size(&mut self, size: u64) {
+        self.max_file_size = size;
+    }
+
+    /// Changes how long the name of who lost the connection is kept for him,
+    /// zero doesn't let the clients resume their session
+    pub fn set_grace(&mut self, grace: Duration) {
+        self.grace = grace;
+    }
+
+    /// Lets the clients ask for the other rooms of the `Lobby` that hosts
+    /// this one
+    pub(crate) fn set_lobby(&mut self, lobby: bool) {
+        self.lobby = lobby;
+    }
+
+    /// Capabilities that the room can agree on
+    fn supported(&self) -> u32 {
+        let mut supported = protocol::CAPABILITIES;
+        if self.tls {
+            supported |= protocol::CAP_TLS;
+        }
+        if self.e2e {
+            supported |= protocol::CAP_E2E;
+        }
+        if self.lobby {
+            supported |= protocol::CAP_ROOMS;
+        }
+        if self.grace.is_zero() {
+            supported &= !protocol::CAP_RESUME;
+        }
+        supported
+    }
+
+    /// The past messages to send to a client that joins
+    fn backlog(&self) -> Vec<Entry> {
+        let start = match self.backlog {
+            Backlog::Last(count) => self.history.len().saturating_sub(count),
+            Backlog::Within(period) => {
+                let since = history::now().saturating_sub(period.as_secs());
+                self.history
+                    .iter()
+                    .position(|entry| entry.time >= since)
+                    .unwrap_or(self.history.len())
+            }
+        };
+        self.history[start..].to_vec()
+    }
+
+    /// Keeps track of a message, or of a change to one, the host is told
+    /// if it can't be saved
+    fn record(&mut self, entry: Entry) -> Vec<Action> {
+        let mut actions = Vec::new();
+        if let Some(store) = &mut self.store {
+            if let Err(error) = store.append(&entry) {
+                actions.push(Action::Log(format!("  History error: {}", error)));
+            }
+        }
+        history::apply(&mut self.history, entry);
+        actions
+    }
+
+    /// Sends `code` to every member that joined, except `exclude`
+    fn broadcast(&self, exclude: Option<Id>, code: impl Fn() -> Code) -> Vec<Action> {
+        self.members
+            .iter()
+            .filter(|member| member.name.is_some() && Some(member.id) != exclude)
+            .map(|member| Action::Send(member.id, code()))
+            .collect()
+    }
+
+    /// Tells the members that someone joined or left, with `code` to the ones
+    /// that keep the list of the users and with the text `fallback` to the others
+    fn announce(
+        &self,
+        exclude: Option<Id>,
+        code: impl Fn() -> Code,
+        fallback: String,
+    ) -> Vec<Action> {
+        self.broadcast_or(exclude, protocol::CAP_USERS, code, fallback)
+    }
+
+    /// Sends `code` to the members that have the `capability` and the text
+    /// `fallback` to the others, except `exclude`
+    fn broadcast_or(
+        &self,
+        exclude: Option<Id>,
+        capability: u32,
+        code: impl Fn() -> Code,
+        fallback: String,
+    ) -> Vec<Action> {
+        self.members
+            .iter()
+            .filter(|member| member.name.is_some() && Some(member.id) != exclude)
+            .map(|member| {
+                if member.capabilities & capability != 0 {
+                    Action::Send(member.id, code())
+                } else {
+                    Action::Send(member.id, Code::MessageTo(fallback.clone()))
+                }
+            })
+            .collect()
+    }
+
+    /// Gives the next id to a text sent by `from`, `None` if it's from the
+    /// admin
+    fn stamp(&mut self, from: Option<Id>) -> u64 {
+        self.last_id += 1;
+        if let Some(from) = from {
+            if self.senders.len() == SENDERS_KEPT {
+                self.senders.pop_front();
+            }
+            self.senders.push_back((self.last_id, from));
+        }
+        self.last_id
+    }
+
+    /// Forgets the texts sent by the connection `id`, another one could get
+    /// its id, and stops the files it was sending or receiving
+    fn forget(&mut self, id: Id) -> Vec<Action> {
+        self.senders.retain(|(_, sender)| *sender != id);
+        for (_, recipients) in &mut self.recipients {
+            recipients.retain(|recipient| *recipient != id);
+        }
+        self.stop_transfers(id)
+    }
+
+    /// Stops the files that the connection `id` was sending or receiving
+    fn stop_transfers(&mut self, id: Id) -> Vec<Action> {
+        let mut actions = Vec::new();
+        self.transfers.retain(|transfer| {
+            let other = match (transfer.from == id, transfer.to == id) {
+                (true, _) => transfer.to,
+                (_, true) => transfer.from,
+                _ => return true,
+            };
+            let reason = "the other side left the room".to_string();
+            actions.push(Action::Send(other, Code::FileCancel(transfer.id, reason)));
+            false
+        });
+        actions
+    }
+
+    /// Sends the text `code` to every member that joined with its id
+    /// `text_id` and the time, the ones that don't know STAMPED get only the
+    /// text. Its sender `from` gets it back only if he knows STAMPED, so that
+    /// he knows that it was relayed
+    fn relay(&mut self, from: Option<Id>, text_id: u64, code: impl Fn() -> Code) -> Vec<Action> {
+        let time = history::now();
+        let actions: Vec<Action> = self
+            .members
+            .iter()
+            .filter(|member| member.name.is_some())
+            .filter_map(|member| {
+                if member.capabilities & protocol::CAP_IDS != 0 {
+                    Some(Action::Send(
+                        member.id,
+                        Code::Stamped(text_id, time, Box::new(code())),
+                    ))
+                } else if Some(member.id) != from {
+                    Some(Action::Send(member.id, code()))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let recipients = self
+            .members
+            .iter()
+            .filter(|member| member.name.is_some() && Some(member.id) != from)
+            .filter(|member| member.capabilities & protocol::CAP_IDS != 0)
+            .map(|member| member.id)
+            .collect();
+        self.stamped(from, text_id, recipients);
+        actions
+    }
+
+    /// Remembers that the text `text_id` sent by `from` was stamped for
+    /// `recipients`, only they can acknowledge it
+    fn stamped(&mut self, from: Option<Id>, text_id: u64, recipients: Vec<Id>) {
+        // Nobody is told that the texts of the admin were received
+        if from.is_none() {
+            return;
+        }
+        if self.recipients.len() == SENDERS_KEPT {
+            self.recipients.pop_front();
+        }
+        self.recipients.push_back((text_id, recipients));
+    }
+
+    /// Names of the members that joined, the ones that lost the connection
+    /// are still there until their name is released
+    fn users(&self) -> Vec<String> {
+        self.members
+            .iter()
+            .filter_map(|member| member.name.clone())
+            .chain(self.away.iter().map(|away| away.name.clone()))
+            .collect()
+    }
+
+    /// Checks if the name `val` is used by the admin or by someone in the
+    /// room, or if it's kept for someone that lost the connection
+    fn taken(&self, val: &str) -> bool {
+        val == self.admin
+            || find_string(&self.members, val)
+            || self.away.iter().any(|away| away.name == val)
+    }
+
+    /// A new connection arrived
+    pub fn connected(&mut self, id: Id, addr: SocketAddr) -> Vec<Action> {
+        self.members.push(Member {
+            id,
+            addr,
+            name: None,
+            capabilities: 0,
+            greeted: false,
+            challenge: None,
+            auth: None,
+            verified: false,
+            public_key: None,
+            token: None,
+        });
+        Vec::new()
+    }
+
+    /// The connection `id` was closed
+    pub fn disconnected(&mut self, id: Id) -> Vec<Action> {
+        let idx = match self.members.iter().position(|member| member.id == id) {
+            Some(idx) => idx,
+            None => return Vec::new(),
+        };
+        let member = self.members.remove(idx);
+        // Keep his name for a while if he can come back
+        if let (Some(name), Some(token)) = (&member.name, &member.token) {
+            let texts = self
+                .senders
+                .iter()
+                .filter(|(_, sender)| *sender == id)
+                .map(|(text_id, _)| *text_id)
+                .collect();
+            let mut actions = self.forget(id);
+            actions.push(Action::Log(format!("  User {} lost the connection", name)));
+            self.away.push(Away {
+                name: name.clone(),
+                addr: member.addr,
+                token: token.clone(),
+                until: history::now().saturating_add(self.grace.as_secs()),
+                verified: member.verified,
+                texts,
+            });
+            // He must not be able to read what is said meanwhile
+            if member.public_key.is_some() {
+                actions.extend(self.rekey());
+            }
+            return actions;
+        }
+        let stopped = self.forget(id);
+        // Nobody knew about him if he didn't join
+        let name = match member.name {
+            Some(name) => name,
+            None => return Vec::new(),
+        };
+        // Comunicating the event to the other clients
+        let mut actions = self.announce(
+            None,
+            || Code::UserLeft(name.clone()),
+            format!("User {} disconnected!", name),
+        );
+        actions.extend(stopped);
+        actions.push(Action::Log(format!("  User {} disconnected!", name)));
+        // He must not be able to read what is said from now on
+        if member.public_key.is_some() {
+            actions.extend(self.rekey());
+        }
+        actions
+    }
+
+    /// The connection `id` goes to another room before joining this one,
+    /// returns its address. Returns `None` if it already joined
+    pub fn moved_out(&mut self, id: Id) -> Option<SocketAddr> {
+        let idx = self
+            .members
+            .iter()
+            .position(|member| member.id == id && member.name.is_none())?;
+        Some(self.members.remove(idx).addr)
+    }
+
+    /// The connection `id` that offered `capabilities` with HELLO comes from
+    /// another room, it's told the ones of this room
+    pub fn moved_in(&mut self, id: Id, addr: SocketAddr, capabilities: u32) -> Vec<Action> {
+        let mut actions = self.connected(id, addr);
+        let capabilities = self.supported() & capabilities;
+        if let Some(member) = self.members.last_mut() {
+            member.capabilities = capabilities;
+            member.greeted = true;
+        }
+        actions.push(Action::Send(
+            id,
+            Code::Hello(protocol::VERSION, capabilities),
+        ));
+        actions
+    }
+
+    /// Releases the names of who lost the connection and didn't come back
+    /// in time, the others are told that they left. `next_expiry` says when
+    /// it must be called
+    pub fn expire(&mut self) -> Vec<Action> {
+        let now = history::now();
+        let mut actions = Vec::new();
+        while let Some(pos) = self.away.iter().position(|away| away.until <= now) {
+            actions.extend(self.release(pos, "disconnected"));
+        }
+        actions
+    }
+
+    /// How long until the name of someone that lost the connection is
+    /// released, `None` if nobody is away
+    pub fn next_expiry(&self) -> Option<Duration> {
+        let now = history::now();
+        self.away
+            .iter()
+            .map(|away| Duration::from_secs(away.until.saturating_sub(now)))
+            .min()
+    }
+
+    /// Forgets who lost the connection at `pos` in `away`, the others are
+    /// told that he `what` (like "disconnected")
+    fn release(&mut self, pos: usize, what: &str) -> Vec<Action> {
+        let name = self.away.remove(pos).name;
+        let mut actions = self.announce(
+            None,
+            || Code::UserLeft(name.clone()),
+            format!("User {} {}!", name, what),
+        );
+        actions.push(Action::Log(format!("  User {} {}!", name, what)));
+        actions
+    }
+
+    /// The admin of the room sent a message
+    pub fn say(&mut self, text: String) -> Vec<Action> {
+        let text_id = self.stamp(None);
+        let mut actions = self.relay(None, text_id, || Code::MessageTo(text.clone()));
+        actions.push(Action::Log(format!("  #{} {}", text_id, text)));
+        let entry = Entry::now(text_id, self.admin.clone(), text);
+        actions.extend(self.record(entry));
+        actions
+    }
+
+    /// The admin of the room sent a private message to `to`
+    pub fn whisper(&mut self, to: String, text: String) -> Vec<Action> {
+        let from = self.admin.clone();
+        self.direct(None, from, to, text)
+    }
+
+    /// Delivers a private message, `from_id` is `None` if it's from the admin
+    fn direct(
+        &mut self,
+        from_id: Option<Id>,
+        from: String,
+        to: String,
+        text: String,
+    ) -> Vec<Action> {
+        if to == self.admin {
+            return vec![Action::Log(format!("  {} (private)> {}", from, text))];
+        }
+        let recipient = self
+            .members
+            .iter()
+            .find(|member| member.name.as_deref() == Some(to.as_str()));
+        let recipient = recipient.map(|member| (member.id, member.capabilities));
+        match (recipient, from_id) {
+            (Some((id, capabilities)), _) => {
+                let code = if capabilities & protocol::CAP_DIRECT != 0 {
+                    Code::DirectFrom(from, text.clone())
+                } else {
+                    Code::MessageFrom(format!("{} (private)", from), text.clone())
+                };
+                if capabilities & protocol::CAP_IDS == 0 {
+                    return vec![Action::Send(id, code)];
+                }
+                // Stamp it only for the recipient, the sender gets it back
+                // only if he knows STAMPED too
+                let stamp = self.stamp(from_id);
+                self.stamped(from_id, stamp, vec![id]);
+                let time = history::now();
+                let mut actions =
+                    vec![Action::Send(id, Code::Stamped(stamp, time, Box::new(code)))];
+                if let Some(from_id) = from_id.filter(|from_id| self.stamps(*from_id)) {
+                    let echo = Code::DirectTo(to, text);
+                    actions.push(Action::Send(
+                        from_id,
+                        Code::Stamped(stamp, time, Box::new(echo)),
+                    ));
+                }
+                actions
+            }
+            (None, Some(id)) => vec![Action::Send(id, Code::NoSuchUser(to))],
+            (None, None) => vec![Action::Log(format!("  There is no user called {}!", to))],
+        }
+    }
+
+    /// The connection `id` sent a message
+    pub fn received(&mut self, id: Id, code: Code) -> Vec<Action> {
+        let idx = match self.members.iter().position(|member| member.id == id) {
+            Some(idx) => idx,
+            None => return Vec::new(),
+        };
+        match self.members[idx].name.clone() {
+            Some(name) => self.message(id, name, code),
+            None => self.handshake(idx, code),
+        }
+    }
+
+    /// Handles the messages of a connection that didn't join yet
+    fn handshake(&mut self, idx: usize, code: Code) -> Vec<Action> {
+        let id = self.members[idx].id;
+        // Check if he is banned before letting him in
+        let name = match &code {
+            Code::Name(name) => Some(name.as_str()),
+            _ => None,
+        };
+        if self.bans.is_banned(name, self.members[idx].addr.ip()) {
+            let member = self.members.remove(idx);
+            return vec![
+                Action::Send(id, Code::Rejected(protocol::Reason::Banned)),
+                Action::Close(id),
+                Action::Log(format!("  Refused banned client {}", member.addr)),
+            ];
+        }
+        let greeted = std::mem::replace(&mut self.members[idx].greeted, true);
+        match code {
+            // If he tells which version of the protocol he speaks:
+            Code::Hello(version, capabilities) => {
+                // The capabilities were agreed already, TLS could be on
+                if greeted {
+                    return self.reject(idx, protocol::Reason::UnexpectedCode);
+                }
+                if version < protocol::MIN_VERSION {
+                    return self.reject(idx, protocol::Reason::UnsupportedVersion);
+                }
+                // Respond with the capabilities both sides support
+                let capabilities = self.supported() & capabilities;
+                self.members[idx].capabilities = capabilities;
+                let mut actions = vec![Action::Send(
+                    id,
+                    Code::Hello(protocol::VERSION, capabilities),
+                )];
+                if capabilities & protocol::CAP_TLS != 0 {
+                    actions.push(Action::StartTls(id));
+                }
+                actions
+            }
+            // If he sends his name:
+            Code::Name(name) => {
+                if self.password.is_none() {
+                    return self.join(idx, name);
+                }
+                // Check if there is noone else with that name before asking
+                // for the password
+                if self.taken(&name) {
+                    return vec![Action::Send(id, Code::AlreadyHere)];
+                }
+                if self.members[idx].capabilities & protocol::CAP_PASSWORD == 0 {
+                    return self.reject(idx, protocol::Reason::WrongPassword);
+                }
+                let nonce = protocol::nonce();
+                self.members[idx].challenge = Some((nonce.clone(), name));
+                vec![Action::Send(id, Code::Challenge(nonce))]
+            }
+            // If he proves that he knows the password:
+            Code::Answer(proof) => {
+                let challenge = self.members[idx].challenge.take();
+                match (challenge, &self.password) {
+                    (Some((nonce, name)), Some(password))
+                        if protocol::verify(password, &nonce, &proof) =>
+                    {
+                        self.join(idx, name)
+                    }
+                    _ => {
+                        let addr = self.members[idx].addr;
+                        let mut actions = self.reject(idx, protocol::Reason::WrongPassword);
+                        actions.push(Action::Log(format!("  Wrong password from {}", addr)));
+                        actions
+                    }
+                }
+            }
+            // If he proves that the registered name is his:
+            Code::Auth(method, proof) => {
+                let auth = self.members[idx].auth.take();
+                let credential = auth
+                    .as_ref()
+                    .and_then(|(_, name)| self.accounts.as_ref()?.get(name));
+                match (auth, credential) {
+                    (Some((nonce, name)), Some(credential))
+                        if credential.verify(&name, &nonce, method, &proof) =>
+                    {
+                        // He could have joined from somewhere else meanwhile
+                        if self.taken(&name) {
+                            return vec![Action::Send(id, Code::AlreadyHere)];
+                        }
+                        self.members[idx].verified = true;
+                        self.welcome(idx, name)
+                    }
+                    _ => {
+                        let addr = self.members[idx].addr;
+                        let mut actions = self.reject(idx, protocol::Reason::AuthFailed);
+                        actions.push(Action::Log(format!(
+                            "  Failed authentication from {}",
+                            addr
+                        )));
+                        actions
+                    }
+                }
+            }
+            // If he comes back after losing the connection:
+            Code::Resume(last_id, token) => self.resume(idx, last_id, token),
+            _ => self.reject(idx, protocol::Reason::UnexpectedCode),
+        }
+    }
+
+    /// Refuses the connection at `idx`, telling it the `reason`
+    fn reject(&mut self, idx: usize, reason: protocol::Reason) -> Vec<Action> {
+        let id = self.members.remove(idx).id;
+        vec![Action::Send(id, Code::Rejected(reason)), Action::Close(id)]
+    }
+
+    /// Lets the connection at `idx` join the room as `name`
+    fn join(&mut self, idx: usize, name: String) -> Vec<Action> {
+        let id = self.members[idx].id;
+        // Who can't encrypt would send the texts in plaintext
+        if self.e2e && self.members[idx].capabilities & protocol::CAP_E2E == 0 {
+            return self.reject(idx, protocol::Reason::EncryptionRequired);
+        }
+        // Check if there is noone else with that name
+        if self.taken(&name) {
+            // Else tell him to use another name
+            return vec![Action::Send(id, Code::AlreadyHere)];
+        }
+        // Ask him to prove that a registered name is his
+        if let Some(credential) = self
+            .accounts
+            .as_ref()
+            .and_then(|accounts| accounts.get(&name))
+        {
+            // Who can't prove it must use another name
+            if self.members[idx].capabilities & protocol::CAP_ACCOUNTS == 0 {
+                return vec![Action::Send(id, Code::AlreadyHere)];
+            }
+            let method = credential.method();
+            // The password would be sent in plaintext
+            if method == protocol::AUTH_PASSWORD
+                && self.members[idx].capabilities & protocol::CAP_TLS == 0
+            {
+                return self.reject(idx, protocol::Reason::TlsRequired);
+            }
+            let nonce = protocol::nonce();
+            self.members[idx].auth = Some((nonce.clone(), name));
+            return vec![Action::Send(id, Code::AuthRequired(method, nonce))];
+        }
+        self.welcome(idx, name)
+    }
+
+    /// Sends `Verified(name)` to the members that keep track of who is
+    /// verified
+    fn verified(&self, name: &str) -> Vec<Action> {
+        self.members
+            .iter()
+            .filter(|member| {
+                member.name.is_some() && member.capabilities & protocol::CAP_ACCOUNTS != 0
+            })
+            .map(|member| Action::Send(member.id, Code::Verified(name.to_string())))
+            .collect()
+    }
+
+    /// Lets the connection at `idx` in, with a name that is free
+    fn welcome(&mut self, idx: usize, name: String) -> Vec<Action> {
+        let id = self.members[idx].id;
+        // Tell the client the name of the room
+        let mut actions = vec![Action::Send(
+            id,
+            Code::Welcome(self.name.clone(), self.admin.clone()),
+        )];
+        // Tell him what was said before he arrived
+        let backlog = self.backlog();
+        if self.members[idx].capabilities & protocol::CAP_HISTORY != 0 && !backlog.is_empty() {
+            actions.push(Action::Send(id, Code::History(backlog)));
+        }
+        // Comunicate the new connection:
+        let verified = self.members[idx].verified;
+        actions.push(Action::Log(format!(
+            "  User connected:\n  {}({}){}",
+            name,
+            self.members[idx].addr,
+            if verified { " verified" } else { "" }
+        )));
+        actions.extend(self.announce(
+            None,
+            || Code::UserJoined(name.clone()),
+            format!("User {} connected!", name),
+        ));
+        if verified {
+            actions.extend(self.verified(&name));
+        }
+        self.members[idx].name = Some(name);
+        // Give him the token to come back if he can, where the connections
+        // are encrypted it can only be sent back through TLS
+        let capabilities = self.members[idx].capabilities;
+        if capabilities & protocol::CAP_RESUME != 0
+            && (!self.tls || capabilities & protocol::CAP_TLS != 0)
+        {
+            self.members[idx].token = Some(protocol::nonce());
+        }
+        actions.extend(self.introduce(idx));
+        actions
+    }
+
+    /// Lets the connection at `idx` take back the session of `token`, the
+    /// texts after `last_id` are sent as history
+    fn resume(&mut self, idx: usize, last_id: u64, token: Vec<u8>) -> Vec<Action> {
+        let id = self.members[idx].id;
+        // Who listens to a plaintext connection mustn't take the session
+        if self.tls && self.members[idx].capabilities & protocol::CAP_TLS == 0 {
+            return self.reject(idx, protocol::Reason::TlsRequired);
+        }
+        // The old connection could still look open, it's closed now
+        let mut actions = Vec::new();
+        if let Some(old) = self
+            .members
+            .iter()
+            .find(|member| member.name.is_some() && member.token.as_ref() == Some(&token))
+            .map(|member| member.id)
+        {
+            actions.extend(self.disconnected(old));
+            actions.push(Action::Close(old));
+        }
+        let idx = match self.members.iter().position(|member| member.id == id) {
+            Some(idx) => idx,
+            None => return actions,
+        };
+        let pos = match self.away.iter().position(|away| away.token == token) {
+            Some(pos) => pos,
+            None => return self.reject(idx, protocol::Reason::SessionExpired),
+        };
+        // Who can't encrypt would send the texts in plaintext
+        if self.e2e && self.members[idx].capabilities & protocol::CAP_E2E == 0 {
+            actions.extend(self.reject(idx, protocol::Reason::EncryptionRequired));
+            return actions;
+        }
+        // He could have been banned from where he is now
+        let addr = self.members[idx].addr;
+        if self.bans.is_banned(Some(&self.away[pos].name), addr.ip()) {
+            actions.extend(self.reject(idx, protocol::Reason::Banned));
+            actions.extend(self.release(pos, "was banned"));
+            return actions;
+        }
+        let away = self.away.remove(pos);
+        // He can still change the texts he sent
+        for text_id in away.texts {
+            if self.senders.len() == SENDERS_KEPT {
+                self.senders.pop_front();
+            }
+            self.senders.push_back((text_id, id));
+        }
+        actions.push(Action::Send(
+            id,
+            Code::Welcome(self.name.clone(), self.admin.clone()),
+        ));
+        actions.push(Action::Log(format!(
+            "  User {} is back ({})",
+            away.name, addr
+        )));
+        // Tell him what was said while he was away
+        let missed: Vec<Entry> = self
+            .history
+            .iter()
+            .filter(|entry| entry.id > last_id)
+            .cloned()
+            .collect();
+        if self.members[idx].capabilities & protocol::CAP_HISTORY != 0 && !missed.is_empty() {
+            actions.push(Action::Send(id, Code::History(missed)));
+        }
+        // The token could have been seen by someone else, it's used once and
+        // the name must be proven again to be verified
+        let member = &mut self.members[idx];
+        member.name = Some(away.name);
+        member.token = Some(protocol::nonce());
+        actions.extend(self.introduce(idx));
+        // The others saw him as verified
+        if away.verified {
+            let others = (0..self.members.len())
+                .filter(|other| *other != idx && self.members[*other].name.is_some());
+            for other in others {
+                actions.extend(self.roster(other));
+            }
+        }
+        actions
+    }
+
+    /// Tells the member at `idx`, who just joined or came back, who is in
+    /// the room and how to come back if he loses the connection
+    fn introduce(&self, idx: usize) -> Vec<Action> {
+        let id = self.members[idx].id;
+        let mut actions = self.roster(idx);
+        // And the keys he will send the group keys with
+        if self.e2e {
+            actions.extend(self.members.iter().filter_map(|member| {
+                let key = member.public_key.clone()?;
+                Some(Action::Send(id, Code::MemberKey(member.name.clone()?, key)))
+            }));
+        }
+        // And how long his name is kept if he loses the connection
+        if let Some(token) = &self.members[idx].token {
+            let seconds = self.grace.as_secs().min(u32::MAX as u64) as u32;
+            actions.push(Action::Send(id, Code::Token(seconds, token.clone())));
+        }
+        actions
+    }
+
+    /// Tells the member at `idx` who is in the room, himself included, and
+    /// who of them is verified
+    fn roster(&self, idx: usize) -> Vec<Action> {
+        let id = self.members[idx].id;
+        let capabilities = self.members[idx].capabilities;
+        let mut actions = Vec::new();
+        if capabilities & protocol::CAP_USERS != 0 {
+            actions.push(Action::Send(id, Code::UserList(self.users())));
+        }
+        if capabilities & protocol::CAP_ACCOUNTS != 0 {
+            actions.extend(
+                self.members
+                    .iter()
+                    .filter(|member| member.verified)
+                    .filter_map(|member| member.name.clone())
+                    .chain(
+                        self.away
+                            .iter()
+                            .filter(|away| away.verified)
+                            .map(|away| away.name.clone()),
+                    )
+                    .map(|name| Action::Send(id, Code::Verified(name))),
+            );
+        }
+        actions
+    }
+
+    /// Asks for a new group key to the member that joined first, among the
+    /// ones that sent their key
+    fn rekey(&mut self) -> Vec<Action> {
+        let keeper = self
+            .members
+            .iter()
+            .find(|member| member.name.is_some() && member.public_key.is_some());
+        match keeper {
+            Some(keeper) => {
+                self.epoch = self.epoch.wrapping_add(1);
+                vec![Action::Send(keeper.id, Code::Rekey(self.epoch))]
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Handles the messages of the member `id` called `name`
+    fn message(&mut self, id: Id, name: String, code: Code) -> Vec<Action> {
+        // Muted members can't talk
+        if let (
+            Code::MessageTo(_)
+            | Code::DirectTo(..)
+            | Code::EncryptedTo(..)
+            | Code::Edit(..)
+            | Code::Delete(..)
+            | Code::FileOffer(..),
+            Some(seconds),
+        ) = (&code, self.muted(&name))
+        {
+            return vec![self.notify(id, Code::Muted(seconds))];
+        }
+        match code {
+            // The text must be encrypted if the room is end-to-end encrypted
+            Code::MessageTo(_) | Code::DirectTo(..) | Code::Edit(..) | Code::FileOffer(..)
+                if self.e2e =>
+            {
+                let text = "This room is end-to-end encrypted, the text wasn't sent!".to_string();
+                vec![Action::Send(id, Code::MessageTo(text))]
+            }
+            // If he tells his key tell it to the others, they need a new
+            // group key to send it to him
+            Code::PublicKey(key) if self.e2e && key.len() == e2e::KEY_LEN => {
+                let mut actions =
+                    self.broadcast(Some(id), || Code::MemberKey(name.clone(), key.clone()));
+                if let Some(member) = self.members.iter_mut().find(|member| member.id == id) {
+                    member.public_key = Some(key);
+                }
+                actions.extend(self.rekey());
+                actions
+            }
+            // If he sends a group key to someone forward it, nobody needs it
+            // if he left
+            Code::GroupKeyTo(to, epoch, sealed) if self.e2e => match self.find(&to) {
+                Some(idx) => vec![Action::Send(
+                    self.members[idx].id,
+                    Code::GroupKeyFrom(name, epoch, sealed),
+                )],
+                None => Vec::new(),
+            },
+            // If it's an encrypted text send it to the other clients
+            Code::EncryptedTo(epoch, sealed) if self.e2e => {
+                let text_id = self.stamp(Some(id));
+                let mut actions =
+                    vec![Action::Log(format!("  #{} {}> (encrypted)", text_id, name))];
+                actions.extend(self.relay(Some(id), text_id, || {
+                    Code::EncryptedFrom(name.clone(), epoch, sealed.clone())
+                }));
+                actions
+            }
+            // If it's a text message send it to the other clients
+            Code::MessageTo(text) => {
+                let text_id = self.stamp(Some(id));
+                let mut actions = vec![Action::Log(format!("  #{} {}> {}", text_id, name, text))];
+                actions.extend(self.relay(Some(id), text_id, || {
+                    Code::MessageFrom(name.clone(), text.clone())
+                }));
+                actions.extend(self.record(Entry::now(text_id, name, text)));
+                actions
+            }
+            // If it's a private message send it only to the recipient
+            Code::DirectTo(to, text) => self.direct(Some(id), name, to, text),
+            // If he received a text tell it to whoever sent it
+            Code::Ack(text_id) => self.acknowledged(id, name, text_id),
+            // If he wants to change or delete one of his texts:
+            Code::Edit(text_id, text) => {
+                self.revise(Some((id, name)), text_id, Change::Edited, text)
+            }
+            Code::Delete(text_id) => {
+                self.revise(Some((id, name)), text_id, Change::Deleted, String::new())
+            }
+            // If he wants another name:
+            Code::Nick(new) => self.rename(id, name, new),
+            // If he wants to protect his name:
+            Code::Register(method, secret) => self.register(id, name, method, secret),
+            // If he wants to send a file:
+            Code::FileOffer(offer, to, file, size, sha256) => {
+                self.offer(id, name, offer, to, file, size, sha256)
+            }
+            // If he answers to a file, sends it or stops it:
+            Code::FileAnswer(..)
+            | Code::FileChunk(..)
+            | Code::FileReceived(..)
+            | Code::FileCancel(..) => self.transfer(id, name, code),
+            _ => vec![Action::Log(format!(
+                "  Code not expected from {}: {:?}",
+                name, code
+            ))],
+        }
+    }
+
+    /// Applies the `change` to the text `text_id`, `by` is the member who
+    /// asked for it or `None` if it's the admin
+    fn revise(
+        &mut self,
+        by: Option<(Id, String)>,
+        text_id: u64,
+        change: Change,
+        text: String,
+    ) -> Vec<Action> {
+        let sender = match self
+            .history
+            .iter()
+            .find(|entry| entry.id != 0 && entry.id == text_id)
+        {
+            Some(entry) => entry.sender.clone(),
+            None => {
+                let text = format!("There is no message with id {}!", text_id);
+                return vec![match by {
+                    Some((id, _)) => Action::Send(id, Code::MessageTo(text)),
+                    None => Action::Log(format!("  {}", text)),
+                }];
+            }
+        };
+        // A member can change only what he sent, with a name that is his or
+        // from the same connection
+        if let Some((id, name)) = &by {
+            let verified = self
+                .members
+                .iter()
+                .any(|member| member.id == *id && member.verified);
+            if sender != *name || !(verified || self.senders.contains(&(text_id, *id))) {
+                let text = "Only who sent a message can change it!".to_string();
+                return vec![Action::Send(*id, Code::MessageTo(text))];
+            }
+        }
+        let who = by.map_or_else(|| self.admin.clone(), |(_, name)| name);
+        let what = if who == sender {
+            "a message".to_string()
+        } else {
+            format!("a message of {}", sender)
+        };
+        let mut actions = match change {
+            Change::Deleted => {
+                let mut actions = self.broadcast_or(
+                    None,
+                    protocol::CAP_EDIT,
+                    || Code::Delete(text_id),
+                    format!("{} deleted {}", who, what),
+                );
+                actions.push(Action::Log(format!("  {} deleted #{}", who, text_id)));
+                actions
+            }
+            _ => {
+                let mut actions = self.broadcast_or(
+                    None,
+                    protocol::CAP_EDIT,
+                    || Code::Edit(text_id, text.clone()),
+                    format!("{} edited {}: {}", who, what, text),
+                );
+                actions.push(Action::Log(format!(
+                    "  {} edited #{}: {}",
+                    who, text_id, text
+                )));
+                actions
+            }
+        };
+        actions.extend(self.record(Entry {
+            change,
+            ..Entry::now(text_id, who, text)
+        }));
+        actions
+    }
+
+    /// The member `id` called `name` offers a file to `to`, or to everyone if
+    /// it's empty
+    #[allow(clippy::too_many_arguments)]
+    fn offer(
+        &mut self,
+        id: Id,
+        name: String,
+        offer: u32,
+        to: String,
+        file: String,
+        size: u64,
+        sha256: Vec<u8>,
+    ) -> Vec<Action> {
+        if size > self.max_file_size {
+            let text = format!(
+                "The file is too big, the limit is {} bytes!",
+                self.max_file_size
+            );
+            return vec![Action::Send(id, Code::MessageTo(text))];
+        }
+        let recipients: Vec<Id> = if to.is_empty() {
+            self.members
+                .iter()
+                .filter(|member| {
+                    member.name.is_some()
+                        && member.id != id
+                        && member.capabilities & protocol::CAP_FILES != 0
+                })
+                .map(|member| member.id)
+                .collect()
+        } else {
+            match self.find(&to) {
+                Some(idx) if self.members[idx].capabilities & protocol::CAP_FILES != 0 => {
+                    vec![self.members[idx].id]
+                }
+                Some(_) => {
+                    let text = format!("User {} can't receive files!", to);
+                    return vec![Action::Send(id, Code::MessageTo(text))];
+                }
+                None => return vec![Action::Send(id, Code::NoSuchUser(to))],
+            }
+        };
+        if recipients.is_empty() {
+            let text = "Nobody here can receive files!".to_string();
+            return vec![Action::Send(id, Code::MessageTo(text))];
+        }
+        let mut actions = vec![Action::Log(format!(
+            "  User {} offered the file {} ({} bytes) to {}",
+            name,
+            file,
+            size,
+            if to.is_empty() { "everyone" } else { &to }
+        ))];
+        for recipient in recipients {
+            self.last_transfer += 1;
+            self.transfers.push(Transfer {
+                id: self.last_transfer,
+                from: id,
+                to: recipient,
+                offer,
+                file: file.clone(),
+                size,
+                accepted: false,
+                sent: 0,
+                received: 0,
+            });
+            actions.push(Action::Send(
+                recipient,
+                Code::FileOffered(
+                    self.last_transfer,
+                    name.clone(),
+                    file.clone(),
+                    size,
+                    sha256.clone(),
+                ),
+            ));
+        }
+        actions
+    }
+
+    /// Handles the messages of the member `id` called `name` about the files
+    /// being sent
+    fn transfer(&mut self, id: Id, name: String, code: Code) -> Vec<Action> {
+        match code {
+            // If he answers to a file offered to him tell who offered it
+            Code::FileAnswer(transfer_id, accepted) => {
+                let idx = match self.transfers.iter().position(|transfer| {
+                    transfer.id == transfer_id && transfer.to == id && !transfer.accepted
+                }) {
+                    Some(idx) => idx,
+                    None => return Vec::new(),
+                };
+                let (from, offer) = (self.transfers[idx].from, self.transfers[idx].offer);
+                if accepted {
+                    self.transfers[idx].accepted = true;
+                } else {
+                    self.transfers.remove(idx);
+                }
+                vec![Action::Send(
+                    from,
+                    Code::FileAnswered(offer, transfer_id, accepted, name),
+                )]
+            }
+            // If he sends a part of a file send it to the recipient
+            Code::FileChunk(transfer_id, data) => self.chunk(id, transfer_id, data),
+            // If he received a part of a file tell who sends it
+            Code::FileReceived(transfer_id, bytes) => {
+                let idx = match self.transfers.iter().position(|transfer| {
+                    transfer.id == transfer_id && transfer.to == id && transfer.accepted
+                }) {
+                    Some(idx) => idx,
+                    None => return Vec::new(),
+                };
+                let from = self.transfers[idx].from;
+                // He can't have received what wasn't sent to him
+                if bytes > self.transfers[idx].sent {
+                    let transfer = self.transfers.remove(idx);
+                    let reason = "more of the file was received than sent".to_string();
+                    return vec![
+                        Action::Send(id, Code::FileCancel(transfer_id, reason.clone())),
+                        Action::Send(transfer.from, Code::FileCancel(transfer_id, reason)),
+                    ];
+                }
+                self.transfers[idx].received = bytes;
+                let mut actions = vec![Action::Send(from, Code::FileReceived(transfer_id, bytes))];
+                if bytes >= self.transfers[idx].size {
+                    let transfer = self.transfers.remove(idx);
+                    actions.push(Action::Log(format!(
+                        "  User {} received the file {}",
+                        name, transfer.file
+                    )));
+                }
+                actions
+            }
+            // If he stops a file tell it to the other side
+            Code::FileCancel(transfer_id, reason) => {
+                let idx = match self.transfers.iter().position(|transfer| {
+                    transfer.id == transfer_id && (transfer.from == id || transfer.to == id)
+                }) {
+                    Some(idx) => idx,
+                    None => return Vec::new(),
+                };
+                let transfer = self.transfers.remove(idx);
+                let other = if transfer.from == id {
+                    transfer.to
+                } else {
+                    transfer.from
+                };
+                vec![Action::Send(other, Code::FileCancel(transfer_id, reason))]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// The member `id` sent a part of the file of `transfer_id`
+    fn chunk(&mut self, id: Id, transfer_id: u64, data: Vec<u8>) -> Vec<Action> {
+        let idx = match self.transfers.iter().position(|transfer| {
+            transfer.id == transfer_id && transfer.from == id && transfer.accepted
+        }) {
+            Some(idx) => idx,
+            // It could have been cancelled meanwhile
+            None => return Vec::new(),
+        };
+        let transfer = &mut self.transfers[idx];
+        let sent = transfer.sent + data.len() as u64;
+        // The file can't be longer than what was offered, and the parts that
+        // didn't arrive yet can't fill the room
+        let reason = if data.len() > protocol::CHUNK_SIZE || sent > transfer.size {
+            Some("the file is longer than it should be")
+        } else if sent.saturating_sub(transfer.received)
+            > protocol::FILE_WINDOW * protocol::CHUNK_SIZE as u64
+        {
+            Some("the file is sent too fast")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            let transfer = self.transfers.remove(idx);
+            return vec![
+                Action::Send(id, Code::FileCancel(transfer_id, reason.to_string())),
+                Action::Send(
+                    transfer.to,
+                    Code::FileCancel(transfer_id, reason.to_string()),
+                ),
+            ];
+        }
+        transfer.sent = sent;
+        vec![Action::Send(
+            transfer.to,
+            Code::FileChunk(transfer_id, data),
+        )]
+    }
+
+    /// Whether the member `id` knows STAMPED
+    fn stamps(&self, id: Id) -> bool {
+        self.members
+            .iter()
+            .any(|member| member.id == id && member.capabilities & protocol::CAP_IDS != 0)
+    }
+
+    /// The member `id` called `name` received the text `text_id`
+    fn acknowledged(&mut self, id: Id, name: String, text_id: u64) -> Vec<Action> {
+        // Only who got the id can acknowledge it, once
+        let recipients = match self
+            .recipients
+            .iter_mut()
+            .find(|(other, _)| *other == text_id)
+        {
+            Some((_, recipients)) => recipients,
+            None => return Vec::new(),
+        };
+        match recipients.iter().position(|recipient| *recipient == id) {
+            Some(pos) => recipients.swap_remove(pos),
+            None => return Vec::new(),
+        };
+        // The room doesn't remember who sent the older texts
+        let sender = self
+            .senders
+            .iter()
+            .find(|(other, _)| *other == text_id)
+            .map(|(_, sender)| *sender);
+        match sender {
+            Some(sender) if self.stamps(sender) => {
+                vec![Action::Send(sender, Code::Delivered(text_id, name))]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// The member `id` called `old` wants to be called `new`
+    fn rename(&mut self, id: Id, old: String, new: String) -> Vec<Action> {
+        let idx = match self.members.iter().position(|member| member.id == id) {
+            Some(idx) => idx,
+            None => return Vec::new(),
+        };
+        // Check if there is noone else with that name, like when joining
+        if self.taken(&new)
+            || self.bans.is_banned(Some(&new), self.members[idx].addr.ip())
+            || self
+                .accounts
+                .as_ref()
+                .is_some_and(|accounts| accounts.get(&new).is_some())
+        {
+            // Else tell him to use another name
+            return vec![Action::Send(id, Code::AlreadyHere)];
+        }
+        self.members[idx].name = Some(new.clone());
+        // The new name is not registered
+        self.members[idx].verified = false;
+        // Changing name doesn't end a mute
+        for (muted, _) in &mut self.mutes {
+            if *muted == old {
+                *muted = new.clone();
+            }
+        }
+        let mut actions = vec![Action::Log(format!("  User {} is now called {}", old, new))];
+        actions.extend(
+            self.members
+                .iter()
+                .filter(|member| member.name.is_some())
+                .map(|member| {
+                    if member.capabilities & protocol::CAP_RENAME != 0 {
+                        Action::Send(member.id, Code::Renamed(old.clone(), new.clone()))
+                    } else {
+                        Action::Send(
+                            member.id,
+                            Code::MessageTo(format!("User {} is now called {}", old, new)),
+                        )
+                    }
+                }),
+        );
+        actions
+    }
+
+    /// The member `id` called `name` wants to protect his name with the
+    /// password or the public key in `secret`
+    fn register(&mut self, id: Id, name: String, method: u8, secret: Vec<u8>) -> Vec<Action> {
+        let idx = match self.members.iter().position(|member| member.id == id) {
+            Some(idx) => idx,
+            None => return Vec::new(),
+        };
+        let accounts = match &mut self.accounts {
+            Some(accounts) => accounts,
+            None => {
+                let text = "This room doesn't keep accounts!".to_string();
+                return vec![Action::Send(id, Code::MessageTo(text))];
+            }
+        };
+        // Only who proved that the name is his can change what protects it
+        if accounts.get(&name).is_some() && !self.members[idx].verified {
+            let text = format!("The name {} is registered by someone else!", name);
+            return vec![Action::Send(id, Code::MessageTo(text))];
+        }
+        // The password was sent in plaintext, someone else could have read it
+        if method == protocol::AUTH_PASSWORD
+            && self.members[idx].capabilities & protocol::CAP_TLS == 0
+        {
+            let text = "Register with a password only on encrypted connections!".to_string();
+            return vec![Action::Send(id, Code::MessageTo(text))];
+        }
+        let credential = match method {
+            protocol::AUTH_PASSWORD => match String::from_utf8(secret) {
+                Ok(password) => Credential::password(&password),
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Chattest accounts error: the password is not valid UTF-8!",
+                )),
+            },
+            protocol::AUTH_KEY => Credential::key(secret),
+            method => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Chattest accounts error: method {} not supported!", method),
+            )),
+        };
+        if let Err(error) =
+            credential.and_then(|credential| accounts.register(name.clone(), credential))
+        {
+            let text = format!("Couldn't register the name: {}", error);
+            return vec![
+                Action::Send(id, Code::MessageTo(text)),
+                Action::Log(format!("  Couldn't register {}: {}", name, error)),
+            ];
+        }
+        self.members[idx].verified = true;
+        let mut actions = vec![Action::Log(format!("  User {} registered his name", name))];
+        if self.members[idx].capabilities & protocol::CAP_ACCOUNTS == 0 {
+            let text = "Your name is registered!".to_string();
+            actions.push(Action::Send(id, Code::MessageTo(text)));
+        }
+        actions.extend(self.verified(&name));
+        actions
+    }
+
+    /// For how many seconds the member called `name` can't talk
+    fn muted(&mut self, name: &str) -> Option<u64> {
+        let now = history::now();
+        // Forget the mutes that ended
+        self.mutes.retain(|(_, until)| *until > now);
+        self.mutes
+            .iter()
+            .find(|(muted, _)| muted == name)
+            .map(|(_, until)| match *until {
+                u64::MAX => protocol::MUTED_FOREVER,
+                until => until - now,
+            })
+    }
+
+    /// Sends `code` to the member `id`, or tells him with a text if he
+    /// doesn't know the moderation messages
+    fn notify(&self, id: Id, code: Code) -> Action {
+        let member = match self.members.iter().find(|member| member.id == id) {
+            Some(member) => member,
+            None => return Action::Send(id, code),
+        };
+        if member.capabilities & protocol::CAP_MODERATION != 0 {
+            return Action::Send(id, code);
+        }
+        let text = match code {
+            Code::Kicked => "You were kicked from the room!".to_string(),
+            Code::Banned => "You were banned from the room!".to_string(),
+            Code::Muted(0) => "You can talk again!".to_string(),
+            Code::Muted(protocol::MUTED_FOREVER) => "You are muted!".to_string(),
+            Code::Muted(seconds) => format!("You are muted for {} more seconds!", seconds),
+            code => return Action::Send(id, code),
+        };
+        Action::Send(id, Code::MessageTo(text))
+    }
+
+    /// Removes the member at `idx` telling him why with `code`, the others
+    /// are told that he `what` (like "was kicked")
+    fn remove(&mut self, idx: usize, code: Code, what: &str) -> Vec<Action> {
+        let id = self.members[idx].id;
+        let mut actions = vec![self.notify(id, code), Action::Close(id)];
+        let member = self.members.remove(idx);
+        actions.extend(self.forget(id));
+        // Nobody knew about him if he didn't join
+        if let Some(name) = member.name {
+            actions.extend(self.announce(
+                None,
+                || Code::UserLeft(name.clone()),
+                format!("User {} {}!", name, what),
+            ));
+            actions.push(Action::Log(format!("  User {} {}!", name, what)));
+        }
+        // He must not be able to read what is said from now on
+        if member.public_key.is_some() {
+            actions.extend(self.rekey());
+        }
+        actions
+    }
+
+    /// The admin of the room wants to moderate it
+    pub fn moderate(&mut self, command: Moderation) -> Vec<Action> {
+        match command {
+            Moderation::Kick(name) => match self.find(&name) {
+                Some(idx) => self.remove(idx, Code::Kicked, "was kicked"),
+                // He can't come back if he lost the connection
+                None => match self.away.iter().position(|away| away.name == name) {
+                    Some(pos) => self.release(pos, "was kicked"),
+                    None => vec![Action::Log(format!("  There is no user called {}!", name))],
+                },
+            },
+            Moderation::Ban(target) => {
+                let ban = Ban::parse(&target);
+                let mut actions = match self.bans.add(ban.clone()) {
+                    Ok(true) => vec![Action::Log(format!("  {} is banned", target))],
+                    Ok(false) => vec![Action::Log(format!("  {} is already banned", target))],
+                    Err(error) => vec![Action::Log(format!("  Couldn't save the bans: {}", error))],
+                };
+                // Remove whoever is banned now
+                while let Some(idx) = self
+                    .members
+                    .iter()
+                    .position(|member| ban.applies(member.name.as_deref(), member.addr.ip()))
+                {
+                    actions.extend(self.remove(idx, Code::Banned, "was banned"));
+                }
+                while let Some(pos) = self
+                    .away
+                    .iter()
+                    .position(|away| ban.applies(Some(&away.name), away.addr.ip()))
+                {
+                    actions.extend(self.release(pos, "was banned"));
+                }
+                actions
+            }
+            Moderation::Unban(target) => match self.bans.remove(&Ban::parse(&target)) {
+                Ok(true) => vec![Action::Log(format!("  {} is not banned anymore", target))],
+                Ok(false) => vec![Action::Log(format!("  {} is not banned", target))],
+                Err(error) => vec![Action::Log(format!("  Couldn't save the bans: {}", error))],
+            },
+            Moderation::Mute(name, period) => {
+                let id = match self.find(&name) {
+                    Some(idx) => self.members[idx].id,
+                    None => {
+                        return vec![Action::Log(format!("  There is no user called {}!", name))]
+                    }
+                };
+                let until = match period {
+                    Some(period) => history::now().saturating_add(period.as_secs()),
+                    None => u64::MAX,
+                };
+                self.mutes.retain(|(muted, _)| *muted != name);
+                self.mutes.push((name.clone(), until));
+                let seconds = self.muted(&name).unwrap_or(0);
+                vec![
+                    self.notify(id, Code::Muted(seconds)),
+                    Action::Log(format!("  User {} is muted", name)),
+                ]
+            }
+            Moderation::Unmute(name) => {
+                let len = self.mutes.len();
+                self.mutes.retain(|(muted, _)| *muted != name);
+                if self.mutes.len() == len {
+                    return vec![Action::Log(format!("  User {} is not muted", name))];
+                }
+                let mut actions = vec![Action::Log(format!("  User {} can talk again", name))];
+                if let Some(idx) = self.find(&name) {
+                    actions.push(self.notify(self.members[idx].id, Code::Muted(0)));
+                }
+                actions
+            }
+            Moderation::Edit(text_id, text) => self.revise(None, text_id, Change::Edited, text),
+            Moderation::Delete(text_id) => {
+                self.revise(None, text_id, Change::Deleted, String::new())
+            }
+        }
+    }
+
+    /// Position of the member that joined with `name`
+    fn find(&self, name: &str) -> Option<usize> {
+        self.members
+            .iter()
+            .position(|member| member.name.as_deref() == Some(name))
+    }
+}
@@ -0,0 +1,378 @@
+use crate::{accounts, bans, history, host, lobby, protocol, room};
+use std::io;
+use std::net::TcpListener;
+use std::time::Duration;
+
+const USAGE: &str = "Usage: chattest serve [OPTIONS]
+
+Hosts one or more rooms without the user interface
+
+Options:
+    --bind ADDR    address to listen on, the port can follow it like in
+                   0.0.0.0:7357 or [::]:7357 (default: 0.0.0.0)
+    --port PORT    port to listen on if ADDR doesn't say it (default: 7357)
+    --room NAME    name of the room (default: Chattest), repeat it to host
+                   more rooms: the options that follow it are only for that
+                   room, the ones before the first --room are for all of them
+    --admin NAME   name of the admin of the room (default: server)
+    --history FILE keep the messages in a log file
+    --history-db FILE
+                   keep the messages in an SQLite database
+    --backlog N    send the last N messages to the clients that join (default: 20)
+    --backlog Tm   send the messages of the last T minutes (or Th for hours)
+    --password PASS
+                   the clients must know PASS to join (it can also be set
+                   with the CHATTEST_PASSWORD environment variable)
+    --bans FILE    file with the banned names and addresses
+                   (default: ~/.chattest/bans/ADMIN/ROOM.txt)
+    --accounts FILE
+                   let the clients register their names in FILE
+    --max-file-size SIZE
+                   largest file that the clients can send, in bytes or
+                   with K, M or G (default: 16M)
+    --grace T      keep the name of who loses the connection for T seconds
+                   (or Tm, Th) so that he can come back, 0s doesn't let
+                   the clients resume their session (default: 60s)
+    --e2e          the clients must encrypt the texts end-to-end, so that
+                   this room can't read them
+    --tls          let the clients encrypt the connection, the certificate
+                   is made the first time (needs the tls feature)
+    --cert FILE    PEM file with the certificate for TLS
+                   (default: ~/.chattest/tls/cert.pem)
+    --key FILE     PEM file with the private key of the certificate
+                   (default: ~/.chattest/tls/key.pem)
+    --help         print this message";
+
+/// Settings of the headless rooms
+struct Options {
+    bind: String,
+    port: u16,
+    rooms: Vec<RoomOptions>,
+    #[cfg(feature = "tls")]
+    tls: Option<Tls>,
+}
+
+/// Settings of one of the rooms
+#[derive(Clone)]
+struct RoomOptions {
+    room: String,
+    admin: String,
+    history: Option<History>,
+    backlog: Option<room::Backlog>,
+    bans: Option<String>,
+    password: Option<String>,
+    accounts: Option<String>,
+    max_file_size: u64,
+    grace: Duration,
+    e2e: bool,
+}
+
+/// Where the certificate for TLS and its key are
+#[cfg(feature = "tls")]
+#[derive(Default)]
+struct Tls {
+    cert: Option<String>,
+    key: Option<String>,
+}
+
+/// Where the messages of the room are kept
+#[derive(Clone, PartialEq)]
+enum History {
+    LogFile(String),
+    #[cfg(feature = "sqlite")]
+    Sqlite(String),
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Options {
+            bind: "0.0.0.0".to_string(),
+            port: protocol::PORT,
+            rooms: Vec::new(),
+            #[cfg(feature = "tls")]
+            tls: None,
+        };
+        // What is given before the first --room is for every room
+        let mut every = RoomOptions {
+            room: "Chattest".to_string(),
+            admin: "server".to_string(),
+            history: None,
+            backlog: None,
+            bans: None,
+            // Other users can't see the environment like the arguments
+            password: std::env::var("CHATTEST_PASSWORD").ok(),
+            accounts: None,
+            max_file_size: room::MAX_FILE_SIZE,
+            grace: room::GRACE,
+            e2e: false,
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            // Every option is followed by its value
+            let mut value = || {
+                args.next()
+                    .cloned()
+                    .ok_or_else(|| format!("Missing value for {}", arg))
+            };
+            let room = options.rooms.last_mut().unwrap_or(&mut every);
+            match arg.as_str() {
+                "--bind" => options.bind = value()?,
+                "--port" => {
+                    let port = value()?;
+                    options.port = port
+                        .parse()
+                        .map_err(|_| format!("Invalid port: {}", port))?;
+                }
+                "--room" => {
+                    let name = value()?;
+                    options.rooms.push(RoomOptions {
+                        room: name,
+                        ..every.clone()
+                    });
+                }
+                "--admin" => room.admin = value()?,
+                "--history" => room.history = Some(History::LogFile(value()?)),
+                "--backlog" => {
+                    let backlog = value()?;
+                    room.backlog = Some(
+                        parse_backlog(&backlog)
+                            .ok_or_else(|| format!("Invalid backlog: {}", backlog))?,
+                    );
+                }
+                "--bans" => room.bans = Some(value()?),
+                "--password" => room.password = Some(value()?),
+                "--accounts" => room.accounts = Some(value()?),
+                "--max-file-size" => {
+                    let size = value()?;
+                    room.max_file_size =
+                        parse_size(&size).ok_or_else(|| format!("Invalid size: {}", size))?;
+                }
+                "--grace" => {
+                    let grace = value()?;
+                    room.grace = parse_duration(&grace)
+                        .ok_or_else(|| format!("Invalid grace period: {}", grace))?;
+                }
+                "--e2e" => room.e2e = true,
+                #[cfg(feature = "tls")]
+                "--tls" => {
+                    options.tls.get_or_insert_with(Tls::default);
+                }
+                #[cfg(feature = "tls")]
+                "--cert" => options.tls.get_or_insert_with(Tls::default).cert = Some(value()?),
+                #[cfg(feature = "tls")]
+                "--key" => options.tls.get_or_insert_with(Tls::default).key = Some(value()?),
+                #[cfg(not(feature = "tls"))]
+                "--tls" | "--cert" | "--key" => {
+                    return Err("This program was built without the tls feature".to_string())
+                }
+                #[cfg(feature = "sqlite")]
+                "--history-db" => room.history = Some(History::Sqlite(value()?)),
+                #[cfg(not(feature = "sqlite"))]
+                "--history-db" => {
+                    return Err("This program was built without the sqlite feature".to_string())
+                }
+                _ => return Err(format!("Unknown option: {}", arg)),
+            }
+        }
+        if options.rooms.is_empty() {
+            options.rooms.push(every);
+        }
+        for (idx, room) in options.rooms.iter().enumerate() {
+            if room.room.is_empty() || room.admin.is_empty() {
+                return Err("The names of the room and of the admin can't be empty".to_string());
+            }
+            // The rooms can't write in the same files
+            for other in &options.rooms[..idx] {
+                if other.room == room.room {
+                    return Err(format!("There are two rooms called {}", room.room));
+                }
+                if other.history.is_some() && other.history == room.history {
+                    return Err(format!(
+                        "The rooms {} and {} can't keep the messages in the same file",
+                        other.room, room.room
+                    ));
+                }
+                if other.bans.is_some() && other.bans == room.bans {
+                    return Err(format!(
+                        "The rooms {} and {} can't keep the bans in the same file",
+                        other.room, room.room
+                    ));
+                }
+                if other.accounts.is_some() && other.accounts == room.accounts {
+                    return Err(format!(
+                        "The rooms {} and {} can't keep the accounts in the same file",
+                        other.room, room.room
+                    ));
+                }
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Parses `N` as the last N messages and `Tm` or `Th` as the messages of
+/// the last T minutes or hours
+fn parse_backlog(val: &str) -> Option<room::Backlog> {
+    match val.parse() {
+        Ok(count) => Some(room::Backlog::Last(count)),
+        Err(_) => parse_duration(val).map(room::Backlog::Within),
+    }
+}
+
+/// Creates the room, loading its history if it has one
+fn load_room(name: String, admin: String, history: Option<History>) -> io::Result<room::Room> {
+    let store: Box<dyn history::Store> = match history {
+        None => return Ok(room::Room::new(name, admin)),
+        Some(History::LogFile(path)) => Box::new(history::LogFile::open(path)?),
+        #[cfg(feature = "sqlite")]
+        Some(History::Sqlite(path)) => Box::new(history::Sqlite::open(path)?),
+    };
+    let room = room::Room::with_store(name, admin, store)?;
+    println!(
+        "Loaded {} messages in {}",
+        room.history().len(),
+        room.name()
+    );
+    Ok(room)
+}
+
+/// Creates the room with its settings, returns what went wrong if it can't
+fn open_room(options: RoomOptions) -> Result<room::Room, String> {
+    let bans_path = match options.bans {
+        Some(path) => path.into(),
+        None => bans::default_path(&options.admin, &options.room),
+    };
+    let name = options.room.clone();
+    let mut room = load_room(options.room, options.admin, options.history)
+        .map_err(|error| format!("Couldn't load the history of {}: {}", name, error))?;
+    if let Some(backlog) = options.backlog {
+        room.set_backlog(backlog);
+    }
+    room.set_password(options.password.filter(|password| !password.is_empty()));
+    room.set_e2e(options.e2e);
+    room.set_max_file_size(options.max_file_size);
+    room.set_grace(options.grace);
+    let bans = bans::BanList::open(&bans_path)
+        .map_err(|error| format!("Couldn't load the bans of {}: {}", name, error))?;
+    room.set_bans(bans);
+    // Nobody can register if there is nowhere to keep the accounts
+    if let Some(path) = options.accounts {
+        let accounts = accounts::Accounts::open(&path)
+            .map_err(|error| format!("Couldn't load the accounts of {}: {}", name, error))?;
+        room.set_accounts(accounts);
+    }
+    Ok(room)
+}
+
+/// Starts the rooms letting the clients use TLS
+#[cfg(feature = "tls")]
+fn start_tls(listener: TcpListener, lobby: lobby::Lobby, tls: Tls) -> io::Result<host::Host> {
+    use chattest::tls;
+    let (default_cert, default_key) = tls::default_paths();
+    let cert = tls.cert.map_or(default_cert, Into::into);
+    let key = tls.key.map_or(default_key, Into::into);
+    let config = tls::server_config(&cert, &key)?;
+    println!("TLS fingerprint: {}", tls::file_fingerprint(&cert)?);
+    host::Host::start_tls(listener, lobby, config)
+}
+
+/// Runs the `serve` subcommand with the arguments that follow it,
+/// returns the exit code of the process
+pub fn main(args: &[String]) -> i32 {
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", USAGE);
+        return 0;
+    }
+    let options = match Options::parse(args) {
+        Ok(options) => options,
+        Err(error) => {
+            eprintln!("{}\n\n{}", error, USAGE);
+            return 2;
+        }
+    };
+
+    let (host, port) = match protocol::parse_addr(&options.bind, options.port) {
+        Ok(addr) => addr,
+        Err(error) => {
+            eprintln!("{}\n\n{}", error, USAGE);
+            return 2;
+        }
+    };
+    let listener = TcpListener::bind((host.as_str(), port))
+        .and_then(|listener| Ok((listener.local_addr()?, listener)));
+    let (addr, listener) = match listener {
+        Ok(listener) => listener,
+        Err(error) => {
+            // IPv6 addresses are written between brackets before the port
+            let host = if host.contains(':') {
+                format!("[{}]", host)
+            } else {
+                host
+            };
+            eprintln!("Couldn't listen on {}:{}: {}", host, port, error);
+            return 1;
+        }
+    };
+    for room in &options.rooms {
+        println!(
+            "Room {} (admin: {}) listening on {}",
+            room.room, room.admin, addr
+        );
+    }
+
+    let mut rooms = Vec::new();
+    for room in options.rooms {
+        match open_room(room) {
+            Ok(room) => rooms.push(room),
+            Err(error) => {
+                eprintln!("{}", error);
+                return 1;
+            }
+        }
+    }
+    let lobby = lobby::Lobby::new(rooms);
+    #[cfg(feature = "tls")]
+    let host = match options.tls {
+        Some(tls) => start_tls(listener, lobby, tls),
+        None => host::Host::start(listener, lobby),
+    };
+    #[cfg(not(feature = "tls"))]
+    let host = host::Host::start(listener, lobby);
+    let host = match host {
+        Ok(host) => host,
+        Err(error) => {
+            eprintln!("Couldn't start the room: {}", error);
+            return 1;
+        }
+    };
+
+    // Print the events of the room as they happen
+    for line in host.events().iter() {
+        println!("{}", line.trim_start());
+    }
+    1
+}
+
+/// Parses a period of time like `30s`, `10m` or `2h`
+pub fn parse_duration(val: &str) -> Option<Duration> {
+    let (number, unit) = val.split_at(val.len().checked_sub(1)?);
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => return None,
+    };
+    let seconds = number.parse::<u64>().ok()?.checked_mul(unit)?;
+    Some(Duration::from_secs(seconds))
+}
+
+/// Parses a size in bytes like `512`, `64K`, `16M` or `1G`
+pub fn parse_size(val: &str) -> Option<u64> {
+    let (number, unit) = match val.char_indices().last()? {
+        (idx, 'K' | 'k') => (&val[..idx], 1 << 10),
+        (idx, 'M' | 'm') => (&val[..idx], 1 << 20),
+        (idx, 'G' | 'g') => (&val[..idx], 1 << 30),
+        _ => (val, 1),
+    };
+    number.parse::<u64>().ok()?.checked_mul(unit)
+}
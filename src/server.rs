@@ -1,110 +1,274 @@
 use crate::*;
-use std::io::ErrorKind;
 use std::net::TcpListener;
-use std::sync::{Arc, RwLock};
-use std::thread;
 
 pub fn chat(win: &Window, name: String) -> bool {
     // Get the name of the room from the user
     win.printw("  What's the name of this room?\n  [press ESC to return to menu]\n > ");
-    let room = Arc::new(match get_string(&win) {
+    let room = match get_string(win) {
         (string, false) => string,
         (_, true) => return false,
-    });
+    };
+    win.mv(0, 0);
+    win.clrtobot();
+    // Get the password of the room, if it has one
+    win.printw("  Password of the room\n  [leave it empty for no password]\n > ");
+    let password = match get_secret(win) {
+        (string, false) => Some(string).filter(|string| !string.is_empty()),
+        (_, true) => return false,
+    };
+    win.mv(0, 0);
+    win.clrtobot();
+    // Ask if the clients must hide the texts from this program
+    win.printw("  Encrypt the messages end-to-end? [y/N]\n  [nobody, not even you, will see them here]\n > ");
+    let e2e = match get_string(win) {
+        (answer, false) => answer.eq_ignore_ascii_case("y"),
+        (_, true) => return false,
+    };
+    win.mv(0, 0);
+    win.clrtobot();
 
-    let arc_name = Arc::new(name);
+    // Bind the listener where he wants, every address on port 7357 if he
+    // doesn't say
+    let listener = loop {
+        win.printw(format!(
+            "  Where should the room listen?\n  [leave it empty for 0.0.0.0:{}]\n > ",
+            protocol::PORT
+        ));
+        let addr = match get_string(win) {
+            (addr, false) if addr.is_empty() => format!("0.0.0.0:{}", protocol::PORT),
+            (addr, false) => addr,
+            (_, true) => return false,
+        };
+        win.mv(0, 0);
+        win.clrtobot();
+        let listener = protocol::parse_addr(&addr, protocol::PORT)
+            .and_then(|(host, port)| TcpListener::bind((host.as_str(), port)));
+        match listener {
+            Ok(listener) => break listener,
+            // The port could be used by another program
+            Err(error) => {
+                win.printw(format!(
+                    "  Couldn't listen on {}!\n  {}\n  [press any key to try again]",
+                    addr, error
+                ));
+                win.getch();
+                win.mv(0, 0);
+                win.clrtobot();
+            }
+        }
+    };
 
     // Print out the information of the room
+    let addr = listener
+        .local_addr()
+        .map_or_else(|_| "?".to_string(), |addr| addr.to_string());
     win.mvprintw(
         0,
         0,
-        format!("  Room name: {}\n  Admin: {}\n", room, *arc_name),
+        format!(
+            "  Room name: {}\n  Admin: {}\n  Address: {}\n",
+            room, name, addr
+        ),
     );
     // Remove the previous text
     win.clrtobot();
     win.refresh();
 
-    // Bind the listener to the port 7357
-    let listener = Arc::new(TcpListener::bind("0.0.0.0:7357").unwrap());
-
-    // Vector that stores all the incoming messages
-    let messages = Arc::new(RwLock::new(Vec::new()));
-
-    // Vector of the connected clients and their names
-    let clients = Arc::new(RwLock::new(Vec::new()));
-
-    accept_thread(&listener, &messages, &clients, &room, &arc_name);
-    clients_thread(&messages, &clients);
-
-    win.nodelay(true);
-    win.mvprintw(LAST, 0, " > ");
+    // Messages shown in the room
+    let mut messages = Messages::new();
+    // Reload what was said the last time this room was hosted
+    let bans = bans::BanList::open(bans::default_path(&name, &room));
+    let accounts = accounts::Accounts::open(accounts::default_path(&name, &room));
+    let mut room = history::LogFile::open(history::default_path(&name, &room))
+        .and_then(|store| room::Room::with_store(room.clone(), name.clone(), Box::new(store)))
+        .unwrap_or_else(|error| {
+            messages.push(format!("  History error: {}", error));
+            room::Room::new(room, name.clone())
+        });
+    room.set_password(password);
+    room.set_e2e(e2e);
+    // Keep out who was banned the last time
+    match bans {
+        Ok(bans) => room.set_bans(bans),
+        Err(error) => {
+            messages.push(format!("  Bans error: {}", error));
+        }
+    }
+    // And who registered his name
+    match accounts {
+        Ok(accounts) => room.set_accounts(accounts),
+        Err(error) => {
+            messages.push(format!("  Accounts error: {}", error));
+        }
+    }
+    for entry in room.history() {
+        // The id is needed to edit or delete the message
+        let id = match entry.id {
+            0 => String::new(),
+            id => format!("#{} ", id),
+        };
+        if entry.sender == name {
+            messages.push(format!("  {}{}", id, entry.text));
+        } else {
+            messages.push(format!("  {}{}> {}", id, entry.sender, entry.text));
+        }
+    }
+    // The commands of the admin are for this room
+    let title = room.name().to_string();
+    // Let the clients encrypt the connection if this program can do it
+    #[cfg(feature = "tls")]
+    let host = {
+        let (cert, key) = chattest::tls::default_paths();
+        match chattest::tls::server_config(&cert, &key) {
+            Ok(config) => {
+                if let Ok(fingerprint) = chattest::tls::file_fingerprint(&cert) {
+                    messages.push(format!("  TLS fingerprint: {}", fingerprint));
+                }
+                host::Host::start_tls(listener, room, config)
+            }
+            Err(error) => {
+                messages.push(format!("  TLS is off: {}", error));
+                host::Host::start(listener, room)
+            }
+        }
+    };
+    #[cfg(not(feature = "tls"))]
+    let host = host::Host::start(listener, room);
+    let host = match host {
+        Ok(host) => host,
+        Err(error) => {
+            win.mvprintw(
+                0,
+                0,
+                format!(
+                    "  Couldn't start the room!\n  {}\n  [press any key to return to the menu]",
+                    error
+                ),
+            );
+            win.clrtobot();
+            win.getch();
+            win.mv(0, 0);
+            win.clrtobot();
+            return false;
+        }
+    };
+    messages.last();
 
-    let mut last = messages.read().unwrap().len();
-    let mut index = 0;
+    // Wait for the keys only for a while, so that new messages are shown
+    win.timeout(50);
+    messages.draw(win, "", 0);
 
     let mut string = String::new();
     let mut cursor = 0;
     loop {
-        let rmsgs = messages.read().unwrap();
-        if rmsgs.len() > last {
-            if index + 1 == last || last == 0 {
-                index = rmsgs.len() - 1;
-
-                win.mvprintw(3, 0, format!("{}", rmsgs[index]));
-                win.clrtobot();
-                win.mvprintw(LAST, 0, " > ");
-                win.printw(&string);
-                win.mv(LAST, 3 + cursor as i32);
-            }
-            last = rmsgs.len();
-            println!(
-                "New messages (last): {} (index: {}, len: {})",
-                rmsgs.last().unwrap(),
-                index,
-                last
-            );
+        let mut moved = false;
+        for event in host.events().try_iter() {
+            moved |= messages.push(event);
+        }
+        if moved {
+            messages.draw(win, &string, cursor);
             win.refresh();
         }
-        if let Some(input) = try_get_string(&win, &mut string, &mut cursor) {
+        if let Some(input) = try_get_string(win, &mut string, &mut cursor) {
             match input {
                 Input::Character('\n') if string.len() > 1 => {
-                    let mut mut_clients = clients.write().unwrap();
-                    for i in 0..mut_clients.len() {
-                        println!("Sending message {} to {}", string, mut_clients[i].1);
-                        match mut_clients[i]
-                            .0
-                            .write(chattest::Code::MessageTo(string.clone()))
-                        {
-                            Ok(()) => println!("Ok!"),
-                            Err(error) => println!("Err! {:?}", error),
+                    let sent = match command(&string) {
+                        Some(("msg", args)) => match first_word(args) {
+                            (to, text) if !text.is_empty() => {
+                                messages.push(format!("  (to {}) {}", to, text));
+                                host.whisper(&title, to.to_string(), text.to_string())
+                            }
+                            _ => {
+                                messages.push("  Usage: /msg <name> <text>".to_string());
+                                Ok(())
+                            }
+                        },
+                        Some(("kick", name)) if !name.is_empty() => {
+                            host.moderate(&title, room::Moderation::Kick(name.to_string()))
+                        }
+                        Some(("ban", target)) if !target.is_empty() => {
+                            host.moderate(&title, room::Moderation::Ban(target.to_string()))
+                        }
+                        Some(("unban", target)) if !target.is_empty() => {
+                            host.moderate(&title, room::Moderation::Unban(target.to_string()))
+                        }
+                        Some(("mute", args)) => match first_word(args) {
+                            ("", _) => {
+                                messages.push("  Usage: /mute <name> [30s|10m|2h]".to_string());
+                                Ok(())
+                            }
+                            (name, "") => host
+                                .moderate(&title, room::Moderation::Mute(name.to_string(), None)),
+                            (name, period) => match serve::parse_duration(period) {
+                                Some(period) => host.moderate(
+                                    &title,
+                                    room::Moderation::Mute(name.to_string(), Some(period)),
+                                ),
+                                None => {
+                                    messages.push(format!("  Invalid duration: {}", period));
+                                    Ok(())
+                                }
+                            },
+                        },
+                        Some(("unmute", name)) if !name.is_empty() => {
+                            host.moderate(&title, room::Moderation::Unmute(name.to_string()))
                         }
+                        Some(("edit", args)) => match first_word(args) {
+                            (id, text) if !text.is_empty() => match parse_id(id) {
+                                Some(id) => host
+                                    .moderate(&title, room::Moderation::Edit(id, text.to_string())),
+                                None => {
+                                    messages.push(format!("  Invalid id: {}", id));
+                                    Ok(())
+                                }
+                            },
+                            _ => {
+                                messages.push("  Usage: /edit <#id> <text>".to_string());
+                                Ok(())
+                            }
+                        },
+                        Some(("delete", id)) if !id.is_empty() => match parse_id(id) {
+                            Some(id) => host.moderate(&title, room::Moderation::Delete(id)),
+                            None => {
+                                messages.push(format!("  Invalid id: {}", id));
+                                Ok(())
+                            }
+                        },
+                        Some(("delete", _)) => {
+                            messages.push("  Usage: /delete <#id>".to_string());
+                            Ok(())
+                        }
+                        Some(("kick", _)) => {
+                            messages.push("  Usage: /kick <name>".to_string());
+                            Ok(())
+                        }
+                        Some(("ban" | "unban", _)) => {
+                            messages.push("  Usage: /ban|/unban <name or IP>".to_string());
+                            Ok(())
+                        }
+                        Some(("unmute", _)) => {
+                            messages.push("  Usage: /unmute <name>".to_string());
+                            Ok(())
+                        }
+                        Some((name, _)) => {
+                            messages.push(format!("  Unknown command /{}", name));
+                            Ok(())
+                        }
+                        None => host.say(&title, string.clone()),
+                    };
+                    // The thread serving the room couldn't be woken up
+                    if let Err(error) = sent {
+                        messages.push(format!("  Host error: {}", error));
                     }
-                    std::mem::drop(rmsgs);
-                    let mut wmsgs = messages.write().unwrap();
-                    wmsgs.push(format!("  {}", string));
-
                     string.clear();
                     cursor = 0;
-                    win.mv(HEIGHT - 3, 3);
-                    win.clrtobot();
+                    messages.draw(win, &string, cursor);
                 }
-                Input::KeyUp if index > 0 => {
-                    println!("UP!");
-                    index -= 1;
-                    win.mvprintw(3, 0, &rmsgs[index]);
-                    win.clrtobot();
-                    win.mvprintw(LAST, 0, " > ");
-                    win.printw(&string);
-                    win.mv(LAST, 3 + cursor as i32);
+                Input::KeyUp if messages.up() => {
+                    messages.draw(win, &string, cursor);
                 }
-                Input::KeyDown if index + 1 < last => {
-                    println!("DOWN!");
-                    index += 1;
-                    win.mvprintw(3, 0, &rmsgs[index]);
-                    win.clrtobot();
-                    win.mvprintw(LAST, 0, " > ");
-                    win.printw(&string);
-                    win.mv(LAST, 3 + cursor as i32);
+                Input::KeyDown if messages.down() => {
+                    messages.draw(win, &string, cursor);
                 }
                 _ => (),
             }
@@ -112,172 +276,3 @@ pub fn chat(win: &Window, name: String) -> bool {
         }
     }
 }
-
-fn find_string(vec: &[(chattest::NonBlockingStream, String)], val: &str) -> bool {
-    for (_, string) in vec.iter() {
-        if string == val {
-            return true;
-        }
-    }
-    false
-}
-
-fn accept_thread(
-    listener: &Arc<TcpListener>,
-    messages: &Arc<RwLock<Vec<String>>>,
-    clients: &Arc<RwLock<Vec<(chattest::NonBlockingStream, String)>>>,
-    room: &Arc<String>,
-    arc_name: &Arc<String>,
-) {
-    let messages = Arc::clone(messages);
-    let listener = Arc::clone(listener);
-    let clients = Arc::clone(clients);
-    let room = Arc::clone(room);
-    let arc_name = Arc::clone(arc_name);
-    thread::spawn(move || loop {
-        // Wait for a client to connect
-        match listener.accept() {
-            // When the client connectes:
-            Ok((stream, addr)) => {
-                // Make the stream a chattest BlockingStream
-                let mut stream = chattest::BlockingStream::new(stream);
-                loop {
-                    // Get the client name:
-                    match stream.read() {
-                        // If he sends a chattest message match the code
-                        Ok(code) => match code {
-                            // If he sends his name:
-                            chattest::Code::Name(name) => {
-                                // Check if there is noone else with that name
-                                if name != **arc_name
-                                    && !find_string(&clients.read().unwrap(), &name)
-                                {
-                                    // Tell the client the name of the room
-                                    stream
-                                        .write(chattest::Code::Welcome(
-                                            (*room).clone(),
-                                            (*arc_name).clone(),
-                                        ))
-                                        .unwrap();
-                                    println!("Connected: {}({})", name, addr);
-                                    // Comunicate the new connection:
-                                    messages
-                                        .write()
-                                        .unwrap()
-                                        .push(format!("  User connected:\n  {}({})", name, addr));
-                                    // Lock the vector of clients
-                                    let mut mut_clients = clients.write().unwrap();
-                                    // Comunicating the event to the other clients
-                                    for j in 0..mut_clients.len() {
-                                        println!(
-                                            "Sending User {} connected! to {}",
-                                            name, mut_clients[j].1
-                                        );
-                                        match mut_clients[j].0.write(chattest::Code::MessageTo(
-                                            format!("User {} connected!", name),
-                                        )) {
-                                            Ok(()) => println!("Ok!"),
-                                            Err(error) => println!("Err! {:?}", error),
-                                        }
-                                    }
-                                    // Push the new client in the list
-                                    mut_clients.push((stream.non_blocking(), name));
-                                    break;
-                                }
-                                println!("Already here: {}", name);
-                                // Else tell him to use another name
-                                stream.write(chattest::Code::AlreadyHere).unwrap();
-                            }
-                            _ => println!("Code not expected: {:?}", code),
-                        },
-                        Err(error) => println!("Read error: {}", error),
-                    }
-                }
-            }
-            Err(error) => println!("Accept error: {}", error),
-        }
-    });
-}
-
-fn clients_thread(
-    messages: &Arc<RwLock<Vec<String>>>,
-    clients: &Arc<RwLock<Vec<(chattest::NonBlockingStream, String)>>>,
-) {
-    let messages = Arc::clone(messages);
-    let clients = Arc::clone(clients);
-    thread::spawn(move || loop {
-        // Lock the clients vector
-        let mut mut_clients = clients.write().unwrap();
-
-        // For every client:
-        for i in 0..mut_clients.len() {
-            // Get the name of the client
-            let name = mut_clients[i].1.clone();
-            // Try to get his message
-            match mut_clients[i].0.try_read() {
-                // If his message arrived match the code:
-                Ok(Some(code)) => match code {
-                    // If it's a text message
-                    chattest::Code::MessageTo(text) => {
-                        println!("Recived {} from {}", text, name);
-                        // Print the message
-                        messages
-                            .write()
-                            .unwrap()
-                            .push(format!("  {}> {}", name, text));
-                        // Send the message to the other clients
-                        for j in 0..mut_clients.len() {
-                            // Exclude the current client
-                            if j != i {
-                                println!("Sending {} to {}", text, mut_clients[j].1);
-                                match mut_clients[j]
-                                    .0
-                                    .write(chattest::Code::MessageFrom(name.clone(), text.clone()))
-                                {
-                                    Ok(()) => println!("Ok!"),
-                                    Err(error) => println!("Err! {:?}", error),
-                                }
-                            }
-                        }
-                    }
-                    _ => println!("Code not expected from {}: {:?}", name, code),
-                },
-                // If the message is not complete, go to the next client
-                Ok(None) => (),
-                // If there was an error:
-                Err(error) => match error.kind() {
-                    // If the client discnnected:
-                    ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => {
-                        // Comunicating the event to the other clients
-                        for j in 0..mut_clients.len() {
-                            // Exclude the current client
-                            if j != i {
-                                println!(
-                                    "Sending User {} disconnected! to {}",
-                                    name, mut_clients[j].1
-                                );
-                                match mut_clients[j].0.write(chattest::Code::MessageTo(format!(
-                                    "User {} disconnected!",
-                                    name
-                                ))) {
-                                    Ok(()) => println!("Ok!"),
-                                    Err(error) => println!("Err! {:?}", error),
-                                }
-                            }
-                        }
-                        // Remove it from the list
-                        mut_clients.remove(i);
-                        println!("Disconnected {}", name);
-                        // Comunicate the event
-                        messages
-                            .write()
-                            .unwrap()
-                            .push(format!("  User {} disconnected!", name));
-                        break;
-                    }
-                    _ => println!("Error with client {}: {}({:?})", name, error, error.kind()),
-                },
-            }
-        }
-    });
-}
@@ -0,0 +1,198 @@
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring as provider, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, ServerConfig, SignatureScheme};
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Name written in the certificates made by `generate`, clients don't check
+/// it because they pin the certificate instead
+pub const SERVER_NAME: &str = "chattest";
+
+/// Turns an error of rustls into an `io::Error`
+pub(crate) fn tls_error(error: rustls::Error) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("Chattest TLS error: {}!", error),
+    )
+}
+
+fn pem_error(error: rustls::pki_types::pem::Error) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("Chattest TLS error: {}!", error),
+    )
+}
+
+/// SHA-256 of a certificate, written as pairs of hex digits divided by `:`
+pub fn fingerprint(cert: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, cert)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Files where the host keeps its certificate and its private key by default
+pub fn default_paths() -> (PathBuf, PathBuf) {
+    let dir = crate::data_dir().join("tls");
+    (dir.join("cert.pem"), dir.join("key.pem"))
+}
+
+/// Makes a self-signed certificate and saves it, with its key, in PEM files
+pub fn generate(cert: &Path, key: &Path) -> io::Result<()> {
+    let certified =
+        rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string(), "localhost".to_string()])
+            .map_err(io::Error::other)?;
+    for path in [cert, key] {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+    }
+    // Only the key is secret
+    fs::write(cert, certified.cert.pem())?;
+    crate::write_secret(key, certified.key_pair.serialize_pem().as_bytes())
+}
+
+/// Settings of a server that uses the certificate and the key in the PEM
+/// files `cert` and `key`, they are generated if they don't exist yet
+pub fn server_config(cert: &Path, key: &Path) -> io::Result<Arc<ServerConfig>> {
+    if !cert.exists() && !key.exists() {
+        generate(cert, key)?;
+    }
+    let certs = CertificateDer::pem_file_iter(cert)
+        .map_err(pem_error)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(pem_error)?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(pem_error)?;
+    let config = ServerConfig::builder_with_provider(Arc::new(provider::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(tls_error)?;
+    Ok(Arc::new(config))
+}
+
+/// Fingerprint of the first certificate in the PEM file `cert`
+pub fn file_fingerprint(cert: &Path) -> io::Result<String> {
+    let cert = CertificateDer::from_pem_file(cert).map_err(pem_error)?;
+    Ok(fingerprint(&cert))
+}
+
+/// The fingerprints of the servers the user already connected to, kept in a
+/// file where every line is `address fingerprint`
+pub struct KnownHosts {
+    path: PathBuf,
+}
+
+impl KnownHosts {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        KnownHosts { path: path.into() }
+    }
+
+    /// The file used by the client by default
+    pub fn default_path() -> PathBuf {
+        crate::data_dir().join("known_hosts")
+    }
+
+    /// Fingerprint saved for the server at `host`
+    pub fn get(&self, host: &str) -> io::Result<Option<String>> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        Ok(text.lines().find_map(|line| {
+            let (known, fingerprint) = line.split_once(' ')?;
+            (known == host).then(|| fingerprint.to_string())
+        }))
+    }
+
+    /// Remembers the fingerprint of the server at `host`
+    pub fn add(&self, host: &str, fingerprint: &str) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)?;
+        writeln!(file, "{} {}", host, fingerprint)
+    }
+}
+
+/// Accepts any certificate, as long as the server has its key: clients pin
+/// the fingerprint of the certificate after the handshake instead of trusting
+/// an authority
+#[derive(Debug)]
+struct AnyCertificate {
+    provider: CryptoProvider,
+}
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Settings of a client, it must check the fingerprint of the server once
+/// the handshake is done
+pub fn client_config() -> io::Result<Arc<ClientConfig>> {
+    let provider = provider::default_provider();
+    let verifier = AnyCertificate {
+        provider: provider.clone(),
+    };
+    let config = ClientConfig::builder_with_provider(Arc::new(provider))
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
@@ -1,162 +1,357 @@
+use crate::{LAST, WIDTH};
 use pancurses::*;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
-pub fn get_string(win: &Window) -> (String, bool) {
-    let mut string = String::new();
-    let y = win.get_cur_y();
-    let mut cursor = 0;
-    loop {
-        let ch = win.getch();
-        if let Some(Input::Character(ch)) = ch {
-            println!("Pressed: {}({:x})\nLength: {}", ch, ch as u32, string.len());
-            match ch {
-                '\n' => break,
-                '\u{8}' => {
-                    if cursor > 0 {
-                        if cursor < string.len() {
-                            string.remove(cursor);
-                        } else {
-                            string.pop();
-                        }
-                        win.addch('\u{8}');
-                        win.delch();
-                        cursor -= 1;
-                    }
-                }
-                '\u{1b}' => return (string, true),
-                '\u{7f}' => {
-                    win.mv(y, win.get_cur_x() - cursor as i32);
-                    win.clrtoeol();
-                    if let Some(idx) = string[0..cursor].rfind(char::is_whitespace) {
-                        let (take, divide) = string.split_at(idx);
-                        string = format!("{}{}", take, divide.split_at(cursor - take.len()).1);
-                        cursor = idx;
-                    } else {
-                        use std::iter::FromIterator;
-                        string = String::from_iter(
-                            string.split_at(cursor).1.chars().skip_while(|c| *c == ' '),
-                        );
-                        cursor = 0;
-                    }
-                    win.printw(&string);
-                    win.mv(y, win.get_cur_x() - (string.len() - cursor) as i32);
-                }
-                _ => {
-                    if ch.is_ascii() && !ch.is_ascii_control() {
-                        // if let Some(max) = max {
-                        //     if max == string.len() {
-                        //         win.mvprintw(
-                        //             y,
-                        //             0,
-                        //             format!("  Maximum number of characters is {}!", max),
-                        //         );
-                        //         noecho();
-                        //         win.getch();
-                        //         echo();
-                        //         win.mv(y, 0);
-                        //         win.clrtoeol();
-                        //         win.printw(format!(" > {}", string));
-                        //         continue;
-                        //     }
-                        // }
-                        string.insert(cursor, ch);
-                        cursor += 1;
-                        win.insch(ch);
-                        win.mv(y, win.get_cur_x() + 1);
+/// Number of columns that the text before `cursor` (a byte index) takes on
+/// the screen, wide glyphs take two columns
+pub fn cursor_x(string: &str, cursor: usize) -> i32 {
+    UnicodeWidthStr::width(&string[..cursor]) as i32
+}
+
+/// Byte index of the character that comes before `cursor`
+fn prev_boundary(string: &str, cursor: usize) -> usize {
+    string[..cursor]
+        .char_indices()
+        .next_back()
+        .map_or(0, |(idx, _)| idx)
+}
+
+/// Byte index of the character that comes after `cursor`
+fn next_boundary(string: &str, cursor: usize) -> usize {
+    string[cursor..]
+        .chars()
+        .next()
+        .map_or(cursor, |ch| cursor + ch.len_utf8())
+}
+
+/// The messages of a room, one at a time is shown above the input line
+pub struct Messages {
+    lines: Vec<String>,
+    selected: usize,
+}
+
+impl Messages {
+    pub fn new() -> Self {
+        Messages {
+            lines: Vec::new(),
+            selected: 0,
+        }
+    }
+
+    /// Adds a message, the view follows it if the last one was shown,
+    /// returns `true` if the view changed
+    pub fn push(&mut self, line: String) -> bool {
+        self.lines.push(line);
+        if self.lines.len() == 1 || self.selected + 2 == self.lines.len() {
+            self.selected = self.lines.len() - 1;
+            return true;
+        }
+        false
+    }
+
+    /// Number of messages
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Position of the message that is shown
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    /// Replaces the message at `idx`, returns `true` if it's the one shown
+    pub fn set(&mut self, idx: usize, line: String) -> bool {
+        match self.lines.get_mut(idx) {
+            Some(old) => {
+                *old = line;
+                idx == self.selected
+            }
+            None => false,
+        }
+    }
+
+    /// Moves the view to the last message
+    pub fn last(&mut self) {
+        self.selected = self.lines.len().saturating_sub(1);
+    }
+
+    /// Moves the view to the previous message, returns `true` if it moved
+    pub fn up(&mut self) -> bool {
+        if self.selected > 0 {
+            self.selected -= 1;
+            return true;
+        }
+        false
+    }
+
+    /// Moves the view to the next message, returns `true` if it moved
+    pub fn down(&mut self) -> bool {
+        if self.selected + 1 < self.lines.len() {
+            self.selected += 1;
+            return true;
+        }
+        false
+    }
+
+    /// Shows the selected message and the input line with its `cursor`
+    pub fn draw(&self, win: &Window, string: &str, cursor: usize) {
+        win.mv(3, 0);
+        if let Some(line) = self.lines.get(self.selected) {
+            win.printw(line);
+        }
+        win.clrtobot();
+        win.mvprintw(LAST, 0, " > ");
+        win.printw(string);
+        win.mv(LAST, 3 + cursor_x(string, cursor));
+    }
+}
+
+/// The users in a room, shown in a status line above the messages
+pub struct Users {
+    admin: String,
+    /// `None` if the server doesn't send the list of the users
+    names: Option<Vec<String>>,
+    /// Users that proved their name is theirs
+    verified: Vec<String>,
+    shown: bool,
+}
+
+impl Users {
+    pub fn new(admin: String) -> Self {
+        Users {
+            admin,
+            names: None,
+            verified: Vec::new(),
+            shown: true,
+        }
+    }
+
+    /// Replaces the list with the one sent by the server, it sends again who
+    /// is verified after it
+    pub fn set(&mut self, names: Vec<String>) {
+        self.names = Some(names);
+        self.verified.clear();
+    }
+
+    pub fn joined(&mut self, name: String) {
+        if let Some(names) = &mut self.names {
+            names.push(name);
+        }
+    }
+
+    pub fn left(&mut self, name: &str) {
+        if let Some(names) = &mut self.names {
+            names.retain(|other| other != name);
+        }
+        self.verified.retain(|other| other != name);
+    }
+
+    pub fn verified(&mut self, name: String) {
+        if !self.verified.contains(&name) {
+            self.verified.push(name);
+        }
+    }
+
+    /// The new name is not verified, even if the old one was
+    pub fn renamed(&mut self, old: &str, new: String) {
+        self.verified.retain(|other| other != old);
+        if let Some(names) = &mut self.names {
+            if let Some(name) = names.iter_mut().find(|name| *name == old) {
+                *name = new;
+            }
+        }
+    }
+
+    /// Shows or hides the status line
+    pub fn toggle(&mut self) {
+        self.shown = !self.shown;
+    }
+
+    /// Draws the status line, the admin is marked with a `*` and the
+    /// verified users with a `+`
+    pub fn draw(&self, win: &Window) {
+        win.mv(2, 0);
+        win.clrtoeol();
+        if !self.shown {
+            return;
+        }
+        let line = match &self.names {
+            Some(names) => {
+                let mut line = format!(" Users: {}*", self.admin);
+                for name in names {
+                    line.push_str(", ");
+                    line.push_str(name);
+                    if self.verified.contains(name) {
+                        line.push('+');
                     }
                 }
+                line
             }
-        } else if let Some(input) = ch {
-            println!("Pressed: {:?}\nLength: {}", input, string.len());
-            match input {
-                Input::KeyLeft if cursor > 0 => {
-                    cursor -= 1;
-                    win.mv(y, win.get_cur_x() - 1);
-                }
-                Input::KeyRight if cursor < string.len() => {
-                    cursor += 1;
-                    win.mv(y, win.get_cur_x() + 1);
-                }
-                Input::KeyDC if cursor < string.len() => {
-                    string.remove(cursor);
-                    win.delch();
-                }
-                Input::KeyHome => {
-                    win.mv(y, win.get_cur_x() - cursor as i32);
-                    cursor = 0;
-                }
-                _ => (),
+            None => " Users: unknown".to_string(),
+        };
+        // Keep it on one line
+        let mut width = 0;
+        let end = line
+            .char_indices()
+            .find(|(_, ch)| {
+                width += ch.width().unwrap_or(0);
+                width >= WIDTH as usize
+            })
+            .map_or(line.len(), |(idx, _)| idx);
+        win.printw(&line[..end]);
+    }
+}
+
+/// Lets the user pick one of `items` with Up, Down and Enter, like the menu
+/// of the program, they are shown from the line `top`. Returns `None` if
+/// ESC is pressed
+pub fn choose(win: &Window, top: i32, items: &[String]) -> Option<usize> {
+    let rows = (LAST - top + 1).max(1) as usize;
+    let mut selected: usize = 0;
+    loop {
+        // Keep the selected item on the screen
+        let first = selected.saturating_sub(rows - 1);
+        win.mv(top, 0);
+        win.clrtobot();
+        for (idx, item) in items.iter().enumerate().skip(first).take(rows) {
+            let line = if idx == selected {
+                format!("> {} <", item)
+            } else {
+                item.clone()
+            };
+            let x = (WIDTH - UnicodeWidthStr::width(line.as_str()) as i32) / 2;
+            win.mvprintw(top + (idx - first) as i32, x.max(0), line);
+        }
+        win.refresh();
+        match win.getch() {
+            Some(Input::KeyEnter | Input::Character('\n')) => return Some(selected),
+            Some(Input::Character('\u{1b}')) => return None,
+            Some(Input::KeyUp) => {
+                selected = selected.checked_sub(1).unwrap_or(items.len() - 1);
+            }
+            Some(Input::KeyDown) => selected = (selected + 1) % items.len(),
+            _ => (),
+        }
+    }
+}
+
+/// Splits a line like `/msg name text` in the command (`msg`) and what
+/// follows it, returns `None` if the line is not a command
+pub fn command(line: &str) -> Option<(&str, &str)> {
+    let line = line.strip_prefix('/')?;
+    let mut parts = line.splitn(2, ' ');
+    Some((parts.next()?, parts.next().unwrap_or("").trim()))
+}
+
+/// Splits the arguments of a command in the first word and the rest
+pub fn first_word(args: &str) -> (&str, &str) {
+    let mut parts = args.splitn(2, ' ');
+    (
+        parts.next().unwrap_or(""),
+        parts.next().unwrap_or("").trim(),
+    )
+}
+
+/// Parses the id of a message, written like `#12` or `12`
+pub fn parse_id(val: &str) -> Option<u64> {
+    val.strip_prefix('#').unwrap_or(val).parse().ok()
+}
+
+/// Like `get_string` but every character is shown as `*`, the text is not
+/// trimmed
+pub fn get_secret(win: &Window) -> (String, bool) {
+    let mut secret = String::new();
+    loop {
+        match win.getch() {
+            Some(Input::Character('\n')) => return (secret, false),
+            Some(Input::Character('\u{1b}')) => return (secret, true),
+            Some(Input::Character('\u{8}')) | Some(Input::KeyBackspace)
+                if secret.pop().is_some() =>
+            {
+                win.mv(win.get_cur_y(), win.get_cur_x() - 1);
+                win.delch();
+            }
+            Some(Input::Character(ch)) if !ch.is_control() => {
+                secret.push(ch);
+                win.addch('*');
             }
+            _ => (),
+        }
+    }
+}
+
+pub fn get_string(win: &Window) -> (String, bool) {
+    let mut string = String::new();
+    let mut cursor = 0;
+    loop {
+        match try_get_string(win, &mut string, &mut cursor) {
+            Some(Input::Character('\n')) => break,
+            Some(Input::Character('\u{1b}')) => return (string, true),
+            _ => (),
         }
     }
     (string.trim().to_string(), false)
 }
 
+/// Reads a key and applies it to `string`, `cursor` is the byte index where
+/// the next character will be inserted
 pub fn try_get_string(win: &Window, string: &mut String, cursor: &mut usize) -> Option<Input> {
     let ch = win.getch();
     let y = win.get_cur_y();
+    // Column where the text starts
+    let x = win.get_cur_x() - cursor_x(string, *cursor);
     if let Some(Input::Character(ch)) = ch {
-        println!("Pressed: {}({:x})\nLength: {}", ch, ch as u32, string.len());
         match ch {
             '\u{8}' => {
                 if *cursor > 0 {
-                    if *cursor < string.len() {
-                        string.remove(*cursor);
-                    } else {
-                        string.pop();
-                    }
-                    win.addch('\u{8}');
-                    win.delch();
-                    *cursor -= 1;
+                    *cursor = prev_boundary(string, *cursor);
+                    string.remove(*cursor);
                 }
             }
             '\u{7f}' => {
-                win.mv(y, win.get_cur_x() - *cursor as i32);
-                win.clrtoeol();
                 if let Some(idx) = string[0..*cursor].rfind(char::is_whitespace) {
                     let (take, divide) = string.split_at(idx);
                     *string = format!("{}{}", take, divide.split_at(*cursor - take.len()).1);
                     *cursor = idx;
                 } else {
-                    use std::iter::FromIterator;
-                    *string = String::from_iter(
-                        string.split_at(*cursor).1.chars().skip_while(|c| *c == ' '),
-                    );
+                    *string = string
+                        .split_at(*cursor)
+                        .1
+                        .trim_start_matches(' ')
+                        .to_string();
                     *cursor = 0;
                 }
-                win.printw(&string);
-                win.mv(y, win.get_cur_x() - (string.len() - *cursor) as i32);
             }
             _ => {
-                if ch.is_ascii() && !ch.is_ascii_control() {
+                if !ch.is_control() {
                     string.insert(*cursor, ch);
-                    *cursor += 1;
-                    win.insch(ch);
-                    win.mv(y, win.get_cur_x() + 1);
+                    *cursor += ch.len_utf8();
                 }
             }
         }
     } else if let Some(input) = ch {
-        println!("Pressed: {:?}\nLength: {}", input, string.len());
         match input {
-            Input::KeyLeft if *cursor > 0 => {
-                *cursor -= 1;
-                win.mv(y, win.get_cur_x() - 1);
-            }
-            Input::KeyRight if *cursor < string.len() => {
-                *cursor += 1;
-                win.mv(y, win.get_cur_x() + 1);
+            Input::KeyBackspace if *cursor > 0 => {
+                *cursor = prev_boundary(string, *cursor);
+                string.remove(*cursor);
             }
+            Input::KeyLeft if *cursor > 0 => *cursor = prev_boundary(string, *cursor),
+            Input::KeyRight if *cursor < string.len() => *cursor = next_boundary(string, *cursor),
             Input::KeyDC if *cursor < string.len() => {
                 string.remove(*cursor);
-                win.delch();
-            }
-            Input::KeyHome => {
-                win.mv(y, win.get_cur_x() - *cursor as i32);
-                *cursor = 0;
             }
+            Input::KeyHome => *cursor = 0,
+            Input::KeyEnd => *cursor = string.len(),
             _ => (),
         }
     }
+    if ch.is_some() {
+        // Draw the text again, characters can take more than one column
+        win.mv(y, x);
+        win.printw(&string);
+        win.clrtoeol();
+        win.mv(y, x + cursor_x(string, *cursor));
+    }
     ch
 }
 
@@ -234,7 +429,6 @@ impl<'a> TextBox<'a> {
                 }
                 _ => (),
             }
-            println!("Pressed: {:?}", input);
         }
         self.clear();
         input
@@ -0,0 +1,33 @@
+use chattest::discovery::{self, Found};
+use chattest::host::Host;
+use chattest::lobby::Lobby;
+use chattest::room::Room;
+use std::net::{TcpListener, UdpSocket};
+use std::time::Duration;
+
+#[test]
+fn finds_the_rooms_on_loopback() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let lobby = Lobby::new(vec![
+        Room::new("Lobby".to_string(), "server".to_string()),
+        Room::new("Games".to_string(), "alice".to_string()),
+    ]);
+    let _host = Host::start(listener, lobby).unwrap();
+
+    // Datagrams that are not a whole message are skipped
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    for junk in [&[][..], &[1], &[1, 0, 0, 0, 9, 0]] {
+        socket.send_to(junk, addr).unwrap();
+    }
+
+    let found = discovery::discover(addr.port(), Duration::from_millis(500)).unwrap();
+    let expected = |room: &str, admin: &str| Found {
+        addr,
+        room: room.to_string(),
+        admin: admin.to_string(),
+    };
+    assert_eq!(found.len(), 2);
+    assert!(found.contains(&expected("Lobby", "server")));
+    assert!(found.contains(&expected("Games", "alice")));
+}
@@ -0,0 +1,82 @@
+#![cfg(feature = "tls")]
+
+use chattest::connection::Connection;
+use chattest::host::Host;
+use chattest::room::Room;
+use chattest::tls::{self, KnownHosts};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::{env, fs};
+
+/// Hosts a room on loopback with the certificate in `dir`, it's made if
+/// it's not there
+fn host(dir: &Path) -> (Host, SocketAddr) {
+    let config = tls::server_config(&dir.join("cert.pem"), &dir.join("key.pem")).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let room = Room::new("Room".to_string(), "admin".to_string());
+    (Host::start_tls(listener, room, config).unwrap(), addr)
+}
+
+#[test]
+fn pins_the_certificate() {
+    // The known hosts are in the home of the user, use an empty one
+    let home = env::temp_dir().join(format!("chattest-tls-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&home);
+    env::set_var("HOME", &home);
+
+    // The certificate is made the first time
+    let first = home.join("first");
+    let (_first_host, first_addr) = host(&first);
+    let fingerprint = tls::file_fingerprint(&first.join("cert.pem")).unwrap();
+    // Only the user can read its key
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(first.join("key.pem"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    // It's trusted on the first connection and remembered
+    let connection = Connection::connect(first_addr).unwrap();
+    assert_eq!(connection.fingerprint(), Some(fingerprint.clone()));
+    let known_hosts = KnownHosts::new(KnownHosts::default_path());
+    let known = known_hosts.get(&first_addr.to_string()).unwrap();
+    assert_eq!(known, Some(fingerprint.clone()));
+    // It can still join the room through TLS
+    assert!(connection.join("alice".to_string()).is_ok());
+
+    // The same certificate is accepted again
+    let connection = Connection::connect(first_addr).unwrap();
+    assert_eq!(connection.fingerprint(), Some(fingerprint.clone()));
+
+    // Another certificate at an address that had the first one is refused
+    let second = home.join("second");
+    let (_second_host, second_addr) = host(&second);
+    assert_ne!(
+        tls::file_fingerprint(&second.join("cert.pem")).unwrap(),
+        fingerprint
+    );
+    known_hosts
+        .add(&second_addr.to_string(), &fingerprint)
+        .unwrap();
+    let error = Connection::connect(second_addr).err().unwrap();
+    assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+
+    // And so is a server that stopped using TLS
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let plain_addr = listener.local_addr().unwrap();
+    let room = Room::new("Room".to_string(), "admin".to_string());
+    let _plain_host = Host::start(listener, room).unwrap();
+    known_hosts
+        .add(&plain_addr.to_string(), &fingerprint)
+        .unwrap();
+    let error = Connection::connect(plain_addr).err().unwrap();
+    assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+
+    fs::remove_dir_all(&home).unwrap();
+}